@@ -0,0 +1,42 @@
+use crate::kib_board as bsp;
+
+use bsp::hal;
+use bsp::pac;
+
+use cortex_m::interrupt as interrupt_helpers;
+use core::cell::{Cell, RefCell};
+use pac::interrupt;
+
+use hal::prelude::*;
+use hal::timer::TimerCounter;
+
+static TICK_TIMER: interrupt_helpers::Mutex<RefCell<Option<TimerCounter<pac::TC2>>>> =
+    interrupt_helpers::Mutex::new(RefCell::new(None));
+
+static MILLIS: interrupt_helpers::Mutex<Cell<u32>> = interrupt_helpers::Mutex::new(Cell::new(0));
+
+#[interrupt]
+fn TC2() {
+    interrupt_helpers::free(|cs| {
+        if let Some(timer) = TICK_TIMER.borrow(cs).borrow_mut().as_mut() {
+            //Clears the overflow flag
+            timer.wait().ok();
+        }
+
+        let millis = MILLIS.borrow(cs);
+        millis.set(millis.get().wrapping_add(1));
+    });
+}
+
+/// Takes ownership of a started 1 kHz timer and uses its overflow interrupt as the millisecond tick.
+pub fn configure_tick_timer(mut timer: TimerCounter<pac::TC2>) {
+    timer.enable_interrupt();
+
+    interrupt_helpers::free(|cs| {
+        TICK_TIMER.borrow(cs).replace(Some(timer));
+    });
+}
+
+pub fn millis() -> u32 {
+    interrupt_helpers::free(|cs| MILLIS.borrow(cs).get())
+}
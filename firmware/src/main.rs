@@ -2,6 +2,7 @@
 #![no_main]
 
 mod kib_board;
+mod clock;
 mod i2c_peripheral;
 mod protocol;
 
@@ -24,6 +25,7 @@ use pac::interrupt;
 use pac::{CorePeripherals, Peripherals};
 
 use hal::clock::GenericClockController;
+use hal::gpio::DynPin;
 use hal::delay::Delay;
 use hal::prelude::*;
 use hal::time::*;
@@ -33,7 +35,7 @@ use hal::sercom::Sercom;
 
 use ws2812_timer_delay as ws2812;
 
-use keyboard_matrix::KeyboardMatrix;
+use keyboard_matrix::{KeyboardMatrix, KeyboardState, KIB_WIRING};
 use synth_engine::SynthEngine;
 
 use illuminator::IlluminationEngine;
@@ -42,6 +44,11 @@ use comms::BusStatus;
 
 use rtt_target::{ rtt_init_print, rprintln };
 
+#[cfg(feature = "record")]
+use keyboard_matrix::{max_record_size, Recorder, KIB_KEY_COUNT, RECORDING_HEADER_SIZE};
+#[cfg(feature = "record")]
+use rtt_target::rtt_init;
+
 #[entry]
 fn main() -> ! {
     // rtt_init_print!();
@@ -79,21 +86,36 @@ fn main() -> ! {
         NVIC::unmask(interrupt::SERCOM0);
     }
 
+    let mut tick_timer = TimerCounter::tc2_(tc12, peripherals.TC2, &mut peripherals.PM);
+    tick_timer.start(KiloHertz::kHz(1).into_duration());
+
+    clock::configure_tick_timer(tick_timer);
+
+    unsafe {
+        core.NVIC.set_priority(interrupt::TC2, 2);
+        NVIC::unmask(interrupt::TC2);
+    }
+
     let mut delay = Delay::new(core.SYST, &mut clocks);
 
     let mut synth_engine = SynthEngine::new();
 
     let mut keyboard_matrix = KeyboardMatrix::new(
-        pins.row_a.into_push_pull_output(),
-        pins.row_b.into_push_pull_output(),
-        pins.row_c.into_push_pull_output(),
-        pins.row_d.into_push_pull_output(),
-        pins.row_e.into_push_pull_output(),
-        pins.col_n.into_pull_down_input(),
-        pins.col_m.into_pull_down_input(),
-        pins.col_o.into_pull_down_input(),
-        pins.col_p.into_pull_down_input(),
-        pins.col_q.into_pull_down_input(),
+        [
+            DynPin::from(pins.row_a.into_push_pull_output()),
+            DynPin::from(pins.row_b.into_push_pull_output()),
+            DynPin::from(pins.row_c.into_push_pull_output()),
+            DynPin::from(pins.row_d.into_push_pull_output()),
+            DynPin::from(pins.row_e.into_push_pull_output()),
+        ],
+        [
+            DynPin::from(pins.col_m.into_pull_down_input()),
+            DynPin::from(pins.col_n.into_pull_down_input()),
+            DynPin::from(pins.col_o.into_pull_down_input()),
+            DynPin::from(pins.col_p.into_pull_down_input()),
+            DynPin::from(pins.col_q.into_pull_down_input()),
+        ],
+        KIB_WIRING,
     );
 
     let mut led_timer = TimerCounter::tc1_(tc12, peripherals.TC1, &mut peripherals.PM);
@@ -106,10 +128,39 @@ fn main() -> ! {
 
     let mut illumination_engine = IlluminationEngine::new(&mut led_strand);
 
-    let delta_t_ms = 1;
+    let mut last_millis = clock::millis();
+
+    #[cfg(feature = "record")]
+    let mut recording_channel = {
+        let channels = rtt_init! {
+            up: {
+                0: {
+                    size: 1024,
+                    name: "KeyRecording"
+                }
+            }
+        };
+
+        channels.up.0
+    };
+
+    #[cfg(feature = "record")]
+    // Scans run back to back, timed by the 1kHz tick
+    let mut recorder: Recorder = Recorder::new(keyboard_matrix.now_us(), 1_000);
+
+    #[cfg(feature = "record")]
+    {
+        let mut header = [0u8; RECORDING_HEADER_SIZE];
+
+        if let Ok(size) = recorder.write_header(&mut header) {
+            recording_channel.write(&header[..size]);
+        }
+    }
 
     let mut communication_register : u8 = 0x00;
 
+    let mut keystate = KeyboardState::default();
+
     loop {
         let command = interrupt_helpers::free(|cs| {
             if let Some(comms_status) = i2c_peripheral::BUS_STATUS.borrow(cs).borrow_mut().as_mut() {
@@ -125,16 +176,41 @@ fn main() -> ! {
             protocol::process_command(&command, &mut synth_engine, &mut illumination_engine);
         }
 
-        let keystate = keyboard_matrix.scan(&mut delay);
+        let now_millis = clock::millis();
+        let delta_t_ms = now_millis.wrapping_sub(last_millis);
+        last_millis = now_millis;
+
+        match keyboard_matrix.scan(&mut delay, delta_t_ms.saturating_mul(1000)) {
+            Ok(new_keystate) => {
+                keystate = new_keystate;
+
+                #[cfg(feature = "record")]
+                {
+                    let mut record = [0u8; max_record_size(KIB_KEY_COUNT)];
+
+                    if let Ok(size) = recorder.record(&keystate, keyboard_matrix.now_us(), &mut record) {
+                        recording_channel.write(&record[..size]);
+                    }
+                }
+            }
+            // A failed scan keeps the keys as they were, so the LEDs, synth and bus keep running
+            Err(_) => {
+                keystate.pressed.clear();
+                keystate.released.clear();
+                keystate.pressed_count = 0;
+                keystate.released_count = 0;
+            }
+        }
 
         // Update Synth Engine state
-        synth_engine.update(&keystate);
+        synth_engine.update_timed(delta_t_ms, &keystate);
 
-        illumination_engine.update(delta_t_ms, &keystate, &synth_engine.state);
+        // Animations advance one step per frame, their speed is tuned to the loop rate
+        illumination_engine.update(1, &keystate, &synth_engine.state);
 
         illumination_engine.render();
 
-        if let Some((register_data, data_size)) = protocol::build_response(communication_register, &synth_engine, &illumination_engine) {
+        if let Some((register_data, data_size)) = protocol::build_response(communication_register, &keystate, &synth_engine, &illumination_engine) {
             interrupt_helpers::free(|cs| {
                 if let Some(comms_status) = i2c_peripheral::BUS_STATUS.borrow(cs).borrow_mut().as_mut() {
                     comms_status.provide_data(communication_register, &register_data, data_size)
@@ -1,5 +1,6 @@
 use keyboard_matrix::KeyboardMatrix;
-use synth_engine::SynthEngine;
+use keyboard_matrix::KeyboardState;
+use synth_engine::{AdsrSettings, ArpSettings, ChordSettings, ChordShape, Keymap, MonoSettings, NotePriority, Scale, StealPolicy, SynthEngine, Tuning, TuningSystem, VoiceSettings};
 
 use illuminator::IlluminationEngine;
 
@@ -17,12 +18,136 @@ where LedStrand: SmartLedsWrite<Error = (), Color = RGB8> {
                 synth_engine.set_octave(command.data[0])
             }
         }
+        0x12 => {
+            //Keymap: first key, then one role byte per key.  Applied only if the result is valid.
+            if command.data_size >= 2 {
+                let first_key = command.data[0] as usize;
+                let roles = &command.data[1..command.data_size];
+
+                let mut bytes = synth_engine.state.keymap.to_bytes();
+
+                if first_key + roles.len() <= bytes.len() {
+                    bytes[first_key..first_key + roles.len()].copy_from_slice(roles);
+
+                    if let Ok(keymap) = Keymap::from_bytes(&bytes) {
+                        synth_engine.set_keymap(keymap);
+                    }
+                }
+            }
+        }
+        0x13 => {
+            //Raw MIDI stream from the host, messages may span writes
+            synth_engine.receive_midi(&command.data[..command.data_size]);
+        }
+        0x14 => {
+            //Root, scale id, then the steps of a custom scale
+            if command.data_size >= 2 && command.data[0] < 12 {
+                if let Ok(scale) = Scale::from_id(command.data[1], &command.data[2..command.data_size]) {
+                    synth_engine.set_scale(command.data[0], scale);
+                }
+            }
+        }
+        0x15 => {
+            //Signed transpose in semitones, then optionally signed fine tune in cents
+            if command.data_size >= 1 {
+                synth_engine.set_transpose(command.data[0] as i8);
+            }
+            if command.data_size >= 2 {
+                synth_engine.set_fine_tune(command.data[1] as i8);
+            }
+        }
+        0x16 => {
+            //Arpeggiator: 0 to turn off, or 1 followed by the six settings bytes
+            if command.data_size == 1 && command.data[0] == 0 {
+                synth_engine.set_arpeggiator(None);
+            } else if command.data_size == 7 && command.data[0] == 1 {
+                let mut settings = [0u8; 6];
+                settings.copy_from_slice(&command.data[1..7]);
+
+                if let Some(settings) = ArpSettings::from_bytes(&settings) {
+                    synth_engine.set_arpeggiator(Some(settings));
+                }
+            }
+        }
+        0x17 => {
+            //Chord mode: 0 to turn off, or 1, chord id, inversion, then the intervals of a custom chord
+            if command.data_size == 1 && command.data[0] == 0 {
+                synth_engine.set_chord(None);
+            } else if command.data_size >= 3 && command.data[0] == 1 {
+                if let Ok(shape) = ChordShape::from_id(command.data[1], &command.data[3..command.data_size]) {
+                    let mut settings = ChordSettings::new(shape);
+                    settings.inversion = command.data[2];
+
+                    synth_engine.set_chord(Some(settings));
+                }
+            }
+        }
+        0x18 => {
+            //Bit 0 sustain pedal, bit 1 latch mode
+            if command.data_size == 1 {
+                synth_engine.set_sustain_pedal(command.data[0] & 0x01 != 0);
+
+                if synth_engine.latch() != (command.data[0] & 0x02 != 0) {
+                    synth_engine.set_latch(command.data[0] & 0x02 != 0);
+                }
+            }
+        }
+        0x19 => {
+            //Voice limit: 0 to turn off, or 1, voice count, steal policy
+            if command.data_size == 1 && command.data[0] == 0 {
+                synth_engine.set_voice_allocation(None);
+            } else if command.data_size == 3 && command.data[0] == 1 {
+                if let Some(policy) = StealPolicy::from_id(command.data[2]) {
+                    synth_engine.set_voice_allocation(Some(VoiceSettings { voices: command.data[1], policy }));
+                }
+            }
+        }
+        0x1a => {
+            //Mono mode: 0 to turn off, or 1, priority, legato, glide time in ms little endian
+            if command.data_size == 1 && command.data[0] == 0 {
+                synth_engine.set_mono(None);
+            } else if command.data_size == 5 && command.data[0] == 1 {
+                if let Some(priority) = NotePriority::from_id(command.data[1]) {
+                    synth_engine.set_mono(Some(MonoSettings {
+                        priority,
+                        legato: command.data[2] != 0,
+                        glide_ms: u16::from_le_bytes([command.data[3], command.data[4]]),
+                    }));
+                }
+            }
+        }
+        0x1b => {
+            //Envelope: attack, decay, sustain level, release, see AdsrSettings::from_bytes
+            if command.data_size == 7 {
+                let mut settings = [0u8; 7];
+                settings.copy_from_slice(&command.data[..7]);
+
+                synth_engine.set_envelope(AdsrSettings::from_bytes(&settings));
+            }
+        }
+        0x1c => {
+            //Tuning: system (0 equal, 1 just), equal divisions, A4 reference in millihertz little endian
+            if command.data_size == 6 {
+                let system = match command.data[0] {
+                    0 if command.data[1] > 0 => Some(TuningSystem::Equal { divisions: command.data[1] }),
+                    1 => Some(TuningSystem::Just),
+                    _ => None,
+                };
+                let reference_millihz = u32::from_le_bytes([command.data[2], command.data[3], command.data[4], command.data[5]]);
+
+                if let Some(system) = system {
+                    if reference_millihz > 0 {
+                        synth_engine.set_tuning(Tuning { reference_millihz, system });
+                    }
+                }
+            }
+        }
 
         _ => { }
     }
 }
 
-pub fn build_response<LedStrand>(register: u8, synth_engine: &SynthEngine, illumination_engine: &IlluminationEngine<LedStrand>) -> Option<([u8; 20], usize)>
+pub fn build_response<LedStrand>(register: u8, keyboard_state: &KeyboardState, synth_engine: &SynthEngine, illumination_engine: &IlluminationEngine<LedStrand>) -> Option<([u8; 20], usize)>
 where LedStrand: SmartLedsWrite<Error = (), Color = RGB8> {
     
     let mut register_data: [u8; 20] = [0; 20];
@@ -35,6 +160,106 @@ where LedStrand: SmartLedsWrite<Error = (), Color = RGB8> {
 
             Some((register_data, 1))
         }
+        0x11 => {
+            //Stuck key bitmask, key 0 in the LSB of the first byte
+            for key in keyboard_state.stuck.iter() {
+                register_data[key / 8] |= 1 << (key % 8);
+            }
+
+            Some((register_data, 3))
+        }
+        0x12 | 0x1d => {
+            //Keymap as written: first key, then role bytes.  Keys 0 - 18 from 0x12, the rest from 0x1d
+            let bytes = synth_engine.state.keymap.to_bytes();
+            let first_key = if register == 0x12 { 0 } else { register_data.len() - 1 };
+            let roles = &bytes[first_key..bytes.len().min(first_key + register_data.len() - 1)];
+
+            register_data[0] = first_key as u8;
+            register_data[1..1 + roles.len()].copy_from_slice(roles);
+
+            Some((register_data, 1 + roles.len()))
+        }
+        0x14 => {
+            register_data[0] = synth_engine.state.root;
+            register_data[1] = synth_engine.state.scale.id();
+
+            Some((register_data, 2))
+        }
+        0x15 => {
+            register_data[0] = synth_engine.state.transpose as u8;
+            register_data[1] = synth_engine.state.fine_tune_cents as u8;
+
+            Some((register_data, 2))
+        }
+        0x16 => {
+            match synth_engine.arpeggiator() {
+                Some(settings) => {
+                    register_data[0] = 1;
+                    register_data[1..7].copy_from_slice(&settings.to_bytes());
+
+                    Some((register_data, 7))
+                }
+                None => Some((register_data, 1)),
+            }
+        }
+        0x17 => {
+            match synth_engine.chord() {
+                Some(settings) => {
+                    register_data[0] = 1;
+                    register_data[1] = settings.shape.id();
+                    register_data[2] = settings.inversion;
+
+                    Some((register_data, 3))
+                }
+                None => Some((register_data, 1)),
+            }
+        }
+        0x18 => {
+            register_data[0] = synth_engine.sustain() as u8 | (synth_engine.latch() as u8) << 1;
+
+            Some((register_data, 1))
+        }
+        0x19 => {
+            match synth_engine.voice_allocation() {
+                Some(settings) => {
+                    register_data[0] = 1;
+                    register_data[1] = settings.voices;
+                    register_data[2] = settings.policy.id();
+
+                    Some((register_data, 3))
+                }
+                None => Some((register_data, 1)),
+            }
+        }
+        0x1a => {
+            match synth_engine.mono() {
+                Some(settings) => {
+                    register_data[0] = 1;
+                    register_data[1] = settings.priority.id();
+                    register_data[2] = settings.legato as u8;
+                    register_data[3..5].copy_from_slice(&settings.glide_ms.to_le_bytes());
+
+                    Some((register_data, 5))
+                }
+                None => Some((register_data, 1)),
+            }
+        }
+        0x1b => {
+            register_data[..7].copy_from_slice(&synth_engine.state.envelope.to_bytes());
+
+            Some((register_data, 7))
+        }
+        0x1c => {
+            let tuning = &synth_engine.state.tuning;
+
+            register_data[0] = tuning.system.id();
+            if let TuningSystem::Equal { divisions } = tuning.system {
+                register_data[1] = divisions;
+            }
+            register_data[2..6].copy_from_slice(&tuning.reference_millihz.to_le_bytes());
+
+            Some((register_data, 6))
+        }
         _ => { 
             None
         }
@@ -131,6 +131,11 @@ impl KeyFadeAnimation {
     pub fn is_complete(duration: u32) -> bool {
         duration > FADE_DURATION
     }
+
+    /// Fade duration matching a note's release progress of 0 - 255.
+    pub fn duration_for_progress(progress: u8) -> u32 {
+        progress as u32 * FADE_DURATION / 255
+    }
 }
 
 impl PixelAnimation for KeyFadeAnimation {
@@ -5,7 +5,7 @@ use crate::data::*;
 use crate::keystrike_animation::*;
 
 use keyboard_matrix::KeyboardState;
-use synth_engine::SynthState;
+use synth_engine::{KeyRole, Keymap, SynthState};
 
 use smart_leds::hsv::RGB8;
 
@@ -45,27 +45,33 @@ impl KeyData {
 
 pub struct KeystrikeIlluminator {
     key_data: [KeyData; 21],
+    key_types: [KeyType; 21],
 }
 
 impl KeystrikeIlluminator {
     pub fn new() -> Self {
         Self {
             key_data: [KeyData::new(); 21],
+            key_types: KeystrikeIlluminator::key_types_for_keymap(&Keymap::DEFAULT),
         }
     }
 }
 
 impl KeystrikeIlluminator {
-    fn keytype_for_index(key_index: usize) -> KeyType {
-        match key_index {
-            0..=7 => KeyType::Octave,
-            _ => KeyType::Normal,
+    fn key_types_for_keymap(keymap: &Keymap) -> [KeyType; 21] {
+        let mut key_types = [KeyType::Normal; 21];
+
+        for (key_type, role) in key_types.iter_mut().zip(keymap.roles().iter()) {
+            if let KeyRole::OctaveSelect(_) = role {
+                *key_type = KeyType::Octave;
+            }
         }
+
+        key_types
     }
 
-    fn compute_pixel_for_index(key_index: usize, key_data: &KeyData) -> Option<RGB8> {
-        let key_type = KeystrikeIlluminator::keytype_for_index(key_index);
-        KeystrikeIlluminator::compute_pixel(key_type, key_data)
+    fn compute_pixel_for_index(&self, key_index: usize) -> Option<RGB8> {
+        KeystrikeIlluminator::compute_pixel(self.key_types[key_index], &self.key_data[key_index])
     }
 
     fn compute_pixel(key_type: KeyType, key_data: &KeyData) -> Option<RGB8> {
@@ -103,15 +109,28 @@ impl Illuminator for KeystrikeIlluminator {
         keyboard_state: &KeyboardState,
         synth_state: &SynthState,
     ) {
+        self.key_types = KeystrikeIlluminator::key_types_for_keymap(&synth_state.keymap);
+
         //Set selected octave
-        self.key_data[synth_state.octave as usize - 1].state = KeyState::Selected;
+        if let Some(octave_key) = synth_state.keymap.key_for_octave(synth_state.octave) {
+            self.key_data[octave_key].state = KeyState::Selected;
+        }
 
         for key_index in 0..21 {
+            let key_type = self.key_types[key_index];
+
+            // Notes from playback or MIDI light their keys like a local press.  A key whose note
+            // lost its voice, or that is stuck, goes out while still held.  An ambiguous key
+            // keeps its previous state.
+            let note_down = synth_state.note_held_for_key(key_index);
+            let key_down = keyboard_state.is_trusted_down(key_index) && !synth_state.note_stolen_for_key(key_index);
+            let is_down = key_down || note_down;
+            let ghosted = keyboard_state.ghosted[key_index];
             let mut key_data = &mut self.key_data[key_index];
 
             match key_data.state {
                 KeyState::Off => {
-                    if keyboard_state.state[key_index] {
+                    if is_down {
                         key_data.state = KeyState::Pressed;
                         key_data.counter = 0;
 
@@ -132,9 +151,8 @@ impl Illuminator for KeystrikeIlluminator {
                     }
                 }
                 KeyState::Pressed => {
-                    if !keyboard_state.state[key_index] {
-                        let previous_color =
-                            KeystrikeIlluminator::compute_pixel_for_index(key_index, key_data);
+                    if !is_down && !ghosted {
+                        let previous_color = KeystrikeIlluminator::compute_pixel(key_type, key_data);
 
                         let previous_color = previous_color.unwrap_or(RGB8::default());
 
@@ -146,7 +164,7 @@ impl Illuminator for KeystrikeIlluminator {
                     }
                 }
                 KeyState::Fade => {
-                    if keyboard_state.state[key_index] {
+                    if is_down {
                         key_data.state = KeyState::Pressed;
 
                         adjacency_recursion(
@@ -164,7 +182,11 @@ impl Illuminator for KeystrikeIlluminator {
                             },
                         );
                     } else {
-                        key_data.counter += delta_t_ms;
+                        // Fade along with the note's release while it sounds
+                        key_data.counter = match synth_state.release_progress_for_key(key_index) {
+                            Some(progress) => KeyFadeAnimation::duration_for_progress(progress),
+                            None => key_data.counter + delta_t_ms,
+                        };
 
                         if KeyFadeAnimation::is_complete(key_data.counter) {
                             key_data.state = KeyState::Off;
@@ -173,7 +195,7 @@ impl Illuminator for KeystrikeIlluminator {
                     }
                 }
                 KeyState::Radiant => {
-                    if keyboard_state.state[key_index] {
+                    if is_down {
                         key_data.state = KeyState::Pressed;
 
                         adjacency_recursion(
@@ -191,8 +213,7 @@ impl Illuminator for KeystrikeIlluminator {
                             },
                         );
                     } else if key_data.counter > 50 {
-                        let previous_color =
-                            KeystrikeIlluminator::compute_pixel_for_index(key_index, key_data);
+                        let previous_color = KeystrikeIlluminator::compute_pixel(key_type, key_data);
 
                         let previous_color = previous_color.unwrap_or(RGB8::default());
 
@@ -204,10 +225,9 @@ impl Illuminator for KeystrikeIlluminator {
                     }
                 }
                 KeyState::Selected => {
-                    if synth_state.octave != (key_index as u8 + 1) {
+                    if synth_state.keymap.role(key_index) != KeyRole::OctaveSelect(synth_state.octave) {
                         //Fade previously selected octave
-                        let previous_color =
-                            KeystrikeIlluminator::compute_pixel_for_index(key_index, key_data);
+                        let previous_color = KeystrikeIlluminator::compute_pixel(key_type, key_data);
                         let previous_color = previous_color.unwrap_or(RGB8::default());
                         key_data.state = KeyState::Fade;
                         key_data.counter = 0;
@@ -225,11 +245,9 @@ impl Illuminator for KeystrikeIlluminator {
         // rprintln!("R");
 
         for key_index in 0..21 {
-            let key_data = &self.key_data[key_index];
-
             // rprintln!("K");
 
-            let color = KeystrikeIlluminator::compute_pixel_for_index(key_index, key_data);
+            let color = self.compute_pixel_for_index(key_index);
 
             if color.is_some() {
                 leds[key_index] = color.unwrap();
@@ -300,6 +318,125 @@ mod test {
         assert_eq!(leds[3], crate::keystrike_animation::OCTAVE_SELECTED_COLOR_1);
     }
 
+    #[test]
+    fn test_remapped_octave_key_is_selected() {
+        let mut illuminator = super::KeystrikeIlluminator::new();
+
+        let keyboard_state = keyboard_matrix::KeyboardState::default();
+        let mut synth_state = synth_engine::SynthState::new();
+
+        let mut roles = *synth_engine::Keymap::DEFAULT.roles();
+        roles[0..8].reverse();
+        synth_state.keymap = synth_engine::Keymap::new(roles).unwrap();
+        synth_state.octave = 4;
+
+        illuminator.update(0, &keyboard_state, &synth_state);
+
+        assert_eq!(illuminator.key_data[4].state, super::KeyState::Selected);
+        assert_eq!(illuminator.key_data[3].state, super::KeyState::Off);
+    }
+
+    #[test]
+    fn test_remote_note_shows_pressed() {
+        let mut illuminator = super::KeystrikeIlluminator::new();
+
+        let keyboard_state = keyboard_matrix::KeyboardState::default();
+        let mut synth_engine = synth_engine::SynthEngine::new();
+
+        // A4 from a sequencer lights key 18
+        synth_engine.receive_midi(&[0x90, 69, 100]);
+        synth_engine.update(&keyboard_state);
+
+        illuminator.update(0, &keyboard_state, &synth_engine.state);
+
+        assert_eq!(illuminator.key_data[18].state, super::KeyState::Pressed);
+
+        synth_engine.receive_midi(&[0x80, 69, 64]);
+        synth_engine.update(&keyboard_state);
+
+        illuminator.update(10, &keyboard_state, &synth_engine.state);
+
+        assert_eq!(illuminator.key_data[18].state, super::KeyState::Fade);
+    }
+
+    #[test]
+    fn test_stolen_note_fades_while_held() {
+        let mut illuminator = super::KeystrikeIlluminator::new();
+
+        let mut keyboard_state = keyboard_matrix::KeyboardState::default();
+        let mut synth_engine = synth_engine::SynthEngine::new();
+
+        synth_engine.set_voice_allocation(Some(synth_engine::VoiceSettings {
+            voices: 1,
+            policy: synth_engine::StealPolicy::Oldest,
+        }));
+
+        keyboard_state.state.set(13, true);
+        synth_engine.update(&keyboard_state);
+        illuminator.update(0, &keyboard_state, &synth_engine.state);
+
+        assert_eq!(illuminator.key_data[13].state, super::KeyState::Pressed);
+
+        // E takes the only voice from C
+        keyboard_state.state.set(15, true);
+        synth_engine.update(&keyboard_state);
+        illuminator.update(10, &keyboard_state, &synth_engine.state);
+
+        assert_eq!(illuminator.key_data[13].state, super::KeyState::Fade);
+        assert_eq!(illuminator.key_data[15].state, super::KeyState::Pressed);
+    }
+
+    #[test]
+    fn test_stuck_key_fades_while_held() {
+        let mut illuminator = super::KeystrikeIlluminator::new();
+
+        let mut keyboard_state = keyboard_matrix::KeyboardState::default();
+        let mut synth_engine = synth_engine::SynthEngine::new();
+
+        keyboard_state.state.set(13, true);
+        synth_engine.update(&keyboard_state);
+        illuminator.update(0, &keyboard_state, &synth_engine.state);
+
+        assert_eq!(illuminator.key_data[13].state, super::KeyState::Pressed);
+
+        keyboard_state.stuck.set(13, true);
+        synth_engine.update(&keyboard_state);
+        illuminator.update(10, &keyboard_state, &synth_engine.state);
+
+        assert_eq!(illuminator.key_data[13].state, super::KeyState::Fade);
+    }
+
+    #[test]
+    fn test_fade_follows_note_release() {
+        let mut illuminator = super::KeystrikeIlluminator::new();
+
+        let mut keyboard_state = keyboard_matrix::KeyboardState::default();
+        let mut synth_engine = synth_engine::SynthEngine::new();
+
+        synth_engine.set_envelope(synth_engine::AdsrSettings {
+            attack_ms: 0,
+            decay_ms: 0,
+            sustain_level: 200,
+            release_ms: 100,
+        });
+
+        keyboard_state.state.set(13, true);
+        synth_engine.update_timed(10, &keyboard_state);
+        illuminator.update(10, &keyboard_state, &synth_engine.state);
+
+        keyboard_state.state.set(13, false);
+        synth_engine.update_timed(10, &keyboard_state);
+        illuminator.update(10, &keyboard_state, &synth_engine.state);
+
+        assert_eq!(illuminator.key_data[13].state, super::KeyState::Fade);
+
+        // Half way through the release is half way through the fade
+        synth_engine.update_timed(40, &keyboard_state);
+        illuminator.update(40, &keyboard_state, &synth_engine.state);
+
+        assert_eq!(illuminator.key_data[13].counter, 501);
+    }
+
     #[test]
     fn test_with_keypress_18_shows_pressed() {
         let mut illuminator = super::KeystrikeIlluminator::new();
@@ -308,7 +445,7 @@ mod test {
         let mut synth_state = synth_engine::SynthState::new();
 
         synth_state.octave = 4;
-        keyboard_state.state[18] = true;
+        keyboard_state.state.set(18, true);
 
         illuminator.update(0, &keyboard_state, &synth_state);
 
@@ -357,7 +494,7 @@ mod test {
         let mut synth_state = synth_engine::SynthState::new();
 
         synth_state.octave = 4;
-        keyboard_state.state[18] = true;
+        keyboard_state.state.set(18, true);
 
         illuminator.update(0, &keyboard_state, &synth_state);
 
@@ -408,11 +545,11 @@ mod test {
         let mut synth_state = synth_engine::SynthState::new();
 
         synth_state.octave = 4;
-        keyboard_state.state[18] = true;
+        keyboard_state.state.set(18, true);
 
         illuminator.update(0, &keyboard_state, &synth_state);
 
-        keyboard_state.state[18] = false;
+        keyboard_state.state.set(18, false);
 
         illuminator.update(10, &keyboard_state, &synth_state);
 
@@ -469,11 +606,11 @@ mod test {
         let mut synth_state = synth_engine::SynthState::new();
 
         synth_state.octave = 4;
-        keyboard_state.state[18] = true;
+        keyboard_state.state.set(18, true);
 
         illuminator.update(0, &keyboard_state, &synth_state);
 
-        keyboard_state.state[18] = false;
+        keyboard_state.state.set(18, false);
 
         //First update to Fade sets counter to zero.  Also sets radiant keys to radiant expiration
         illuminator.update(50, &keyboard_state, &synth_state);
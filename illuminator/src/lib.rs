@@ -57,3 +57,87 @@ where
             .unwrap();
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::IlluminationEngine;
+
+    use keyboard_matrix::{KeyboardState, Recorder, Replay};
+    use synth_engine::{NoteState, SynthEngine};
+
+    use smart_leds::{hsv::RGB8, SmartLedsWrite};
+
+    struct TestStrand {
+        leds: [RGB8; 21],
+    }
+
+    impl SmartLedsWrite for TestStrand {
+        type Error = ();
+        type Color = RGB8;
+
+        fn write<T, I>(&mut self, iterator: T) -> Result<(), ()>
+        where
+            T: IntoIterator<Item = I>,
+            I: Into<RGB8>,
+        {
+            for (led, color) in self.leds.iter_mut().zip(iterator) {
+                *led = color.into();
+            }
+
+            Ok(())
+        }
+    }
+
+    fn record(frames: &[(u32, &[usize])], out: &mut [u8]) -> usize {
+        let mut recorder = Recorder::<21>::new(0, 1_000);
+        let mut keyboard_state = KeyboardState::<21>::default();
+        let mut size = recorder.write_header(out).unwrap();
+
+        for (timestamp_us, keys) in frames {
+            let mut bools = [false; 21];
+
+            for key in keys.iter() {
+                bools[*key] = true;
+            }
+
+            keyboard_state = keyboard_state.build_new(bools);
+            size += recorder.record(&keyboard_state, *timestamp_us, &mut out[size..]).unwrap();
+        }
+
+        size
+    }
+
+    #[test]
+    fn test_replayed_recording_drives_synth_and_leds() {
+        let mut recording = [0u8; 64];
+        let size = record(&[(1_000, &[13]), (20_000, &[13]), (400_000, &[])], &mut recording);
+
+        let mut strand = TestStrand {
+            leds: [RGB8::default(); 21],
+        };
+        let mut illumination_engine = IlluminationEngine::new(&mut strand);
+        let mut synth_engine = SynthEngine::new();
+
+        let mut replay = Replay::<21>::new(&recording[..size]).unwrap();
+
+        let frame = replay.next().unwrap().unwrap();
+        synth_engine.update(&frame.keyboard_state);
+        illumination_engine.update(frame.delta_us / 1000, &frame.keyboard_state, &synth_engine.state);
+        illumination_engine.render();
+
+        assert!(synth_engine.state.note_index_state[36] == NoteState::Pressed);
+        assert_ne!(illumination_engine.led_data[13], RGB8::default());
+
+        // The unchanged scans in between were not recorded, but replay steps through them
+        let frame = replay.next().unwrap().unwrap();
+        synth_engine.update(&frame.keyboard_state);
+
+        assert!(synth_engine.state.note_index_state[36] == NoteState::Sustain);
+
+        for frame in replay {
+            synth_engine.update(&frame.unwrap().keyboard_state);
+        }
+
+        assert!(synth_engine.state.note_index_state[36] == NoteState::Release);
+    }
+}
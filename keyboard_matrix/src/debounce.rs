@@ -0,0 +1,346 @@
+/// Filters the raw level of a single key into a debounced level.
+pub trait Debouncer {
+    /// Feeds the raw level read `elapsed_us` after the previous call and returns the debounced level.
+    fn update(&mut self, raw: bool, elapsed_us: u32) -> bool;
+
+    fn is_pressed(&self) -> bool;
+}
+
+/// Reacts to the first edge, then ignores further changes until the lockout expires.
+#[derive(Clone, Copy, Debug)]
+pub struct EagerDebouncer {
+    lockout_us: u32,
+    remaining_us: u32,
+    state: bool,
+}
+
+impl EagerDebouncer {
+    pub const fn new(lockout_us: u32) -> Self {
+        Self {
+            lockout_us,
+            remaining_us: 0,
+            state: false,
+        }
+    }
+}
+
+impl Debouncer for EagerDebouncer {
+    fn update(&mut self, raw: bool, elapsed_us: u32) -> bool {
+        self.remaining_us = self.remaining_us.saturating_sub(elapsed_us);
+
+        if raw != self.state && self.remaining_us == 0 {
+            self.state = raw;
+            self.remaining_us = self.lockout_us;
+        }
+
+        self.state
+    }
+
+    fn is_pressed(&self) -> bool {
+        self.state
+    }
+}
+
+/// Changes level only once the raw level has been stable for the settle time.
+/// Press and release may use different settle times.
+#[derive(Clone, Copy, Debug)]
+pub struct AsymmetricDebouncer {
+    press_us: u32,
+    release_us: u32,
+    pending_us: u32,
+    last_raw: bool,
+    state: bool,
+}
+
+impl AsymmetricDebouncer {
+    pub const fn new(press_us: u32, release_us: u32) -> Self {
+        Self {
+            press_us,
+            release_us,
+            pending_us: 0,
+            last_raw: false,
+            state: false,
+        }
+    }
+}
+
+impl Debouncer for AsymmetricDebouncer {
+    fn update(&mut self, raw: bool, elapsed_us: u32) -> bool {
+        if raw != self.state {
+            if raw == self.last_raw {
+                self.pending_us = self.pending_us.saturating_add(elapsed_us);
+            } else {
+                self.pending_us = 0;
+            }
+
+            let settle_us = if raw { self.press_us } else { self.release_us };
+
+            if self.pending_us >= settle_us {
+                self.state = raw;
+                self.pending_us = 0;
+            }
+        } else {
+            self.pending_us = 0;
+        }
+
+        self.last_raw = raw;
+
+        self.state
+    }
+
+    fn is_pressed(&self) -> bool {
+        self.state
+    }
+}
+
+/// Waits for the raw level to be stable for the same time on press and release.
+#[derive(Clone, Copy, Debug)]
+pub struct DeferredDebouncer {
+    inner: AsymmetricDebouncer,
+}
+
+impl DeferredDebouncer {
+    pub const fn new(settle_us: u32) -> Self {
+        Self {
+            inner: AsymmetricDebouncer::new(settle_us, settle_us),
+        }
+    }
+}
+
+impl Debouncer for DeferredDebouncer {
+    fn update(&mut self, raw: bool, elapsed_us: u32) -> bool {
+        self.inner.update(raw, elapsed_us)
+    }
+
+    fn is_pressed(&self) -> bool {
+        self.inner.is_pressed()
+    }
+}
+
+/// Integrates time spent high against time spent low, switching only at the extremes.
+#[derive(Clone, Copy, Debug)]
+pub struct IntegratorDebouncer {
+    threshold_us: u32,
+    integral_us: u32,
+    state: bool,
+}
+
+impl IntegratorDebouncer {
+    pub const fn new(threshold_us: u32) -> Self {
+        Self {
+            threshold_us,
+            integral_us: 0,
+            state: false,
+        }
+    }
+}
+
+impl Debouncer for IntegratorDebouncer {
+    fn update(&mut self, raw: bool, elapsed_us: u32) -> bool {
+        if raw {
+            self.integral_us = self.integral_us.saturating_add(elapsed_us).min(self.threshold_us);
+        } else {
+            self.integral_us = self.integral_us.saturating_sub(elapsed_us);
+        }
+
+        if self.integral_us >= self.threshold_us {
+            self.state = true;
+        } else if self.integral_us == 0 {
+            self.state = false;
+        }
+
+        self.state
+    }
+
+    fn is_pressed(&self) -> bool {
+        self.state
+    }
+}
+
+/// Debounce strategy for one key.  Keys in a matrix may each use a different strategy.
+#[derive(Clone, Copy, Debug)]
+pub enum KeyDebouncer {
+    Eager(EagerDebouncer),
+    Deferred(DeferredDebouncer),
+    Asymmetric(AsymmetricDebouncer),
+    Integrator(IntegratorDebouncer),
+}
+
+pub const DEFAULT_LOCKOUT_US: u32 = 5_000;
+
+impl Default for KeyDebouncer {
+    fn default() -> Self {
+        KeyDebouncer::Eager(EagerDebouncer::new(DEFAULT_LOCKOUT_US))
+    }
+}
+
+impl Debouncer for KeyDebouncer {
+    fn update(&mut self, raw: bool, elapsed_us: u32) -> bool {
+        match self {
+            KeyDebouncer::Eager(debouncer) => debouncer.update(raw, elapsed_us),
+            KeyDebouncer::Deferred(debouncer) => debouncer.update(raw, elapsed_us),
+            KeyDebouncer::Asymmetric(debouncer) => debouncer.update(raw, elapsed_us),
+            KeyDebouncer::Integrator(debouncer) => debouncer.update(raw, elapsed_us),
+        }
+    }
+
+    fn is_pressed(&self) -> bool {
+        match self {
+            KeyDebouncer::Eager(debouncer) => debouncer.is_pressed(),
+            KeyDebouncer::Deferred(debouncer) => debouncer.is_pressed(),
+            KeyDebouncer::Asymmetric(debouncer) => debouncer.is_pressed(),
+            KeyDebouncer::Integrator(debouncer) => debouncer.is_pressed(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+
+    use more_asserts::*;
+
+    const SAMPLE_US: u32 = 500;
+
+    const SAMPLES: usize = 40;
+
+    // Press with 2ms of contact bounce, hold for 8ms, release with 2ms of bounce.
+    const BOUNCY_PRESS: [bool; SAMPLES] = [
+        false, false, true, false, true, true, false, true, // bounce on press
+        true, true, true, true, true, true, true, true, //
+        true, true, true, true, true, true, true, true, //
+        false, true, false, false, true, false, false, false, // bounce on release
+        false, false, false, false, false, false, false, false, //
+    ];
+
+    fn run<D: Debouncer>(debouncer: &mut D, waveform: &[bool]) -> [bool; SAMPLES] {
+        let mut output = [false; SAMPLES];
+
+        for (i, raw) in waveform.iter().enumerate() {
+            output[i] = debouncer.update(*raw, SAMPLE_US);
+        }
+
+        output
+    }
+
+    fn edges(output: &[bool]) -> usize {
+        output.windows(2).filter(|pair| pair[0] != pair[1]).count()
+    }
+
+    fn first_index_of(output: &[bool], level: bool, from: usize) -> usize {
+        (from..output.len()).find(|i| output[*i] == level).unwrap()
+    }
+
+    #[test]
+    fn test_eager_reacts_on_first_edge() {
+        let mut debouncer = EagerDebouncer::new(3_000);
+
+        let output = run(&mut debouncer, &BOUNCY_PRESS);
+
+        assert_eq!(first_index_of(&output, true, 0), 2);
+    }
+
+    #[test]
+    fn test_eager_ignores_bounce_during_lockout() {
+        let mut debouncer = EagerDebouncer::new(3_000);
+
+        let output = run(&mut debouncer, &BOUNCY_PRESS);
+
+        assert_eq!(edges(&output), 2);
+        assert_eq!(first_index_of(&output, false, 2), 24);
+        assert!(!debouncer.is_pressed());
+    }
+
+    #[test]
+    fn test_eager_lockout_counts_down_with_elapsed_time() {
+        let mut debouncer = EagerDebouncer::new(3_000);
+
+        debouncer.update(true, 100);
+        debouncer.update(false, 2_000);
+
+        assert!(debouncer.is_pressed());
+
+        debouncer.update(false, 1_000);
+
+        assert!(!debouncer.is_pressed());
+    }
+
+    #[test]
+    fn test_deferred_waits_for_stable_level() {
+        let mut debouncer = DeferredDebouncer::new(1_500);
+
+        let output = run(&mut debouncer, &BOUNCY_PRESS);
+
+        // Stable high from index 7 and low from index 29, each settled 3 samples later
+        assert_eq!(first_index_of(&output, true, 0), 10);
+        assert_eq!(first_index_of(&output, false, 10), 32);
+        assert_eq!(edges(&output), 2);
+    }
+
+    #[test]
+    fn test_deferred_with_zero_settle_follows_raw() {
+        let mut debouncer = DeferredDebouncer::new(0);
+
+        let output = run(&mut debouncer, &BOUNCY_PRESS);
+
+        assert_eq!(output, BOUNCY_PRESS);
+    }
+
+    #[test]
+    fn test_asymmetric_uses_separate_press_and_release_times() {
+        let mut debouncer = AsymmetricDebouncer::new(500, 1_500);
+
+        let output = run(&mut debouncer, &BOUNCY_PRESS);
+
+        // Press needs one stable sample after the edge, release needs three
+        assert_eq!(first_index_of(&output, true, 0), 5);
+        assert_eq!(first_index_of(&output, false, 5), 32);
+    }
+
+    #[test]
+    fn test_asymmetric_rejects_short_glitch() {
+        let mut debouncer = AsymmetricDebouncer::new(1_000, 1_000);
+
+        for raw in [true, false, true, false, true, false] {
+            debouncer.update(raw, SAMPLE_US);
+        }
+
+        assert!(!debouncer.is_pressed());
+    }
+
+    #[test]
+    fn test_integrator_switches_at_threshold() {
+        let mut debouncer = IntegratorDebouncer::new(2_000);
+
+        let output = run(&mut debouncer, &BOUNCY_PRESS);
+
+        assert_eq!(edges(&output), 2);
+        assert_gt!(first_index_of(&output, true, 0), 2);
+        assert!(!debouncer.is_pressed());
+    }
+
+    #[test]
+    fn test_integrator_tolerates_dropouts_while_held() {
+        let mut debouncer = IntegratorDebouncer::new(2_000);
+
+        debouncer.update(true, 2_000);
+        assert!(debouncer.is_pressed());
+
+        debouncer.update(false, 1_500);
+        debouncer.update(true, 500);
+
+        assert!(debouncer.is_pressed());
+    }
+
+    #[test]
+    fn test_key_debouncer_default_is_eager() {
+        let mut debouncer = KeyDebouncer::default();
+
+        debouncer.update(true, SAMPLE_US);
+        debouncer.update(false, SAMPLE_US);
+
+        assert!(debouncer.is_pressed());
+        assert!(matches!(debouncer, KeyDebouncer::Eager(_)));
+    }
+}
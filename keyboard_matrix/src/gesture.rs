@@ -0,0 +1,319 @@
+use crate::KeyboardState;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GestureKind {
+    /// Short press and release, reported once the double tap window has passed.
+    Tap,
+    /// The key has been held past the hold threshold.  Reported while still held.
+    Hold,
+    /// A key that reported `Hold` was released.
+    HoldRelease,
+    /// Two taps within the double tap window.
+    DoubleTap,
+    /// The key has been held past the long press threshold.  Follows `Hold`.
+    LongPress,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Gesture {
+    pub key: u8,
+    pub kind: GestureKind,
+}
+
+/// Timing for one key.  A `double_tap_ms` of 0 disables double taps so taps are reported on release.
+#[derive(Clone, Copy, Debug)]
+pub struct GestureThresholds {
+    pub hold_ms: u32,
+    pub long_press_ms: u32,
+    pub double_tap_ms: u32,
+}
+
+impl Default for GestureThresholds {
+    fn default() -> Self {
+        Self {
+            hold_ms: 400,
+            long_press_ms: 1500,
+            double_tap_ms: 250,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Phase {
+    Idle,
+    Pressed {
+        held_ms: u32,
+        hold_sent: bool,
+        long_press_sent: bool,
+        second_tap: bool,
+    },
+    TapPending {
+        since_ms: u32,
+    },
+}
+
+impl Phase {
+    const fn pressed(second_tap: bool) -> Self {
+        Phase::Pressed {
+            held_ms: 0,
+            hold_sent: false,
+            long_press_sent: false,
+            second_tap,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct KeyGesture {
+    thresholds: GestureThresholds,
+    phase: Phase,
+}
+
+/// Turns debounced key levels over time into tap, hold and double tap gestures.
+pub struct GestureRecognizer<const N: usize = 21> {
+    keys: [KeyGesture; N],
+}
+
+impl<const N: usize> Default for GestureRecognizer<N> {
+    fn default() -> Self {
+        Self {
+            keys: [KeyGesture {
+                thresholds: GestureThresholds::default(),
+                phase: Phase::Idle,
+            }; N],
+        }
+    }
+}
+
+impl<const N: usize> GestureRecognizer<N> {
+    pub fn set_thresholds(&mut self, key: usize, thresholds: GestureThresholds) {
+        self.keys[key].thresholds = thresholds;
+    }
+
+    pub fn update<const W: usize>(
+        &mut self,
+        delta_t_ms: u32,
+        keyboard_state: &KeyboardState<N, W>,
+        emit: &mut dyn FnMut(Gesture),
+    ) {
+        for (index, key) in self.keys.iter_mut().enumerate() {
+            let mut emit_kind = |kind| {
+                emit(Gesture {
+                    key: index as u8,
+                    kind,
+                })
+            };
+
+            let is_down = keyboard_state.is_down(index);
+            let thresholds = key.thresholds;
+
+            key.phase = match key.phase {
+                Phase::Idle => {
+                    if is_down {
+                        Phase::pressed(false)
+                    } else {
+                        Phase::Idle
+                    }
+                }
+                Phase::Pressed {
+                    held_ms,
+                    mut hold_sent,
+                    mut long_press_sent,
+                    mut second_tap,
+                } => {
+                    let held_ms = held_ms.saturating_add(delta_t_ms);
+
+                    if is_down {
+                        if !hold_sent && held_ms >= thresholds.hold_ms {
+                            if second_tap {
+                                // The first tap stands on its own once the second press becomes a hold
+                                emit_kind(GestureKind::Tap);
+                                second_tap = false;
+                            }
+
+                            emit_kind(GestureKind::Hold);
+                            hold_sent = true;
+                        }
+
+                        if hold_sent && !long_press_sent && held_ms >= thresholds.long_press_ms {
+                            emit_kind(GestureKind::LongPress);
+                            long_press_sent = true;
+                        }
+
+                        Phase::Pressed {
+                            held_ms,
+                            hold_sent,
+                            long_press_sent,
+                            second_tap,
+                        }
+                    } else if hold_sent {
+                        emit_kind(GestureKind::HoldRelease);
+                        Phase::Idle
+                    } else if second_tap {
+                        emit_kind(GestureKind::DoubleTap);
+                        Phase::Idle
+                    } else if thresholds.double_tap_ms == 0 {
+                        emit_kind(GestureKind::Tap);
+                        Phase::Idle
+                    } else {
+                        Phase::TapPending { since_ms: 0 }
+                    }
+                }
+                Phase::TapPending { since_ms } => {
+                    let since_ms = since_ms.saturating_add(delta_t_ms);
+
+                    if is_down && since_ms <= thresholds.double_tap_ms {
+                        Phase::pressed(true)
+                    } else if since_ms >= thresholds.double_tap_ms {
+                        emit_kind(GestureKind::Tap);
+
+                        if is_down {
+                            Phase::pressed(false)
+                        } else {
+                            Phase::Idle
+                        }
+                    } else {
+                        Phase::TapPending { since_ms }
+                    }
+                }
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+
+    use std::vec::Vec;
+
+    struct Harness {
+        recognizer: GestureRecognizer<2>,
+        keyboard_state: KeyboardState<2>,
+        gestures: Vec<Gesture>,
+    }
+
+    impl Harness {
+        fn new() -> Self {
+            Self {
+                recognizer: GestureRecognizer::default(),
+                keyboard_state: KeyboardState::default(),
+                gestures: Vec::new(),
+            }
+        }
+
+        /// Holds key 0 at `down` for `duration_ms` in 10ms steps.
+        fn run(&mut self, down: bool, duration_ms: u32) {
+            self.keyboard_state.state.set(0, down);
+
+            let gestures = &mut self.gestures;
+
+            for _ in 0..(duration_ms / 10) {
+                self.recognizer
+                    .update(10, &self.keyboard_state, &mut |gesture| gestures.push(gesture));
+            }
+        }
+
+        fn kinds(&self) -> Vec<GestureKind> {
+            self.gestures.iter().map(|gesture| gesture.kind).collect()
+        }
+    }
+
+    #[test]
+    fn test_short_press_is_tap_after_double_tap_window() {
+        let mut harness = Harness::new();
+
+        harness.run(true, 100);
+        harness.run(false, 100);
+
+        assert!(harness.kinds().is_empty());
+
+        harness.run(false, 200);
+
+        assert_eq!(harness.kinds(), [GestureKind::Tap]);
+        assert_eq!(harness.gestures[0].key, 0);
+    }
+
+    #[test]
+    fn test_tap_reported_on_release_without_double_tap() {
+        let mut harness = Harness::new();
+        harness.recognizer.set_thresholds(
+            0,
+            GestureThresholds {
+                double_tap_ms: 0,
+                ..GestureThresholds::default()
+            },
+        );
+
+        harness.run(true, 100);
+        harness.run(false, 10);
+
+        assert_eq!(harness.kinds(), [GestureKind::Tap]);
+    }
+
+    #[test]
+    fn test_two_quick_taps_are_double_tap() {
+        let mut harness = Harness::new();
+
+        harness.run(true, 50);
+        harness.run(false, 100);
+        harness.run(true, 50);
+        harness.run(false, 500);
+
+        assert_eq!(harness.kinds(), [GestureKind::DoubleTap]);
+    }
+
+    #[test]
+    fn test_hold_then_release() {
+        let mut harness = Harness::new();
+
+        harness.run(true, 500);
+
+        assert_eq!(harness.kinds(), [GestureKind::Hold]);
+
+        harness.run(false, 10);
+
+        assert_eq!(harness.kinds(), [GestureKind::Hold, GestureKind::HoldRelease]);
+    }
+
+    #[test]
+    fn test_long_press_follows_hold() {
+        let mut harness = Harness::new();
+
+        harness.run(true, 2000);
+        harness.run(false, 10);
+
+        assert_eq!(
+            harness.kinds(),
+            [GestureKind::Hold, GestureKind::LongPress, GestureKind::HoldRelease]
+        );
+    }
+
+    #[test]
+    fn test_tap_then_hold_reports_both() {
+        let mut harness = Harness::new();
+
+        harness.run(true, 50);
+        harness.run(false, 100);
+        harness.run(true, 500);
+
+        assert_eq!(harness.kinds(), [GestureKind::Tap, GestureKind::Hold]);
+    }
+
+    #[test]
+    fn test_thresholds_are_per_key() {
+        let mut harness = Harness::new();
+        harness.recognizer.set_thresholds(
+            0,
+            GestureThresholds {
+                hold_ms: 100,
+                ..GestureThresholds::default()
+            },
+        );
+
+        harness.run(true, 150);
+
+        assert_eq!(harness.kinds(), [GestureKind::Hold]);
+    }
+}
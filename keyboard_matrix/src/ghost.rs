@@ -0,0 +1,96 @@
+use crate::wiring::Wiring;
+
+/// What the scanner does with keys that form an ambiguous rectangle.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum GhostPolicy {
+    /// Report the keys in `KeyboardState::ghosted` but pass the raw reads through.
+    Flag,
+    /// Report the keys and hold them at their previous debounced level until the rectangle clears.
+    #[default]
+    Suppress,
+}
+
+fn is_pressed<const N: usize>(keystate: &[bool; N], key: u8) -> bool {
+    (key as usize) < N && keystate[key as usize]
+}
+
+/// Without per-key diodes, any two rows sharing two pressed columns form a rectangle in which one
+/// of the four keys may be a phantom.  Marks every key of such rectangles.
+pub fn ghost_mask<const ROWS: usize, const COLS: usize, const N: usize>(
+    keystate: &[bool; N],
+    wiring: &Wiring<ROWS, COLS>,
+) -> [bool; N] {
+    let mut ghosted = [false; N];
+
+    for row_a in 0..ROWS {
+        for row_b in (row_a + 1)..ROWS {
+            let shared = (0..COLS)
+                .filter(|col| is_pressed(keystate, wiring[row_a][*col]) && is_pressed(keystate, wiring[row_b][*col]))
+                .count();
+
+            if shared < 2 {
+                continue;
+            }
+
+            for col in 0..COLS {
+                if is_pressed(keystate, wiring[row_a][col]) && is_pressed(keystate, wiring[row_b][col]) {
+                    ghosted[wiring[row_a][col] as usize] = true;
+                    ghosted[wiring[row_b][col] as usize] = true;
+                }
+            }
+        }
+    }
+
+    ghosted
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+
+    use crate::wiring::KIB_WIRING;
+
+    fn pressed(keys: &[usize]) -> [bool; 21] {
+        let mut keystate = [false; 21];
+
+        for key in keys {
+            keystate[*key] = true;
+        }
+
+        keystate
+    }
+
+    #[test]
+    fn test_keys_in_one_row_are_not_ghosted() {
+        let ghosted = ghost_mask(&pressed(&[0, 1, 2, 3]), &KIB_WIRING);
+
+        assert!(!ghosted.iter().any(|g| *g));
+    }
+
+    #[test]
+    fn test_keys_sharing_one_column_are_not_ghosted() {
+        // Row A col M and row B col M
+        let ghosted = ghost_mask(&pressed(&[3, 4]), &KIB_WIRING);
+
+        assert!(!ghosted.iter().any(|g| *g));
+    }
+
+    #[test]
+    fn test_rectangle_ghosts_all_four_corners() {
+        // Rows A and B, columns M and N
+        let ghosted = ghost_mask(&pressed(&[3, 2, 4, 5, 20]), &KIB_WIRING);
+
+        for (key, is_ghosted) in ghosted.iter().enumerate() {
+            assert_eq!(*is_ghosted, [2, 3, 4, 5].contains(&key), "Key {}", key);
+        }
+    }
+
+    #[test]
+    fn test_unmapped_crossings_are_ignored() {
+        // Row E is the only row with a key on column Q
+        let ghosted = ghost_mask(&pressed(&[9, 20, 3]), &KIB_WIRING);
+
+        assert!(!ghosted.iter().any(|g| *g));
+    }
+}
@@ -0,0 +1,167 @@
+/// Bench diagnostics for one key.  Counters saturate rather than wrap.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct KeyHealth {
+    pub presses: u16,
+    pub longest_hold_ms: u32,
+    pub stuck: bool,
+    raw_edges: u16,
+    debounced_edges: u16,
+    held_us: u32,
+    last_raw: bool,
+    last_debounced: bool,
+}
+
+impl KeyHealth {
+    /// Raw edges the debouncer did not pass on.
+    pub fn rejected_bounces(&self) -> u16 {
+        self.raw_edges.saturating_sub(self.debounced_edges)
+    }
+
+    fn update(&mut self, raw: bool, debounced: bool, elapsed_us: u32, stuck_timeout_ms: u32) {
+        if raw != self.last_raw {
+            self.raw_edges = self.raw_edges.saturating_add(1);
+        }
+
+        if debounced != self.last_debounced {
+            self.debounced_edges = self.debounced_edges.saturating_add(1);
+
+            if debounced {
+                self.presses = self.presses.saturating_add(1);
+                self.held_us = 0;
+            } else {
+                self.stuck = false;
+            }
+        }
+
+        if debounced {
+            self.held_us = self.held_us.saturating_add(elapsed_us);
+
+            let held_ms = self.held_us / 1000;
+
+            if held_ms > self.longest_hold_ms {
+                self.longest_hold_ms = held_ms;
+            }
+
+            if stuck_timeout_ms > 0 && held_ms >= stuck_timeout_ms {
+                self.stuck = true;
+            }
+        }
+
+        self.last_raw = raw;
+        self.last_debounced = debounced;
+    }
+}
+
+pub const DEFAULT_STUCK_TIMEOUT_MS: u32 = 60_000;
+
+/// Tracks `KeyHealth` for every key of a matrix.
+pub struct KeyHealthMonitor<const N: usize> {
+    keys: [KeyHealth; N],
+    stuck_timeout_ms: u32,
+}
+
+impl<const N: usize> Default for KeyHealthMonitor<N> {
+    fn default() -> Self {
+        Self {
+            keys: [KeyHealth::default(); N],
+            stuck_timeout_ms: DEFAULT_STUCK_TIMEOUT_MS,
+        }
+    }
+}
+
+impl<const N: usize> KeyHealthMonitor<N> {
+    /// A timeout of 0 disables stuck key detection.
+    pub fn set_stuck_timeout_ms(&mut self, stuck_timeout_ms: u32) {
+        self.stuck_timeout_ms = stuck_timeout_ms;
+    }
+
+    pub fn update(&mut self, raw: &[bool; N], debounced: &[bool; N], elapsed_us: u32) {
+        for (key, health) in self.keys.iter_mut().enumerate() {
+            health.update(raw[key], debounced[key], elapsed_us, self.stuck_timeout_ms);
+        }
+    }
+
+    pub fn key(&self, key: usize) -> &KeyHealth {
+        &self.keys[key]
+    }
+
+    pub fn stuck_mask(&self) -> [bool; N] {
+        let mut stuck = [false; N];
+
+        for (key, health) in self.keys.iter().enumerate() {
+            stuck[key] = health.stuck;
+        }
+
+        stuck
+    }
+
+    pub fn reset(&mut self) {
+        self.keys = [KeyHealth::default(); N];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+
+    fn step(monitor: &mut KeyHealthMonitor<1>, raw: bool, debounced: bool, elapsed_us: u32) {
+        monitor.update(&[raw], &[debounced], elapsed_us);
+    }
+
+    #[test]
+    fn test_clean_press_has_no_rejected_bounces() {
+        let mut monitor = KeyHealthMonitor::<1>::default();
+
+        step(&mut monitor, true, true, 1_000);
+        step(&mut monitor, false, false, 1_000);
+
+        assert_eq!(monitor.key(0).presses, 1);
+        assert_eq!(monitor.key(0).rejected_bounces(), 0);
+    }
+
+    #[test]
+    fn test_bounces_are_counted() {
+        let mut monitor = KeyHealthMonitor::<1>::default();
+
+        for raw in [true, false, true, false, true] {
+            step(&mut monitor, raw, true, 500);
+        }
+
+        assert_eq!(monitor.key(0).presses, 1);
+        assert_eq!(monitor.key(0).rejected_bounces(), 4);
+    }
+
+    #[test]
+    fn test_longest_hold_is_kept() {
+        let mut monitor = KeyHealthMonitor::<1>::default();
+
+        step(&mut monitor, true, true, 0);
+        step(&mut monitor, true, true, 300_000);
+        step(&mut monitor, false, false, 1_000);
+        step(&mut monitor, true, true, 0);
+        step(&mut monitor, true, true, 100_000);
+
+        assert_eq!(monitor.key(0).presses, 2);
+        assert_eq!(monitor.key(0).longest_hold_ms, 300);
+    }
+
+    #[test]
+    fn test_key_held_past_timeout_is_stuck_until_released() {
+        let mut monitor = KeyHealthMonitor::<1>::default();
+        monitor.set_stuck_timeout_ms(1_000);
+
+        step(&mut monitor, true, true, 0);
+        step(&mut monitor, true, true, 999_000);
+
+        assert!(!monitor.stuck_mask()[0]);
+
+        step(&mut monitor, true, true, 1_000);
+
+        assert!(monitor.stuck_mask()[0]);
+
+        step(&mut monitor, false, false, 1_000);
+
+        assert!(!monitor.key(0).stuck);
+    }
+}
@@ -0,0 +1,228 @@
+use crate::KeyboardState;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyEventKind {
+    Press,
+    Release,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct KeyEvent {
+    pub key: u8,
+    pub kind: KeyEventKind,
+    /// Microseconds since the matrix started scanning.  Wraps after ~71 minutes.
+    pub timestamp: u32,
+}
+
+impl KeyEvent {
+    const fn empty() -> Self {
+        Self {
+            key: 0,
+            kind: KeyEventKind::Release,
+            timestamp: 0,
+        }
+    }
+}
+
+/// Returned when a reader fell more than a full queue behind and events were overwritten.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Overrun {
+    pub missed: u32,
+}
+
+/// Read position of one consumer.  Each consumer keeps its own, so they drain independently.
+#[derive(Clone, Copy, Debug)]
+pub struct KeyEventReader {
+    next_seq: u32,
+}
+
+impl KeyEventReader {
+    /// Sequence number of the next event this reader will see.  Numbers count every event
+    /// pushed to the queue, wrapping at `u32::MAX`.
+    pub fn next_seq(&self) -> u32 {
+        self.next_seq
+    }
+}
+
+/// Fixed-capacity ring of key events.  Writing never blocks; the oldest events are overwritten
+/// and readers that had not seen them get an `Overrun`.
+///
+/// The queue is kept by the caller, only when something reads it.  Fill it after each scan with
+/// `push_transitions` and `KeyboardMatrix::now_us`; a capacity of twice the key count keeps one
+/// scan from overrunning a reader.
+///
+/// `CAPACITY` must be a power of two, so slots stay in step with the sequence number when it
+/// wraps.
+pub struct KeyEventQueue<const CAPACITY: usize> {
+    events: [KeyEvent; CAPACITY],
+    next_seq: u32,
+}
+
+impl<const CAPACITY: usize> KeyEventQueue<CAPACITY> {
+    const POWER_OF_TWO: () = assert!(CAPACITY.is_power_of_two(), "KeyEventQueue capacity must be a non-zero power of two");
+}
+
+impl<const CAPACITY: usize> Default for KeyEventQueue<CAPACITY> {
+    fn default() -> Self {
+        #[allow(clippy::let_unit_value)]
+        let _ = Self::POWER_OF_TWO;
+
+        Self {
+            events: [KeyEvent::empty(); CAPACITY],
+            next_seq: 0,
+        }
+    }
+}
+
+impl<const CAPACITY: usize> KeyEventQueue<CAPACITY> {
+    pub fn push(&mut self, key: u8, kind: KeyEventKind, timestamp: u32) {
+        let seq = self.next_seq;
+
+        self.events[seq as usize % CAPACITY] = KeyEvent { key, kind, timestamp };
+
+        self.next_seq = seq.wrapping_add(1);
+    }
+
+    pub fn push_transitions<const N: usize, const W: usize>(&mut self, keyboard_state: &KeyboardState<N, W>, timestamp: u32) {
+        for key in 0..N {
+            if keyboard_state.was_pressed(key) {
+                self.push(key as u8, KeyEventKind::Press, timestamp);
+            }
+            if keyboard_state.was_released(key) {
+                self.push(key as u8, KeyEventKind::Release, timestamp);
+            }
+        }
+    }
+
+    /// A reader that will see only events pushed from now on.
+    pub fn reader(&self) -> KeyEventReader {
+        KeyEventReader {
+            next_seq: self.next_seq,
+        }
+    }
+
+    pub fn pending(&self, reader: &KeyEventReader) -> u32 {
+        self.next_seq.wrapping_sub(reader.next_seq)
+    }
+
+    /// Returns the next event for `reader`.  After an `Overrun` the reader resumes at the oldest
+    /// event still held.
+    pub fn next(&self, reader: &mut KeyEventReader) -> Result<Option<KeyEvent>, Overrun> {
+        let pending = self.pending(reader);
+
+        if pending > CAPACITY as u32 {
+            let missed = pending - CAPACITY as u32;
+            reader.next_seq = reader.next_seq.wrapping_add(missed);
+
+            return Err(Overrun { missed });
+        }
+
+        if pending == 0 {
+            return Ok(None);
+        }
+
+        let event = self.events[reader.next_seq as usize % CAPACITY];
+        reader.next_seq = reader.next_seq.wrapping_add(1);
+
+        Ok(Some(event))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+
+    #[test]
+    fn test_new_reader_has_no_events() {
+        let mut queue = KeyEventQueue::<4>::default();
+        queue.push(1, KeyEventKind::Press, 10);
+
+        let mut reader = queue.reader();
+
+        assert_eq!(queue.next(&mut reader), Ok(None));
+    }
+
+    #[test]
+    fn test_reader_sees_events_in_order() {
+        let mut queue = KeyEventQueue::<4>::default();
+        let mut reader = queue.reader();
+
+        queue.push(1, KeyEventKind::Press, 10);
+        queue.push(1, KeyEventKind::Release, 20);
+
+        let first_seq = reader.next_seq();
+        let first = queue.next(&mut reader).unwrap().unwrap();
+        let second = queue.next(&mut reader).unwrap().unwrap();
+
+        assert_eq!(first.kind, KeyEventKind::Press);
+        assert_eq!(first.timestamp, 10);
+        assert_eq!(second.kind, KeyEventKind::Release);
+        assert_eq!(reader.next_seq(), first_seq + 2);
+        assert_eq!(queue.next(&mut reader), Ok(None));
+    }
+
+    #[test]
+    fn test_readers_drain_independently() {
+        let mut queue = KeyEventQueue::<4>::default();
+        let mut synth_reader = queue.reader();
+        let mut led_reader = queue.reader();
+
+        queue.push(3, KeyEventKind::Press, 10);
+
+        assert!(queue.next(&mut synth_reader).unwrap().is_some());
+        assert_eq!(queue.pending(&synth_reader), 0);
+        assert_eq!(queue.pending(&led_reader), 1);
+        assert_eq!(queue.next(&mut led_reader).unwrap().unwrap().key, 3);
+    }
+
+    #[test]
+    fn test_overflow_is_reported() {
+        let mut queue = KeyEventQueue::<4>::default();
+        let mut reader = queue.reader();
+
+        for i in 0..6 {
+            queue.push(i, KeyEventKind::Press, i as u32);
+        }
+
+        assert_eq!(queue.next(&mut reader), Err(Overrun { missed: 2 }));
+
+        // Resumes at the oldest event still held
+        assert_eq!(queue.next(&mut reader).unwrap().unwrap().key, 2);
+    }
+
+    #[test]
+    fn test_sequence_wrap_keeps_slots_in_order() {
+        let mut queue = KeyEventQueue::<4> {
+            events: [KeyEvent::empty(); 4],
+            next_seq: u32::MAX - 1,
+        };
+        let mut reader = queue.reader();
+
+        for i in 0..4 {
+            queue.push(i, KeyEventKind::Press, i as u32);
+        }
+
+        for i in 0..4 {
+            assert_eq!(queue.next(&mut reader).unwrap().unwrap().key, i);
+        }
+    }
+
+    #[test]
+    fn test_push_transitions_adds_press_and_release() {
+        let mut queue = KeyEventQueue::<4>::default();
+        let mut reader = queue.reader();
+
+        let mut keyboard_state = KeyboardState::<21>::default();
+        keyboard_state.pressed.insert(5);
+        keyboard_state.released.insert(9);
+
+        queue.push_transitions(&keyboard_state, 100);
+
+        let press = queue.next(&mut reader).unwrap().unwrap();
+        let release = queue.next(&mut reader).unwrap().unwrap();
+
+        assert_eq!((press.key, press.kind), (5, KeyEventKind::Press));
+        assert_eq!((release.key, release.kind), (9, KeyEventKind::Release));
+    }
+}
@@ -0,0 +1,259 @@
+use core::ops::{BitAnd, BitOr, Index, Sub};
+
+/// Set of key indices packed into `W` 32 bit words.  One word covers the 21 keys of the KIB board.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeySet<const W: usize = 1> {
+    words: [u32; W],
+}
+
+impl<const W: usize> KeySet<W> {
+    pub const CAPACITY: usize = W * 32;
+
+    pub const fn new() -> Self {
+        Self { words: [0; W] }
+    }
+
+    pub fn from_bools<const N: usize>(keys: &[bool; N]) -> Self {
+        let mut set = Self::new();
+
+        for (key, is_set) in keys.iter().enumerate() {
+            set.set(key, *is_set);
+        }
+
+        set
+    }
+
+    pub fn to_bools<const N: usize>(&self) -> [bool; N] {
+        let mut keys = [false; N];
+
+        for key in self.iter().take_while(|key| *key < N) {
+            keys[key] = true;
+        }
+
+        keys
+    }
+
+    pub fn contains(&self, key: usize) -> bool {
+        key < Self::CAPACITY && self.words[key / 32] & (1 << (key % 32)) != 0
+    }
+
+    /// Keys past `CAPACITY` are ignored, as `contains` never reports them.
+    pub fn insert(&mut self, key: usize) {
+        if key < Self::CAPACITY {
+            self.words[key / 32] |= 1 << (key % 32);
+        }
+    }
+
+    pub fn remove(&mut self, key: usize) {
+        if key < Self::CAPACITY {
+            self.words[key / 32] &= !(1 << (key % 32));
+        }
+    }
+
+    pub fn set(&mut self, key: usize, is_set: bool) {
+        if is_set {
+            self.insert(key);
+        } else {
+            self.remove(key);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.words = [0; W];
+    }
+
+    pub fn len(&self) -> usize {
+        self.words.iter().map(|word| word.count_ones() as usize).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.iter().all(|word| *word == 0)
+    }
+
+    pub fn union(&self, other: &Self) -> Self {
+        self.zip(other, |a, b| a | b)
+    }
+
+    pub fn intersection(&self, other: &Self) -> Self {
+        self.zip(other, |a, b| a & b)
+    }
+
+    pub fn difference(&self, other: &Self) -> Self {
+        self.zip(other, |a, b| a & !b)
+    }
+
+    /// Indices of the keys in the set, lowest first.
+    pub fn iter(&self) -> KeySetIter<W> {
+        KeySetIter {
+            words: self.words,
+            word: 0,
+        }
+    }
+
+    /// Raw words, key 0 in the least significant bit of the first word.
+    pub fn words(&self) -> &[u32; W] {
+        &self.words
+    }
+
+    fn zip(&self, other: &Self, op: impl Fn(u32, u32) -> u32) -> Self {
+        let mut words = [0; W];
+
+        for (i, word) in words.iter_mut().enumerate() {
+            *word = op(self.words[i], other.words[i]);
+        }
+
+        Self { words }
+    }
+}
+
+impl<const W: usize> Default for KeySet<W> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const W: usize> Index<usize> for KeySet<W> {
+    type Output = bool;
+
+    fn index(&self, key: usize) -> &bool {
+        if self.contains(key) {
+            &true
+        } else {
+            &false
+        }
+    }
+}
+
+impl<const W: usize> BitOr for KeySet<W> {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        self.union(&other)
+    }
+}
+
+impl<const W: usize> BitAnd for KeySet<W> {
+    type Output = Self;
+
+    fn bitand(self, other: Self) -> Self {
+        self.intersection(&other)
+    }
+}
+
+impl<const W: usize> Sub for KeySet<W> {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        self.difference(&other)
+    }
+}
+
+pub struct KeySetIter<const W: usize> {
+    words: [u32; W],
+    word: usize,
+}
+
+impl<const W: usize> Iterator for KeySetIter<W> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        while self.word < W {
+            let bits = self.words[self.word];
+
+            if bits != 0 {
+                let bit = bits.trailing_zeros() as usize;
+                self.words[self.word] &= bits - 1;
+
+                return Some(self.word * 32 + bit);
+            }
+
+            self.word += 1;
+        }
+
+        None
+    }
+}
+
+impl<const W: usize> FromIterator<usize> for KeySet<W> {
+    fn from_iter<I: IntoIterator<Item = usize>>(keys: I) -> Self {
+        let mut set = Self::new();
+
+        for key in keys {
+            set.insert(key);
+        }
+
+        set
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+
+    use std::vec::Vec;
+
+    #[test]
+    fn test_single_word_is_four_bytes() {
+        assert_eq!(core::mem::size_of::<KeySet>(), 4);
+    }
+
+    #[test]
+    fn test_insert_remove_contains() {
+        let mut set = KeySet::<1>::new();
+
+        set.insert(20);
+        assert!(set.contains(20));
+        assert!(set[20]);
+        assert!(!set[19]);
+
+        set.remove(20);
+        assert!(set.is_empty());
+    }
+
+    #[test]
+    fn test_out_of_range_keys_are_ignored() {
+        let mut set = KeySet::<1>::new();
+
+        set.insert(32);
+        set.remove(40);
+
+        assert!(set.is_empty());
+        assert!(!set.contains(32));
+    }
+
+    #[test]
+    fn test_len_counts_keys() {
+        let set: KeySet = [0, 3, 20].into_iter().collect();
+
+        assert_eq!(set.len(), 3);
+    }
+
+    #[test]
+    fn test_iter_yields_keys_in_order_across_words() {
+        let set: KeySet<2> = [40, 1, 31, 32].into_iter().collect();
+
+        assert_eq!(set.iter().collect::<Vec<_>>(), [1, 31, 32, 40]);
+    }
+
+    #[test]
+    fn test_set_operations() {
+        let a: KeySet = [1, 2, 3].into_iter().collect();
+        let b: KeySet = [3, 4].into_iter().collect();
+
+        assert_eq!((a | b).iter().collect::<Vec<_>>(), [1, 2, 3, 4]);
+        assert_eq!((a & b).iter().collect::<Vec<_>>(), [3]);
+        assert_eq!((a - b).iter().collect::<Vec<_>>(), [1, 2]);
+    }
+
+    #[test]
+    fn test_bools_round_trip() {
+        let mut keys = [false; 21];
+        keys[0] = true;
+        keys[13] = true;
+
+        let set = KeySet::<1>::from_bools(&keys);
+
+        assert_eq!(set.to_bools::<21>(), keys);
+    }
+}
@@ -1,176 +1,89 @@
+use crate::key_set::KeySet;
+
+/// Debounced keyboard state after one scan.  `W` words of key bits must cover `N` keys.
 #[derive(Clone, Copy, Debug)]
-pub struct KeyboardState {
-    pub state: [bool; 21],
-    pub debounce_counter: [u8; 21],
-    pub pressed: [bool; 21],
-    pub released: [bool; 21],
+pub struct KeyboardState<const N: usize = 21, const W: usize = 1> {
+    pub state: KeySet<W>,
+    pub pressed: KeySet<W>,
+    pub released: KeySet<W>,
+    /// Keys that are part of an ambiguous rectangle on this scan.  See `GhostPolicy`.
+    pub ghosted: KeySet<W>,
+    /// Keys held past the stuck key timeout.  See `KeyHealthMonitor`.
+    pub stuck: KeySet<W>,
     pub depressed_count: u8,
     pub pressed_count: u8,
     pub released_count: u8,
 }
 
-const DEBOUNCE_COUNTER: u8 = 100;
+impl<const N: usize, const W: usize> KeyboardState<N, W> {
+    const FITS: () = assert!(N <= W * 32, "KeyboardState needs more words for this many keys");
+}
+
+impl<const N: usize, const W: usize> Default for KeyboardState<N, W> {
+    fn default() -> Self {
+        #[allow(clippy::let_unit_value)]
+        let _ = Self::FITS;
 
-impl KeyboardState {
-    pub fn default() -> Self {
         Self {
-            state: [false; 21],
-            debounce_counter: [0; 21],
-            pressed: [false; 21],
-            released: [false; 21],
+            state: KeySet::new(),
+            pressed: KeySet::new(),
+            released: KeySet::new(),
+            ghosted: KeySet::new(),
+            stuck: KeySet::new(),
             depressed_count: 0,
             pressed_count: 0,
             released_count: 0,
         }
     }
+}
 
-    pub fn build_new(&self, new_state: [bool; 21]) -> Self {
-        let mut debounced_state: [bool; 21] = [false; 21];
-        let mut debounce_counter: [u8; 21] = self.debounce_counter;
-        let mut pressed: [bool; 21] = [false; 21];
-        let mut released: [bool; 21] = [false; 21];
-        let mut depressed_count = 0;
-        let mut pressed_count = 0;
-        let mut released_count = 0;
-
-        for i in 0..21 {
-            if new_state[i] != self.state[i] {
-                if debounce_counter[i] == 0 {
-                    debounced_state[i] = new_state[i];
-                    debounce_counter[i] = DEBOUNCE_COUNTER;
-                } else {
-                    debounced_state[i] = self.state[i];
-                }
-            } else {
-                debounced_state[i] = self.state[i];
-            }
-
-            if debounce_counter[i] > 0 {
-                debounce_counter[i] -= 1;
-            }
-            
-            if debounced_state[i] && !self.state[i] {
-                pressed[i] = true;
-                pressed_count += 1;
-            }
-            if !debounced_state[i] && self.state[i] {
-                released[i] = true;
-                released_count += 1;
-            }
-            if debounced_state[i] {
-                depressed_count += 1;
-            }
-        }
+impl<const N: usize, const W: usize> KeyboardState<N, W> {
+    /// Builds the next state from already debounced key levels.
+    pub fn build_new(&self, debounced_state: [bool; N]) -> Self {
+        let state = KeySet::from_bools(&debounced_state);
+        let pressed = state - self.state;
+        let released = self.state - state;
 
         Self {
-            state: debounced_state,
-            debounce_counter: debounce_counter,
-            pressed: pressed,
-            released: released,
-
-            depressed_count: depressed_count,
-            pressed_count: pressed_count,
-            released_count: released_count,
+            state,
+            pressed,
+            released,
+            ghosted: KeySet::new(),
+            stuck: KeySet::new(),
+
+            depressed_count: state.len() as u8,
+            pressed_count: pressed.len() as u8,
+            released_count: released.len() as u8,
         }
     }
-}
-
-#[cfg(test)]
-mod tests {
-    extern crate std;
-    use super::*;
-
-    use more_asserts::*;
-
-    #[test]
-    fn test_new_state_reflects_change_when_debounce_is_zero() {
-        let mut before_state = KeyboardState::default();
-        before_state.state[0] = false;
-        before_state.debounce_counter[0] = 0;
-
-        let mut new_state: [bool; 21] = [false; 21];
-        new_state[0] = true;
-
-        let result = before_state.build_new(new_state);
 
-        assert_eq!(result.state[0], true);
+    pub fn is_down(&self, key: usize) -> bool {
+        self.state.contains(key)
     }
 
-    #[test]
-    fn test_state_change_sets_debounce() {
-        let mut before_state = KeyboardState::default();
-        before_state.state[0] = false;
-        before_state.debounce_counter[0] = 0;
-
-        let mut new_state: [bool; 21] = [false; 21];
-        new_state[0] = true;
-
-        let result = before_state.build_new(new_state);
-
-        assert_gt!(result.debounce_counter[0], 0);
+    pub fn was_pressed(&self, key: usize) -> bool {
+        self.pressed.contains(key)
     }
 
-    #[test]
-    fn test_new_state_does_not_change_when_debounce_is_gt_zero() {
-        let mut before_state = KeyboardState::default();
-        before_state.state[0] = false;
-        before_state.debounce_counter[0] = 5;
-
-        let mut new_state: [bool; 21] = [false; 21];
-        new_state[0] = true;
-
-        let result = before_state.build_new(new_state);
-
-        assert_eq!(result.state[0], false);
+    pub fn was_released(&self, key: usize) -> bool {
+        self.released.contains(key)
     }
 
-    #[test]
-    fn test_debounce_decrements_when_state_matches() {
-        let mut before_state = KeyboardState::default();
-        before_state.state[0] = false;
-        before_state.debounce_counter[0] = 5;
-
-        let mut new_state: [bool; 21] = [false; 21];
-        new_state[0] = false;
-
-        let result = before_state.build_new(new_state);
-
-        assert_eq!(result.debounce_counter[0], 4);
-    }
-
-    #[test]
-    fn test_debounce_decrements_when_state_changes() {
-        let mut before_state = KeyboardState::default();
-        before_state.state[0] = false;
-        before_state.debounce_counter[0] = 5;
-
-        let mut new_state: [bool; 21] = [false; 21];
-        new_state[0] = true;
-
-        let result = before_state.build_new(new_state);
-
-        assert_eq!(result.debounce_counter[0], 4);
+    /// Down and neither ghosted nor stuck.
+    pub fn is_trusted_down(&self, key: usize) -> bool {
+        self.state.contains(key) && !self.ghosted.contains(key) && !self.stuck.contains(key)
     }
+}
 
-    #[test]
-    fn test_debounce_stays_zero_without_state_change() {
-        let mut before_state = KeyboardState::default();
-        before_state.state[0] = false;
-        before_state.debounce_counter[0] = 0;
-
-        let mut new_state: [bool; 21] = [false; 21];
-        new_state[0] = false;
-
-        let result = before_state.build_new(new_state);
-
-        assert_eq!(result.debounce_counter[0], 0);
-    }
+#[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
+mod tests {
+    extern crate std;
+    use super::*;
 
     #[test]
     fn test_pressed_counter_reflects_newly_pressed_item() {
-        let mut before_state = KeyboardState::default();
-        before_state.state[0] = false;
-        before_state.debounce_counter[0] = 0;
+        let before_state = KeyboardState::<21>::default();
 
         let mut new_state: [bool; 21] = [false; 21];
         new_state[0] = true;
@@ -182,9 +95,7 @@ mod tests {
 
     #[test]
     fn test_pressed_reflects_newly_pressed_item() {
-        let mut before_state = KeyboardState::default();
-        before_state.state[0] = false;
-        before_state.debounce_counter[0] = 0;
+        let before_state = KeyboardState::<21>::default();
 
         let mut new_state: [bool; 21] = [false; 21];
         new_state[0] = true;
@@ -196,9 +107,8 @@ mod tests {
 
     #[test]
     fn test_pressed_omits_previously_pressed_item() {
-        let mut before_state = KeyboardState::default();
-        before_state.state[0] = true;
-        before_state.debounce_counter[0] = 0;
+        let mut before_state = KeyboardState::<21>::default();
+        before_state.state.insert(0);
 
         let mut new_state: [bool; 21] = [false; 21];
         new_state[0] = true;
@@ -210,9 +120,8 @@ mod tests {
 
     #[test]
     fn test_released_counter_reflects_newly_released_item() {
-        let mut before_state = KeyboardState::default();
-        before_state.state[0] = true;
-        before_state.debounce_counter[0] = 0;
+        let mut before_state = KeyboardState::<21>::default();
+        before_state.state.insert(0);
 
         let mut new_state: [bool; 21] = [false; 21];
         new_state[0] = false;
@@ -224,9 +133,8 @@ mod tests {
 
     #[test]
     fn test_released_reflects_newly_released_item() {
-        let mut before_state = KeyboardState::default();
-        before_state.state[0] = true;
-        before_state.debounce_counter[0] = 0;
+        let mut before_state = KeyboardState::<21>::default();
+        before_state.state.insert(0);
 
         let mut new_state: [bool; 21] = [false; 21];
         new_state[0] = false;
@@ -238,9 +146,7 @@ mod tests {
 
     #[test]
     fn test_preleased_omits_previously_released_item() {
-        let mut before_state = KeyboardState::default();
-        before_state.state[0] = false;
-        before_state.debounce_counter[0] = 0;
+        let before_state = KeyboardState::<21>::default();
 
         let mut new_state: [bool; 21] = [false; 21];
         new_state[0] = false;
@@ -249,4 +155,22 @@ mod tests {
 
         assert_eq!(result.released[0], false);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_state_for_21_keys_fits_in_24_bytes() {
+        assert!(core::mem::size_of::<KeyboardState>() <= 24);
+    }
+
+    #[test]
+    fn test_larger_board_uses_more_words() {
+        let before_state = KeyboardState::<40, 2>::default();
+
+        let mut new_state: [bool; 40] = [false; 40];
+        new_state[39] = true;
+
+        let result = before_state.build_new(new_state);
+
+        assert!(result.was_pressed(39));
+        assert_eq!(result.depressed_count, 1);
+    }
+}
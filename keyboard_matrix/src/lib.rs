@@ -1,126 +1,321 @@
 #![no_std]
 
+mod debounce;
+mod gesture;
+mod ghost;
+mod health;
+mod key_event;
+mod key_set;
 mod keyboard_state;
+mod recording;
+mod scan_error;
+mod wiring;
 
+#[cfg(any(test, feature = "sim"))]
+pub mod sim;
+
+pub use crate::debounce::{
+    AsymmetricDebouncer, Debouncer, DeferredDebouncer, EagerDebouncer, IntegratorDebouncer, KeyDebouncer,
+};
+pub use crate::gesture::{Gesture, GestureKind, GestureRecognizer, GestureThresholds};
+pub use crate::ghost::{ghost_mask, GhostPolicy};
+pub use crate::health::{KeyHealth, KeyHealthMonitor};
+pub use crate::key_event::{KeyEvent, KeyEventKind, KeyEventQueue, KeyEventReader, Overrun};
+pub use crate::key_set::{KeySet, KeySetIter};
 pub use crate::keyboard_state::KeyboardState;
+pub use crate::recording::{
+    max_record_size, Recorder, RecordingError, Replay, ReplayFrame, RECORDING_HEADER_SIZE, RECORDING_MAGIC,
+    RECORDING_VERSION,
+};
+pub use crate::scan_error::{ReadFailurePolicy, ScanError};
+pub use crate::wiring::{Wiring, KIB_KEY_COUNT, KIB_WIRING, NO_KEY};
 use embedded_hal::digital::v2::{InputPin, OutputPin};
 use embedded_hal::blocking::delay::DelayUs;
 
-pub struct KeyboardMatrix<ROWA, ROWB, ROWC, ROWD, ROWE, COLM, COLN, COLO, COLP, COLQ> {
-    row_a: ROWA,
-    row_b: ROWB,
-    row_c: ROWC,
-    row_d: ROWD,
-    row_e: ROWE,
-
-    col_n: COLN,
-    col_m: COLM,
-    col_o: COLO,
-    col_p: COLP,
-    col_q: COLQ,
+pub struct KeyboardMatrix<ROW, COL, const ROWS: usize, const COLS: usize, const N: usize = KIB_KEY_COUNT, const W: usize = 1> {
+    rows: [ROW; ROWS],
+    cols: [COL; COLS],
+    wiring: Wiring<ROWS, COLS>,
+    debouncers: [KeyDebouncer; N],
+    ghost_policy: GhostPolicy,
+    read_failure_policy: ReadFailurePolicy,
+    failed_reads: u32,
+    health: KeyHealthMonitor<N>,
 
-    keyboard_state: KeyboardState,
+    keyboard_state: KeyboardState<N, W>,
+    now_us: u32,
+    /// Time from aborted scans, not yet seen by the debouncers.
+    carried_us: u32,
 }
 
 const SETTLE_DELAY_US: u16 = 1;
 
-impl<ROWA, ROWB, ROWC, ROWD, ROWE, COLM, COLN, COLO, COLP, COLQ>
-    KeyboardMatrix<ROWA, ROWB, ROWC, ROWD, ROWE, COLM, COLN, COLO, COLP, COLQ>
+impl<ROW, COL, E, const ROWS: usize, const COLS: usize, const N: usize, const W: usize>
+    KeyboardMatrix<ROW, COL, ROWS, COLS, N, W>
 where
-    ROWA: OutputPin,
-    ROWB: OutputPin,
-    ROWC: OutputPin,
-    ROWD: OutputPin,
-    ROWE: OutputPin,
-    COLM: InputPin,
-    COLN: InputPin,
-    COLO: InputPin,
-    COLP: InputPin,
-    COLQ: InputPin,
+    ROW: OutputPin<Error = E>,
+    COL: InputPin<Error = E>,
 {
-    pub fn new(
-        row_a: ROWA,
-        row_b: ROWB,
-        row_c: ROWC,
-        row_d: ROWD,
-        row_e: ROWE,
-
-        col_n: COLN,
-        col_m: COLM,
-        col_o: COLO,
-        col_p: COLP,
-        col_q: COLQ,
-    ) -> Self {
+    pub fn new(rows: [ROW; ROWS], cols: [COL; COLS], wiring: Wiring<ROWS, COLS>) -> Self {
         Self {
-            row_a,
-            row_b,
-            row_c,
-            row_d,
-            row_e,
-
-            col_n,
-            col_m,
-            col_o,
-            col_p,
-            col_q,
+            rows,
+            cols,
+            wiring,
+            debouncers: [KeyDebouncer::default(); N],
+            ghost_policy: GhostPolicy::default(),
+            read_failure_policy: ReadFailurePolicy::default(),
+            failed_reads: 0,
+            health: KeyHealthMonitor::default(),
 
             keyboard_state: KeyboardState::default(),
+            now_us: 0,
+            carried_us: 0,
         }
     }
 
-    
+    pub fn set_debouncer(&mut self, key: usize, debouncer: KeyDebouncer) {
+        self.debouncers[key] = debouncer;
+    }
+
+    pub fn set_ghost_policy(&mut self, ghost_policy: GhostPolicy) {
+        self.ghost_policy = ghost_policy;
+    }
 
-    pub fn scan(&mut self, delay: &mut dyn DelayUs<u16>) -> KeyboardState {
-        let mut keystate: [bool; 21] = [false; 21];
+    pub fn set_read_failure_policy(&mut self, read_failure_policy: ReadFailurePolicy) {
+        self.read_failure_policy = read_failure_policy;
+    }
 
-        self.row_a.set_high().ok();
+    /// Pin operations that failed and were tolerated under `ReadFailurePolicy::ReusePrevious`.
+    pub fn failed_reads(&self) -> u32 {
+        self.failed_reads
+    }
 
-        keystate[0] = self.col_p.is_high().ok().unwrap();
-        keystate[1] = self.col_o.is_high().ok().unwrap();
-        keystate[2] = self.col_n.is_high().ok().unwrap();
-        keystate[3] = self.col_m.is_high().ok().unwrap();
+    pub fn health(&self) -> &KeyHealthMonitor<N> {
+        &self.health
+    }
 
-        self.row_a.set_low().ok();
-        delay.delay_us(SETTLE_DELAY_US);
-        self.row_b.set_high().ok();
+    pub fn health_mut(&mut self) -> &mut KeyHealthMonitor<N> {
+        &mut self.health
+    }
 
-        keystate[4] = self.col_m.is_high().ok().unwrap();
-        keystate[5] = self.col_n.is_high().ok().unwrap();
-        keystate[6] = self.col_o.is_high().ok().unwrap();
-        keystate[7] = self.col_p.is_high().ok().unwrap();
+    /// Microseconds of scanning so far, as used for key event and recording timestamps.
+    pub fn now_us(&self) -> u32 {
+        self.now_us
+    }
 
-        self.row_b.set_low().ok();
-        delay.delay_us(SETTLE_DELAY_US);
-        self.row_c.set_high().ok();
+    /// Scans the matrix.  `elapsed_us` is the time since the previous scan and drives debouncing.
+    /// Time from scans that returned an error is added to the next successful one.
+    pub fn scan(&mut self, delay: &mut dyn DelayUs<u16>, elapsed_us: u32) -> Result<KeyboardState<N, W>, ScanError<E>> {
+        let mut keystate: [bool; N] = [false; N];
+        let previous: [bool; N] = self.keyboard_state.state.to_bools();
+        let abort = self.read_failure_policy == ReadFailurePolicy::Abort;
 
-        keystate[11] = self.col_n.is_high().ok().unwrap();
-        keystate[12] = self.col_o.is_high().ok().unwrap();
-        keystate[13] = self.col_p.is_high().ok().unwrap();
-        keystate[14] = self.col_m.is_high().ok().unwrap();
+        self.carried_us = self.carried_us.saturating_add(elapsed_us);
 
-        self.row_c.set_low().ok();
-        delay.delay_us(SETTLE_DELAY_US);
-        self.row_d.set_high().ok();
+        for (row_index, row) in self.rows.iter_mut().enumerate() {
+            if row_index > 0 {
+                delay.delay_us(SETTLE_DELAY_US);
+            }
 
-        keystate[10] = self.col_o.is_high().ok().unwrap();
-        keystate[15] = self.col_m.is_high().ok().unwrap();
-        keystate[16] = self.col_p.is_high().ok().unwrap();
-        keystate[17] = self.col_n.is_high().ok().unwrap();
+            if let Err(error) = row.set_high() {
+                if abort {
+                    return Err(ScanError::Row { row: row_index, error });
+                }
 
-        self.row_d.set_low().ok();
-        delay.delay_us(SETTLE_DELAY_US);
-        self.row_e.set_high().ok();
+                for key in self.wiring[row_index].iter() {
+                    if (*key as usize) < N {
+                        keystate[*key as usize] = previous[*key as usize];
+                    }
+                }
 
-        keystate[8] = self.col_o.is_high().ok().unwrap();
-        keystate[9] = self.col_m.is_high().ok().unwrap();
-        keystate[18] = self.col_n.is_high().ok().unwrap();
-        keystate[19] = self.col_p.is_high().ok().unwrap();
-        keystate[20] = self.col_q.is_high().ok().unwrap();
+                self.failed_reads = self.failed_reads.wrapping_add(1);
+
+                continue;
+            }
+
+            for (col_index, col) in self.cols.iter().enumerate() {
+                let key = self.wiring[row_index][col_index] as usize;
+
+                if key >= N {
+                    continue;
+                }
+
+                match col.is_high() {
+                    Ok(level) => keystate[key] = level,
+                    Err(error) => {
+                        if abort {
+                            row.set_low().ok();
+
+                            return Err(ScanError::Column {
+                                row: row_index,
+                                col: col_index,
+                                error,
+                            });
+                        }
+
+                        keystate[key] = previous[key];
+                        self.failed_reads = self.failed_reads.wrapping_add(1);
+                    }
+                }
+            }
+
+            if let Err(error) = row.set_low() {
+                if abort {
+                    return Err(ScanError::Row { row: row_index, error });
+                }
+
+                self.failed_reads = self.failed_reads.wrapping_add(1);
+            }
+        }
 
-        self.row_e.set_low().ok();
+        let elapsed_us = self.carried_us;
+        self.carried_us = 0;
+        self.now_us = self.now_us.wrapping_add(elapsed_us);
+
+        let raw = keystate;
+        let ghosted = ghost_mask(&raw, &self.wiring);
+
+        for (key, debouncer) in self.debouncers.iter_mut().enumerate() {
+            let level = if ghosted[key] && self.ghost_policy == GhostPolicy::Suppress {
+                debouncer.is_pressed()
+            } else {
+                raw[key]
+            };
+
+            keystate[key] = debouncer.update(level, elapsed_us);
+        }
+
+        self.health.update(&raw, &keystate, elapsed_us);
 
         self.keyboard_state = self.keyboard_state.build_new(keystate);
+        self.keyboard_state.ghosted = KeySet::from_bools(&ghosted);
+        self.keyboard_state.stuck = KeySet::from_bools(&self.health.stuck_mask());
+
+        Ok(self.keyboard_state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+
+    struct TestPin {
+        high: bool,
+        fail: bool,
+    }
+
+    impl TestPin {
+        fn new(high: bool, fail: bool) -> Self {
+            Self { high, fail }
+        }
+
+        fn result<T>(&self, value: T) -> Result<T, ()> {
+            if self.fail {
+                Err(())
+            } else {
+                Ok(value)
+            }
+        }
+    }
+
+    impl OutputPin for TestPin {
+        type Error = ();
+
+        fn set_low(&mut self) -> Result<(), ()> {
+            self.result(())
+        }
+
+        fn set_high(&mut self) -> Result<(), ()> {
+            self.result(())
+        }
+    }
+
+    impl InputPin for TestPin {
+        type Error = ();
+
+        fn is_high(&self) -> Result<bool, ()> {
+            self.result(self.high)
+        }
+
+        fn is_low(&self) -> Result<bool, ()> {
+            self.result(!self.high)
+        }
+    }
+
+    struct NoDelay;
+
+    impl DelayUs<u16> for NoDelay {
+        fn delay_us(&mut self, _us: u16) {}
+    }
+
+    const WIRING: Wiring<1, 2> = [[0, 1]];
+
+    fn matrix(col_1_fails: bool) -> KeyboardMatrix<TestPin, TestPin, 1, 2, 2> {
+        KeyboardMatrix::new(
+            [TestPin::new(false, false)],
+            [TestPin::new(true, false), TestPin::new(true, col_1_fails)],
+            WIRING,
+        )
+    }
+
+    #[test]
+    fn test_scan_reads_wired_keys() {
+        let mut matrix = matrix(false);
+
+        let result = matrix.scan(&mut NoDelay, 1_000).unwrap();
+
+        assert!(result.state[0]);
+        assert!(result.state[1]);
+    }
+
+    #[test]
+    fn test_failed_column_read_reports_row_and_column() {
+        let mut matrix = matrix(true);
+
+        let result = matrix.scan(&mut NoDelay, 1_000);
+
+        assert_eq!(result.err(), Some(ScanError::Column { row: 0, col: 1, error: () }));
+    }
+
+    #[test]
+    fn test_failed_row_drive_reports_row() {
+        let mut matrix: KeyboardMatrix<TestPin, TestPin, 1, 2, 2> =
+            KeyboardMatrix::new([TestPin::new(false, true)], [TestPin::new(true, false), TestPin::new(true, false)], WIRING);
+
+        let result = matrix.scan(&mut NoDelay, 1_000);
+
+        assert_eq!(result.err(), Some(ScanError::Row { row: 0, error: () }));
+    }
+
+    #[test]
+    fn test_aborted_scan_time_reaches_debouncers() {
+        let mut matrix = matrix(false);
+        matrix.scan(&mut NoDelay, 1_000).unwrap();
+
+        matrix.cols[1].fail = true;
+        for _ in 0..3 {
+            assert!(matrix.scan(&mut NoDelay, 2_000).is_err());
+        }
+
+        // The 6ms of failed scans cover the 5ms lockout from the press
+        matrix.cols[1].fail = false;
+        matrix.cols[0].high = false;
+        let result = matrix.scan(&mut NoDelay, 0).unwrap();
+
+        assert!(!result.state[0]);
+        assert_eq!(matrix.now_us(), 7_000);
+    }
+
+    #[test]
+    fn test_reuse_previous_keeps_previous_state_for_failed_key() {
+        let mut matrix = matrix(true);
+        matrix.set_read_failure_policy(ReadFailurePolicy::ReusePrevious);
+
+        let result = matrix.scan(&mut NoDelay, 1_000).unwrap();
 
-        return self.keyboard_state;
+        assert!(result.state[0]);
+        assert!(!result.state[1]);
+        assert_eq!(matrix.failed_reads(), 1);
     }
 }
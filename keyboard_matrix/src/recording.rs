@@ -0,0 +1,417 @@
+//! Compact binary log of key state transitions, for capturing playing patterns on hardware and
+//! replaying them in host tests.
+//!
+//! Layout, all multi-byte values little endian:
+//!
+//! ```text
+//! header:  b"KQ" | version: u8 | key count: u8 | scan interval us: u32
+//! record:  delta_us: LEB128 u32 | key bits | ghosted bits | stuck bits
+//! ```
+//!
+//! Each set of bits is ceil(key count / 8) bytes, key 0 in the LSB.
+//!
+//! A record is written only when the debounced key state, ghosted or stuck keys change.
+//! `delta_us` is the time since the previous record, or since recording started for the first
+//! one.  Replay fills the time between records with unchanged frames at the scan interval, so
+//! state that advances once per scan, such as a note going from pressed to sustained, plays
+//! back as it did live.
+
+use crate::key_set::KeySet;
+use crate::KeyboardState;
+
+pub const RECORDING_MAGIC: [u8; 2] = *b"KQ";
+pub const RECORDING_VERSION: u8 = 2;
+pub const RECORDING_HEADER_SIZE: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RecordingError {
+    BufferTooSmall,
+    BadMagic,
+    UnsupportedVersion(u8),
+    KeyCountMismatch { expected: usize, found: usize },
+    Truncated,
+}
+
+const fn key_bytes(key_count: usize) -> usize {
+    key_count.div_ceil(8)
+}
+
+/// Largest record for `N` keys: a five byte delta plus the key, ghosted and stuck bits.
+pub const fn max_record_size(key_count: usize) -> usize {
+    5 + 3 * key_bytes(key_count)
+}
+
+fn write_keys<const W: usize>(keys: &KeySet<W>, out: &mut [u8]) {
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = (keys.words()[i / 4] >> ((i % 4) * 8)) as u8;
+    }
+}
+
+fn read_keys<const N: usize, const W: usize>(bits: &[u8]) -> KeySet<W> {
+    (0..N).filter(|key| bits[key / 8] & (1 << (key % 8)) != 0).collect()
+}
+
+pub struct Recorder<const N: usize = 21, const W: usize = 1> {
+    scan_interval_us: u32,
+    last_state: KeySet<W>,
+    last_ghosted: KeySet<W>,
+    last_stuck: KeySet<W>,
+    last_timestamp_us: u32,
+}
+
+impl<const N: usize, const W: usize> Recorder<N, W> {
+    const KEYS_FIT: () = assert!(N <= W * 32 && N <= 255, "Recorder needs W words for its keys and at most 255 keys");
+
+    /// `scan_interval_us` is the usual time between scans, used by replay to step through the
+    /// time between records.
+    pub fn new(start_timestamp_us: u32, scan_interval_us: u32) -> Self {
+        #[allow(clippy::let_unit_value)]
+        let _ = Self::KEYS_FIT;
+
+        Self {
+            scan_interval_us,
+            last_state: KeySet::new(),
+            last_ghosted: KeySet::new(),
+            last_stuck: KeySet::new(),
+            last_timestamp_us: start_timestamp_us,
+        }
+    }
+
+    pub fn write_header(&self, out: &mut [u8]) -> Result<usize, RecordingError> {
+        if out.len() < RECORDING_HEADER_SIZE {
+            return Err(RecordingError::BufferTooSmall);
+        }
+
+        out[0] = RECORDING_MAGIC[0];
+        out[1] = RECORDING_MAGIC[1];
+        out[2] = RECORDING_VERSION;
+        out[3] = N as u8;
+        out[4..8].copy_from_slice(&self.scan_interval_us.to_le_bytes());
+
+        Ok(RECORDING_HEADER_SIZE)
+    }
+
+    /// Writes a record if the key state or masks changed since the last one.  Returns the bytes
+    /// written.
+    pub fn record(
+        &mut self,
+        keyboard_state: &KeyboardState<N, W>,
+        timestamp_us: u32,
+        out: &mut [u8],
+    ) -> Result<usize, RecordingError> {
+        if keyboard_state.state == self.last_state
+            && keyboard_state.ghosted == self.last_ghosted
+            && keyboard_state.stuck == self.last_stuck
+        {
+            return Ok(0);
+        }
+
+        let delta_us = timestamp_us.wrapping_sub(self.last_timestamp_us);
+
+        let mut delta = [0u8; 5];
+        let delta_size = write_varint(delta_us, &mut delta);
+        let bytes = key_bytes(N);
+        let size = delta_size + 3 * bytes;
+
+        if out.len() < size {
+            return Err(RecordingError::BufferTooSmall);
+        }
+
+        out[..delta_size].copy_from_slice(&delta[..delta_size]);
+
+        let keys = &mut out[delta_size..size];
+        write_keys(&keyboard_state.state, &mut keys[..bytes]);
+        write_keys(&keyboard_state.ghosted, &mut keys[bytes..2 * bytes]);
+        write_keys(&keyboard_state.stuck, &mut keys[2 * bytes..]);
+
+        self.last_state = keyboard_state.state;
+        self.last_ghosted = keyboard_state.ghosted;
+        self.last_stuck = keyboard_state.stuck;
+        self.last_timestamp_us = timestamp_us;
+
+        Ok(size)
+    }
+}
+
+fn write_varint(mut value: u32, out: &mut [u8; 5]) -> usize {
+    let mut size = 0;
+
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+
+        if value == 0 {
+            out[size] = byte;
+            return size + 1;
+        }
+
+        out[size] = byte | 0x80;
+        size += 1;
+    }
+}
+
+fn read_varint(data: &[u8]) -> Option<(u32, usize)> {
+    let mut value: u32 = 0;
+
+    for (i, byte) in data.iter().enumerate().take(5) {
+        value |= ((byte & 0x7f) as u32) << (i * 7);
+
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+
+    None
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ReplayFrame<const N: usize, const W: usize> {
+    /// Time since the previous frame.
+    pub delta_us: u32,
+    pub keyboard_state: KeyboardState<N, W>,
+}
+
+/// Reads a recording back as a sequence of keyboard states, one per scan, ready to feed to the
+/// synth and illumination engines.
+pub struct Replay<'a, const N: usize = 21, const W: usize = 1> {
+    data: &'a [u8],
+    position: usize,
+    scan_interval_us: u32,
+    keyboard_state: KeyboardState<N, W>,
+    /// Unchanged frames still to emit before `pending`.
+    repeats: u32,
+    pending: Option<ReplayFrame<N, W>>,
+}
+
+impl<'a, const N: usize, const W: usize> Replay<'a, N, W> {
+    pub fn new(data: &'a [u8]) -> Result<Self, RecordingError> {
+        if data.len() < RECORDING_HEADER_SIZE {
+            return Err(RecordingError::Truncated);
+        }
+
+        if data[0..2] != RECORDING_MAGIC {
+            return Err(RecordingError::BadMagic);
+        }
+
+        if data[2] != RECORDING_VERSION {
+            return Err(RecordingError::UnsupportedVersion(data[2]));
+        }
+
+        if data[3] as usize != N {
+            return Err(RecordingError::KeyCountMismatch {
+                expected: N,
+                found: data[3] as usize,
+            });
+        }
+
+        Ok(Self {
+            data,
+            position: RECORDING_HEADER_SIZE,
+            scan_interval_us: u32::from_le_bytes([data[4], data[5], data[6], data[7]]),
+            keyboard_state: KeyboardState::default(),
+            repeats: 0,
+            pending: None,
+        })
+    }
+
+    pub fn scan_interval_us(&self) -> u32 {
+        self.scan_interval_us
+    }
+
+    fn next_record(&mut self) -> Result<Option<ReplayFrame<N, W>>, RecordingError> {
+        let remaining = &self.data[self.position..];
+
+        if remaining.is_empty() {
+            return Ok(None);
+        }
+
+        let (delta_us, delta_size) = read_varint(remaining).ok_or(RecordingError::Truncated)?;
+
+        let bytes = key_bytes(N);
+        let bits = remaining
+            .get(delta_size..delta_size + 3 * bytes)
+            .ok_or(RecordingError::Truncated)?;
+
+        self.position += delta_size + 3 * bytes;
+
+        Ok(Some(ReplayFrame {
+            delta_us,
+            keyboard_state: KeyboardState {
+                state: read_keys::<N, W>(&bits[..bytes]),
+                ghosted: read_keys::<N, W>(&bits[bytes..2 * bytes]),
+                stuck: read_keys::<N, W>(&bits[2 * bytes..]),
+                ..KeyboardState::default()
+            },
+        }))
+    }
+
+    /// Steps the keyboard state to `next`, as a scan `delta_us` after the last frame.
+    fn frame(&mut self, delta_us: u32, next: &KeyboardState<N, W>) -> ReplayFrame<N, W> {
+        let mut keyboard_state = self.keyboard_state.build_new(next.state.to_bools());
+        keyboard_state.ghosted = next.ghosted;
+        keyboard_state.stuck = next.stuck;
+
+        self.keyboard_state = keyboard_state;
+
+        ReplayFrame { delta_us, keyboard_state }
+    }
+}
+
+impl<const N: usize, const W: usize> Iterator for Replay<'_, N, W> {
+    type Item = Result<ReplayFrame<N, W>, RecordingError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pending.is_none() {
+            let record = match self.next_record() {
+                Ok(Some(record)) => record,
+                Ok(None) => return None,
+                Err(error) => {
+                    // Records cannot be found again after a broken one, so the replay ends here
+                    self.position = self.data.len();
+
+                    return Some(Err(error));
+                }
+            };
+
+            // Whole scans before the change, leaving the change at most one interval later
+            self.repeats = match self.scan_interval_us {
+                0 => 0,
+                interval => record.delta_us.saturating_sub(1) / interval,
+            };
+            self.pending = Some(ReplayFrame {
+                delta_us: record.delta_us - self.repeats * self.scan_interval_us,
+                ..record
+            });
+        }
+
+        if self.repeats > 0 {
+            self.repeats -= 1;
+
+            let unchanged = self.keyboard_state;
+
+            return Some(Ok(self.frame(self.scan_interval_us, &unchanged)));
+        }
+
+        let record = self.pending.take()?;
+
+        Some(Ok(self.frame(record.delta_us, &record.keyboard_state)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+
+    use std::vec::Vec;
+
+    fn state_with(keys: &[usize]) -> KeyboardState {
+        let mut bools = [false; 21];
+
+        for key in keys {
+            bools[*key] = true;
+        }
+
+        KeyboardState::<21>::default().build_new(bools)
+    }
+
+    fn record_all(states: &[(u32, KeyboardState)]) -> Vec<u8> {
+        let mut recorder = Recorder::<21>::new(0, 1_000);
+        let mut out = [0u8; 64];
+
+        let mut size = recorder.write_header(&mut out).unwrap();
+
+        for (timestamp_us, keyboard_state) in states {
+            size += recorder.record(keyboard_state, *timestamp_us, &mut out[size..]).unwrap();
+        }
+
+        out[..size].to_vec()
+    }
+
+    #[test]
+    fn test_recording_format_is_stable() {
+        let mut stuck = state_with(&[20]);
+        stuck.stuck.insert(20);
+
+        let recording = record_all(&[(1_000, state_with(&[0, 13])), (300_000, stuck)]);
+
+        assert_eq!(
+            recording,
+            [
+                b'K', b'Q', 2, 21, 0xe8, 0x03, 0x00, 0x00, //
+                0xe8, 0x07, 0x01, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, //
+                0xf8, 0x9f, 0x12, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unchanged_state_writes_nothing() {
+        let mut recorder = Recorder::<21>::new(0, 1_000);
+        let mut out = [0u8; 16];
+
+        let mut keyboard_state = state_with(&[3]);
+
+        assert_eq!(recorder.record(&keyboard_state, 10, &mut out), Ok(10));
+        assert_eq!(recorder.record(&keyboard_state, 20, &mut out), Ok(0));
+
+        keyboard_state.ghosted.insert(3);
+
+        assert_eq!(recorder.record(&keyboard_state, 30, &mut out), Ok(10));
+    }
+
+    #[test]
+    fn test_replay_restores_states_and_transitions() {
+        let recording = record_all(&[(1_000, state_with(&[13])), (3_000, state_with(&[13, 14])), (3_500, state_with(&[]))]);
+
+        let frames: Vec<_> = Replay::<21>::new(&recording).unwrap().map(|frame| frame.unwrap()).collect();
+
+        assert_eq!(frames.len(), 4);
+        assert!(frames[0].keyboard_state.was_pressed(13));
+
+        // One unchanged scan between the records
+        assert_eq!(frames[1].delta_us, 1_000);
+        assert!(frames[1].keyboard_state.is_down(13));
+        assert_eq!(frames[1].keyboard_state.pressed_count, 0);
+
+        assert_eq!(frames[2].delta_us, 1_000);
+        assert!(frames[2].keyboard_state.was_pressed(14));
+        assert!(!frames[2].keyboard_state.was_pressed(13));
+
+        assert_eq!(frames[3].delta_us, 500);
+        assert_eq!(frames[3].keyboard_state.released_count, 2);
+    }
+
+    #[test]
+    fn test_replay_restores_masks_on_every_frame() {
+        let mut ghosted = state_with(&[5, 6]);
+        ghosted.ghosted.insert(6);
+
+        let recording = record_all(&[(1_000, ghosted), (4_000, state_with(&[]))]);
+
+        let frames: Vec<_> = Replay::<21>::new(&recording).unwrap().map(|frame| frame.unwrap()).collect();
+
+        assert_eq!(frames.len(), 4);
+        assert!(frames[..3].iter().all(|frame| frame.keyboard_state.ghosted.contains(6)));
+        assert!(frames[3].keyboard_state.ghosted.is_empty());
+        assert_eq!(frames.iter().map(|frame| frame.delta_us).sum::<u32>(), 4_000);
+    }
+
+    #[test]
+    fn test_replay_rejects_other_versions_and_boards() {
+        assert!(matches!(Replay::<21>::new(b"XX\x02\x15\0\0\0\0"), Err(RecordingError::BadMagic)));
+        assert!(matches!(Replay::<21>::new(b"KQ\x01\x15\0\0\0\0"), Err(RecordingError::UnsupportedVersion(1))));
+        assert!(matches!(
+            Replay::<21>::new(b"KQ\x02\x10\0\0\0\0"),
+            Err(RecordingError::KeyCountMismatch { expected: 21, found: 16 })
+        ));
+    }
+
+    #[test]
+    fn test_replay_reports_truncated_record() {
+        let mut replay = Replay::<21>::new(b"KQ\x02\x15\0\0\0\0\x10\x01").unwrap();
+
+        assert!(matches!(replay.next(), Some(Err(RecordingError::Truncated))));
+        assert!(replay.next().is_none());
+    }
+}
@@ -0,0 +1,18 @@
+/// A pin operation that failed during `KeyboardMatrix::scan`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScanError<E> {
+    /// Driving the row high or low failed.
+    Row { row: usize, error: E },
+    /// Reading the column while `row` was driven failed.
+    Column { row: usize, col: usize, error: E },
+}
+
+/// What `KeyboardMatrix::scan` does when a pin operation fails.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ReadFailurePolicy {
+    /// Stop scanning and return the error.  The keyboard state is not updated.
+    #[default]
+    Abort,
+    /// Keep scanning.  Keys whose read failed reuse their state from the previous scan.
+    ReusePrevious,
+}
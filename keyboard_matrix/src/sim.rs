@@ -0,0 +1,463 @@
+//! Virtual pins for exercising `KeyboardMatrix::scan` on the host.
+//!
+//! Row and column pins share a `VirtualGrid` of switches through a `RefCell`.  The grid models
+//! contact bounce, ghosting on matrices without diodes and rows that are read before a previously
+//! driven row has settled.
+
+use core::cell::RefCell;
+
+use embedded_hal::blocking::delay::DelayUs;
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+
+/// Injected pin failure.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SimError;
+
+/// Switch change applied once the grid clock reaches `at_us`.
+#[derive(Clone, Copy, Debug)]
+pub struct ScriptStep {
+    pub at_us: u32,
+    pub row: usize,
+    pub col: usize,
+    pub pressed: bool,
+}
+
+pub struct VirtualGrid<const ROWS: usize, const COLS: usize> {
+    switches: [[bool; COLS]; ROWS],
+    bounce: [[&'static [bool]; COLS]; ROWS],
+    diodes: bool,
+    settle_us: u32,
+    now_us: u32,
+    row_high: [bool; ROWS],
+    row_released_at: [Option<u32>; ROWS],
+    failing_rows: [bool; ROWS],
+    failing_cols: [bool; COLS],
+    script: &'static [ScriptStep],
+    script_index: usize,
+    settle_violations: u32,
+    overlapping_rows: u32,
+}
+
+impl<const ROWS: usize, const COLS: usize> Default for VirtualGrid<ROWS, COLS> {
+    fn default() -> Self {
+        Self {
+            switches: [[false; COLS]; ROWS],
+            bounce: [[&[]; COLS]; ROWS],
+            diodes: true,
+            settle_us: 0,
+            now_us: 0,
+            row_high: [false; ROWS],
+            row_released_at: [None; ROWS],
+            failing_rows: [false; ROWS],
+            failing_cols: [false; COLS],
+            script: &[],
+            script_index: 0,
+            settle_violations: 0,
+            overlapping_rows: 0,
+        }
+    }
+}
+
+impl<const ROWS: usize, const COLS: usize> VirtualGrid<ROWS, COLS> {
+    pub fn set_key(&mut self, row: usize, col: usize, pressed: bool) {
+        self.switches[row][col] = pressed;
+    }
+
+    /// The next reads of this crossing return `pattern`, one level per read, before the switch
+    /// level applies again.
+    pub fn set_bounce(&mut self, row: usize, col: usize, pattern: &'static [bool]) {
+        self.bounce[row][col] = pattern;
+    }
+
+    /// Without diodes current can flow backwards through pressed switches, so three keys at the
+    /// corners of a rectangle also read the fourth.
+    pub fn set_diodes(&mut self, diodes: bool) {
+        self.diodes = diodes;
+    }
+
+    /// How long a row keeps reading as driven after it is set low.
+    pub fn set_settle_us(&mut self, settle_us: u32) {
+        self.settle_us = settle_us;
+    }
+
+    pub fn set_script(&mut self, script: &'static [ScriptStep]) {
+        self.script = script;
+        self.script_index = 0;
+    }
+
+    pub fn fail_row(&mut self, row: usize, fail: bool) {
+        self.failing_rows[row] = fail;
+    }
+
+    pub fn fail_col(&mut self, col: usize, fail: bool) {
+        self.failing_cols[col] = fail;
+    }
+
+    pub fn now_us(&self) -> u32 {
+        self.now_us
+    }
+
+    /// Column reads that saw a row which had not settled since being set low.
+    pub fn settle_violations(&self) -> u32 {
+        self.settle_violations
+    }
+
+    /// Column reads made while more than one row was driven high.
+    pub fn overlapping_rows(&self) -> u32 {
+        self.overlapping_rows
+    }
+
+    pub fn advance(&mut self, us: u32) {
+        self.now_us = self.now_us.wrapping_add(us);
+
+        while let Some(step) = self.script.get(self.script_index) {
+            if step.at_us > self.now_us {
+                break;
+            }
+
+            self.switches[step.row][step.col] = step.pressed;
+            self.script_index += 1;
+        }
+    }
+
+    fn set_row(&mut self, row: usize, high: bool) -> Result<(), SimError> {
+        if self.failing_rows[row] {
+            return Err(SimError);
+        }
+
+        if self.row_high[row] && !high {
+            self.row_released_at[row] = Some(self.now_us);
+        }
+
+        self.row_high[row] = high;
+
+        Ok(())
+    }
+
+    fn is_row_settling(&self, row: usize) -> bool {
+        match self.row_released_at[row] {
+            Some(released_at) => self.now_us.wrapping_sub(released_at) < self.settle_us,
+            None => false,
+        }
+    }
+
+    fn read_col(&mut self, col: usize) -> Result<bool, SimError> {
+        if self.failing_cols[col] {
+            return Err(SimError);
+        }
+
+        let mut active = [false; ROWS];
+        let mut driven = 0;
+
+        for (row, is_active) in active.iter_mut().enumerate() {
+            if self.row_high[row] {
+                driven += 1;
+                *is_active = true;
+            } else if self.is_row_settling(row) {
+                self.settle_violations += 1;
+                *is_active = true;
+            }
+        }
+
+        if driven > 1 {
+            self.overlapping_rows += 1;
+        }
+
+        let mut closed = self.switches;
+
+        for (row, is_active) in active.iter().enumerate() {
+            if *is_active {
+                if let Some((level, rest)) = self.bounce[row][col].split_first() {
+                    closed[row][col] = *level;
+                    self.bounce[row][col] = rest;
+                }
+            }
+        }
+
+        if self.diodes {
+            return Ok((0..ROWS).any(|row| active[row] && closed[row][col]));
+        }
+
+        // Spread the driven level through closed switches until nothing changes
+        let mut high_rows = active;
+        let mut high_cols = [false; COLS];
+        let mut changed = true;
+
+        while changed {
+            changed = false;
+
+            for row in 0..ROWS {
+                for c in 0..COLS {
+                    if closed[row][c] && high_rows[row] != high_cols[c] {
+                        high_rows[row] = true;
+                        high_cols[c] = true;
+                        changed = true;
+                    }
+                }
+            }
+        }
+
+        Ok(high_cols[col])
+    }
+}
+
+pub struct SimRow<'a, const ROWS: usize, const COLS: usize> {
+    grid: &'a RefCell<VirtualGrid<ROWS, COLS>>,
+    row: usize,
+}
+
+pub struct SimCol<'a, const ROWS: usize, const COLS: usize> {
+    grid: &'a RefCell<VirtualGrid<ROWS, COLS>>,
+    col: usize,
+}
+
+/// Advances the grid clock instead of sleeping.
+pub struct SimDelay<'a, const ROWS: usize, const COLS: usize> {
+    grid: &'a RefCell<VirtualGrid<ROWS, COLS>>,
+}
+
+impl<const ROWS: usize, const COLS: usize> OutputPin for SimRow<'_, ROWS, COLS> {
+    type Error = SimError;
+
+    fn set_low(&mut self) -> Result<(), SimError> {
+        self.grid.borrow_mut().set_row(self.row, false)
+    }
+
+    fn set_high(&mut self) -> Result<(), SimError> {
+        self.grid.borrow_mut().set_row(self.row, true)
+    }
+}
+
+impl<const ROWS: usize, const COLS: usize> InputPin for SimCol<'_, ROWS, COLS> {
+    type Error = SimError;
+
+    fn is_high(&self) -> Result<bool, SimError> {
+        self.grid.borrow_mut().read_col(self.col)
+    }
+
+    fn is_low(&self) -> Result<bool, SimError> {
+        self.is_high().map(|high| !high)
+    }
+}
+
+impl<const ROWS: usize, const COLS: usize> DelayUs<u16> for SimDelay<'_, ROWS, COLS> {
+    fn delay_us(&mut self, us: u16) {
+        self.grid.borrow_mut().advance(us as u32);
+    }
+}
+
+type SimPins<'a, const ROWS: usize, const COLS: usize> = ([SimRow<'a, ROWS, COLS>; ROWS], [SimCol<'a, ROWS, COLS>; COLS]);
+
+/// Row and column pins wired to `grid`, ready for `KeyboardMatrix::new`.
+pub fn pins<const ROWS: usize, const COLS: usize>(grid: &RefCell<VirtualGrid<ROWS, COLS>>) -> SimPins<'_, ROWS, COLS> {
+    let rows = core::array::from_fn(|row| SimRow { grid, row });
+    let cols = core::array::from_fn(|col| SimCol { grid, col });
+
+    (rows, cols)
+}
+
+pub fn delay<const ROWS: usize, const COLS: usize>(grid: &RefCell<VirtualGrid<ROWS, COLS>>) -> SimDelay<'_, ROWS, COLS> {
+    SimDelay { grid }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+
+    use crate::{GhostPolicy, KeyDebouncer, KeyboardMatrix, ReadFailurePolicy, ScanError, KIB_WIRING};
+
+    type KibSim<'a> = KeyboardMatrix<SimRow<'a, 5, 5>, SimCol<'a, 5, 5>, 5, 5>;
+
+    const ROW_A: usize = 0;
+    const ROW_B: usize = 1;
+    const ROW_C: usize = 2;
+    const ROW_D: usize = 3;
+    const ROW_E: usize = 4;
+    const COL_M: usize = 0;
+    const COL_N: usize = 1;
+    const COL_O: usize = 2;
+    const COL_P: usize = 3;
+    const COL_Q: usize = 4;
+
+    // (row, col, key) as laid out by the original hand written scan
+    const KIB_CROSSINGS: [(usize, usize, usize); 21] = [
+        (ROW_A, COL_P, 0),
+        (ROW_A, COL_O, 1),
+        (ROW_A, COL_N, 2),
+        (ROW_A, COL_M, 3),
+        (ROW_B, COL_M, 4),
+        (ROW_B, COL_N, 5),
+        (ROW_B, COL_O, 6),
+        (ROW_B, COL_P, 7),
+        (ROW_C, COL_N, 11),
+        (ROW_C, COL_O, 12),
+        (ROW_C, COL_P, 13),
+        (ROW_C, COL_M, 14),
+        (ROW_D, COL_O, 10),
+        (ROW_D, COL_M, 15),
+        (ROW_D, COL_P, 16),
+        (ROW_D, COL_N, 17),
+        (ROW_E, COL_O, 8),
+        (ROW_E, COL_M, 9),
+        (ROW_E, COL_N, 18),
+        (ROW_E, COL_P, 19),
+        (ROW_E, COL_Q, 20),
+    ];
+
+    fn kib(grid: &RefCell<VirtualGrid<5, 5>>) -> KibSim<'_> {
+        let (rows, cols) = pins(grid);
+
+        let mut matrix = KeyboardMatrix::new(rows, cols, KIB_WIRING);
+
+        for key in 0..21 {
+            matrix.set_debouncer(key, KeyDebouncer::Eager(crate::EagerDebouncer::new(0)));
+        }
+
+        matrix
+    }
+
+    #[test]
+    fn test_each_crossing_maps_to_its_key() {
+        for (row, col, key) in KIB_CROSSINGS {
+            let grid = RefCell::new(VirtualGrid::<5, 5>::default());
+            let mut matrix = kib(&grid);
+
+            grid.borrow_mut().set_key(row, col, true);
+
+            let state = matrix.scan(&mut delay(&grid), 1_000).unwrap();
+
+            for index in 0..21 {
+                assert_eq!(state.state[index], index == key, "Crossing {},{} read key {}", row, col, index);
+            }
+        }
+    }
+
+    #[test]
+    fn test_scan_drives_one_row_at_a_time_and_leaves_rows_low() {
+        let grid = RefCell::new(VirtualGrid::<5, 5>::default());
+        let mut matrix = kib(&grid);
+
+        matrix.scan(&mut delay(&grid), 1_000).unwrap();
+
+        assert_eq!(grid.borrow().overlapping_rows(), 0);
+        assert!(!grid.borrow().row_high.iter().any(|high| *high));
+    }
+
+    #[test]
+    fn test_scan_respects_one_microsecond_settle_time() {
+        let grid = RefCell::new(VirtualGrid::<5, 5>::default());
+        grid.borrow_mut().set_settle_us(1);
+        let mut matrix = kib(&grid);
+
+        matrix.scan(&mut delay(&grid), 1_000).unwrap();
+
+        assert_eq!(grid.borrow().settle_violations(), 0);
+    }
+
+    #[test]
+    fn test_slow_rows_bleed_into_next_row() {
+        let grid = RefCell::new(VirtualGrid::<5, 5>::default());
+        grid.borrow_mut().set_settle_us(10);
+        grid.borrow_mut().set_key(ROW_A, COL_P, true);
+        let mut matrix = kib(&grid);
+
+        let state = matrix.scan(&mut delay(&grid), 1_000).unwrap();
+
+        assert!(grid.borrow().settle_violations() > 0);
+        // Row B reads column P while row A is still high
+        assert!(state.state[7]);
+    }
+
+    #[test]
+    fn test_bounce_is_filtered_by_debouncer() {
+        static BOUNCE: [bool; 4] = [true, false, true, false];
+
+        let grid = RefCell::new(VirtualGrid::<5, 5>::default());
+        grid.borrow_mut().set_bounce(ROW_B, COL_M, &BOUNCE);
+        let (rows, cols) = pins(&grid);
+        let mut matrix: KibSim = KeyboardMatrix::new(rows, cols, KIB_WIRING);
+
+        let mut presses = 0;
+
+        // Long enough for the default lockout to pass and the release to be seen
+        for _ in 0..12 {
+            let state = matrix.scan(&mut delay(&grid), 500).unwrap();
+
+            if state.pressed[4] {
+                presses += 1;
+            }
+        }
+
+        assert_eq!(presses, 1);
+        assert_eq!(matrix.health().key(4).rejected_bounces(), 2);
+    }
+
+    #[test]
+    fn test_rectangle_without_diodes_is_ghosted() {
+        let grid = RefCell::new(VirtualGrid::<5, 5>::default());
+        grid.borrow_mut().set_diodes(false);
+        grid.borrow_mut().set_key(ROW_A, COL_M, true);
+        grid.borrow_mut().set_key(ROW_A, COL_N, true);
+        grid.borrow_mut().set_key(ROW_B, COL_M, true);
+        let mut matrix = kib(&grid);
+        matrix.set_ghost_policy(GhostPolicy::Flag);
+
+        let state = matrix.scan(&mut delay(&grid), 1_000).unwrap();
+
+        // Row B col N is the phantom
+        assert!(state.state[5]);
+        assert!(state.ghosted[5]);
+    }
+
+    #[test]
+    fn test_rectangle_without_diodes_is_suppressed() {
+        let grid = RefCell::new(VirtualGrid::<5, 5>::default());
+        grid.borrow_mut().set_diodes(false);
+        grid.borrow_mut().set_key(ROW_A, COL_M, true);
+        grid.borrow_mut().set_key(ROW_A, COL_N, true);
+        grid.borrow_mut().set_key(ROW_B, COL_M, true);
+        let mut matrix = kib(&grid);
+
+        let state = matrix.scan(&mut delay(&grid), 1_000).unwrap();
+
+        assert!(!state.state[5]);
+    }
+
+    #[test]
+    fn test_script_presses_and_releases_key() {
+        static SCRIPT: [ScriptStep; 2] = [
+            ScriptStep { at_us: 2_000, row: ROW_E, col: COL_Q, pressed: true },
+            ScriptStep { at_us: 5_000, row: ROW_E, col: COL_Q, pressed: false },
+        ];
+
+        let grid = RefCell::new(VirtualGrid::<5, 5>::default());
+        grid.borrow_mut().set_script(&SCRIPT);
+        let mut matrix = kib(&grid);
+
+        let mut levels = [false; 6];
+
+        for level in levels.iter_mut() {
+            grid.borrow_mut().advance(1_000);
+            *level = matrix.scan(&mut delay(&grid), 1_000).unwrap().state[20];
+        }
+
+        assert_eq!(levels, [false, true, true, true, false, false]);
+    }
+
+    #[test]
+    fn test_failing_column_surfaces_scan_error() {
+        let grid = RefCell::new(VirtualGrid::<5, 5>::default());
+        grid.borrow_mut().fail_col(COL_Q, true);
+        let mut matrix = kib(&grid);
+
+        assert_eq!(
+            matrix.scan(&mut delay(&grid), 1_000).err(),
+            Some(ScanError::Column { row: ROW_E, col: COL_Q, error: SimError })
+        );
+
+        matrix.set_read_failure_policy(ReadFailurePolicy::ReusePrevious);
+
+        assert!(matrix.scan(&mut delay(&grid), 1_000).is_ok());
+    }
+}
@@ -0,0 +1,38 @@
+/// Marks a (row, column) crossing with no switch attached.
+pub const NO_KEY: u8 = 255;
+
+/// Maps each (row, column) crossing of the matrix to a logical key index.
+pub type Wiring<const ROWS: usize, const COLS: usize> = [[u8; COLS]; ROWS];
+
+/// Wiring for the KIB board.  Rows A-E, columns M, N, O, P, Q.
+pub const KIB_WIRING: Wiring<5, 5> = [
+    [3, 2, 1, 0, NO_KEY],
+    [4, 5, 6, 7, NO_KEY],
+    [14, 11, 12, 13, NO_KEY],
+    [15, 17, 10, 16, NO_KEY],
+    [9, 18, 8, 19, 20],
+];
+
+pub const KIB_KEY_COUNT: usize = 21;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kib_wiring_maps_every_key_once() {
+        let mut seen = [0u8; KIB_KEY_COUNT];
+
+        for row in KIB_WIRING.iter() {
+            for key in row.iter() {
+                if *key != NO_KEY {
+                    seen[*key as usize] += 1;
+                }
+            }
+        }
+
+        for (key, count) in seen.iter().enumerate() {
+            assert_eq!(*count, 1, "Key {} mapped {} times", key, count);
+        }
+    }
+}
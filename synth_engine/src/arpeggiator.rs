@@ -0,0 +1,429 @@
+use crate::{NoteSet, NUM_NOTES};
+
+/// Chords larger than this only arpeggiate their first notes.  The keymap allows 13 note keys.
+pub const MAX_ARP_NOTES: usize = 16;
+
+/// MIDI clock resolution, used as the common unit of the note divisions.
+pub(crate) const TICKS_PER_QUARTER: u32 = 24;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ArpPattern {
+    Up,
+    Down,
+    /// Up then down without repeating the top and bottom notes.
+    UpDown,
+    /// In the order the keys were pressed.
+    AsPlayed,
+    Random,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NoteDivision {
+    Quarter,
+    Eighth,
+    Sixteenth,
+    ThirtySecond,
+    QuarterTriplet,
+    EighthTriplet,
+    SixteenthTriplet,
+}
+
+impl NoteDivision {
+    pub(crate) fn ticks(&self) -> u32 {
+        match self {
+            NoteDivision::Quarter => 24,
+            NoteDivision::Eighth => 12,
+            NoteDivision::Sixteenth => 6,
+            NoteDivision::ThirtySecond => 3,
+            NoteDivision::QuarterTriplet => 16,
+            NoteDivision::EighthTriplet => 8,
+            NoteDivision::SixteenthTriplet => 4,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ArpSettings {
+    pub pattern: ArpPattern,
+    /// Octaves the chord is repeated over, 1 - 4.
+    pub octaves: u8,
+    pub division: NoteDivision,
+    /// Portion of each step the note sounds for, 1 - 100 percent.
+    pub gate_percent: u8,
+    pub tempo_bpm: u16,
+}
+
+const PATTERNS: [ArpPattern; 5] = [
+    ArpPattern::Up,
+    ArpPattern::Down,
+    ArpPattern::UpDown,
+    ArpPattern::AsPlayed,
+    ArpPattern::Random,
+];
+
+const DIVISIONS: [NoteDivision; 7] = [
+    NoteDivision::Quarter,
+    NoteDivision::Eighth,
+    NoteDivision::Sixteenth,
+    NoteDivision::ThirtySecond,
+    NoteDivision::QuarterTriplet,
+    NoteDivision::EighthTriplet,
+    NoteDivision::SixteenthTriplet,
+];
+
+impl ArpSettings {
+    /// Bus layout: pattern, octaves, division, gate percent, tempo low byte, tempo high byte.
+    /// Patterns and divisions are numbered in declaration order.
+    pub fn from_bytes(bytes: &[u8; 6]) -> Option<Self> {
+        Some(Self {
+            pattern: *PATTERNS.get(bytes[0] as usize)?,
+            octaves: bytes[1],
+            division: *DIVISIONS.get(bytes[2] as usize)?,
+            gate_percent: bytes[3],
+            tempo_bpm: u16::from_le_bytes([bytes[4], bytes[5]]),
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; 6] {
+        let pattern = PATTERNS.iter().position(|pattern| *pattern == self.pattern).unwrap_or(0);
+        let division = DIVISIONS.iter().position(|division| *division == self.division).unwrap_or(0);
+        let [tempo_low, tempo_high] = self.tempo_bpm.to_le_bytes();
+
+        [pattern as u8, self.octaves, division as u8, self.gate_percent, tempo_low, tempo_high]
+    }
+}
+
+impl Default for ArpSettings {
+    fn default() -> Self {
+        Self {
+            pattern: ArpPattern::Up,
+            octaves: 1,
+            division: NoteDivision::Sixteenth,
+            gate_percent: 50,
+            tempo_bpm: 120,
+        }
+    }
+}
+
+/// Steps through the held notes in time.  Time is only advanced by the caller, so the output is
+/// fully determined by the sequence of held notes and time deltas.
+pub struct Arpeggiator {
+    settings: ArpSettings,
+    /// Held note indexes in the order they were pressed.
+    held: [u8; MAX_ARP_NOTES],
+    held_count: usize,
+    step: u32,
+    /// Time into the current step, in units of 1 / (TICKS_PER_QUARTER * tempo) ms.
+    elapsed: u32,
+    random_state: u32,
+    random_note: Option<u8>,
+}
+
+impl Arpeggiator {
+    pub fn new(settings: ArpSettings) -> Self {
+        let mut arpeggiator = Self {
+            settings,
+            held: [0; MAX_ARP_NOTES],
+            held_count: 0,
+            step: 0,
+            elapsed: 0,
+            random_state: 0x2545_f491,
+            random_note: None,
+        };
+
+        arpeggiator.set_settings(settings);
+
+        arpeggiator
+    }
+
+    pub fn settings(&self) -> &ArpSettings {
+        &self.settings
+    }
+
+    /// Takes effect from the next step.  Out of range values are clamped.
+    pub fn set_settings(&mut self, settings: ArpSettings) {
+        self.settings = ArpSettings {
+            octaves: settings.octaves.clamp(1, 4),
+            gate_percent: settings.gate_percent.clamp(1, 100),
+            tempo_bpm: settings.tempo_bpm.clamp(20, 300),
+            ..settings
+        };
+    }
+
+    /// Updates the chord from the notes currently held.  Releasing every note restarts the
+    /// pattern on the next chord.
+    pub fn set_held(&mut self, held: &NoteSet) {
+        // Drop released notes, keeping the press order of the rest
+        let mut kept = 0;
+
+        for i in 0..self.held_count {
+            let note_index = self.held[i];
+
+            if held.contains(note_index as usize) {
+                self.held[kept] = note_index;
+                kept += 1;
+            }
+        }
+
+        self.held_count = kept;
+
+        for note_index in held.iter() {
+            if self.held_count < MAX_ARP_NOTES && !self.held[..self.held_count].contains(&(note_index as u8)) {
+                self.held[self.held_count] = note_index as u8;
+                self.held_count += 1;
+            }
+        }
+
+        if self.held_count == 0 {
+            self.step = 0;
+            self.elapsed = 0;
+            self.random_note = None;
+        }
+    }
+
+    /// Moves time forward and returns the note index sounding now, if any.
+    pub fn advance(&mut self, delta_t_ms: u32) -> Option<u8> {
+        if self.held_count == 0 {
+            return None;
+        }
+
+        let step_length = 60_000 * self.settings.division.ticks();
+        let gate_length = step_length / 100 * self.settings.gate_percent as u32;
+
+        // Saturates rather than overflowing after a long stall
+        let ticks = delta_t_ms.saturating_mul(TICKS_PER_QUARTER * self.settings.tempo_bpm as u32);
+        self.elapsed = self.elapsed.saturating_add(ticks);
+
+        if self.elapsed >= step_length {
+            self.step = self.step.wrapping_add(self.elapsed / step_length);
+            self.elapsed %= step_length;
+            self.random_note = None;
+        }
+
+        if self.elapsed >= gate_length {
+            return None;
+        }
+
+        self.note_for_step()
+    }
+
+    fn note_for_step(&mut self) -> Option<u8> {
+        let mut sequence = [0u8; MAX_ARP_NOTES * 4];
+        let length = self.sequence(&mut sequence);
+
+        if length == 0 {
+            return None;
+        }
+
+        let step = self.step as usize;
+
+        let index = match self.settings.pattern {
+            ArpPattern::Up | ArpPattern::AsPlayed => step % length,
+            ArpPattern::Down => length - 1 - step % length,
+            ArpPattern::UpDown => {
+                let period = (2 * length - 2).max(1);
+                let position = step % period;
+
+                if position < length {
+                    position
+                } else {
+                    period - position
+                }
+            }
+            ArpPattern::Random => {
+                if self.random_note.is_none() {
+                    self.random_note = Some(sequence[self.next_random() as usize % length]);
+                }
+
+                return self.random_note;
+            }
+        };
+
+        Some(sequence[index])
+    }
+
+    /// Writes the held notes repeated over the octave range, sorted unless playing in press order.
+    fn sequence(&self, sequence: &mut [u8; MAX_ARP_NOTES * 4]) -> usize {
+        let mut chord = [0u8; MAX_ARP_NOTES];
+        let chord = &mut chord[..self.held_count];
+        chord.copy_from_slice(&self.held[..self.held_count]);
+
+        if self.settings.pattern != ArpPattern::AsPlayed {
+            chord.sort_unstable();
+        }
+
+        let mut length = 0;
+
+        for octave in 0..self.settings.octaves {
+            for note_index in chord.iter() {
+                let note_index = *note_index as usize + octave as usize * 12;
+
+                if note_index < NUM_NOTES {
+                    sequence[length] = note_index as u8;
+                    length += 1;
+                }
+            }
+        }
+
+        length
+    }
+
+    /// xorshift32, seeded the same every time so host tests are repeatable.
+    fn next_random(&mut self) -> u32 {
+        let mut x = self.random_state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.random_state = x;
+
+        x
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use super::*;
+
+    use std::vec::Vec;
+
+    fn held(note_indexes: &[u8]) -> NoteSet {
+        note_indexes.iter().map(|note_index| *note_index as usize).collect()
+    }
+
+    /// Note at the start of each of `steps` sixteenths at 125 bpm (120ms).
+    fn run(arpeggiator: &mut Arpeggiator, steps: usize) -> Vec<Option<u8>> {
+        let mut notes = Vec::new();
+
+        notes.push(arpeggiator.advance(0));
+
+        for _ in 1..steps {
+            notes.push(arpeggiator.advance(120));
+        }
+
+        notes
+    }
+
+    fn settings(pattern: ArpPattern, octaves: u8) -> ArpSettings {
+        ArpSettings {
+            pattern,
+            octaves,
+            tempo_bpm: 125,
+            ..ArpSettings::default()
+        }
+    }
+
+    #[test]
+    fn up_pattern_over_two_octaves() {
+        let mut arpeggiator = Arpeggiator::new(settings(ArpPattern::Up, 2));
+        arpeggiator.set_held(&held(&[40, 36, 43]));
+
+        assert_eq!(
+            run(&mut arpeggiator, 7),
+            [Some(36), Some(40), Some(43), Some(48), Some(52), Some(55), Some(36)]
+        );
+    }
+
+    #[test]
+    fn long_stall_does_not_overflow() {
+        let mut arpeggiator = Arpeggiator::new(ArpSettings {
+            tempo_bpm: 300,
+            ..ArpSettings::default()
+        });
+        arpeggiator.set_held(&held(&[36]));
+
+        arpeggiator.advance(u32::MAX);
+
+        assert!(arpeggiator.elapsed < 60_000 * arpeggiator.settings.division.ticks());
+    }
+
+    #[test]
+    fn down_and_up_down_patterns() {
+        let mut arpeggiator = Arpeggiator::new(settings(ArpPattern::Down, 1));
+        arpeggiator.set_held(&held(&[36, 40, 43]));
+
+        assert_eq!(run(&mut arpeggiator, 4), [Some(43), Some(40), Some(36), Some(43)]);
+
+        let mut arpeggiator = Arpeggiator::new(settings(ArpPattern::UpDown, 1));
+        arpeggiator.set_held(&held(&[36, 40, 43]));
+
+        assert_eq!(run(&mut arpeggiator, 6), [Some(36), Some(40), Some(43), Some(40), Some(36), Some(40)]);
+    }
+
+    #[test]
+    fn as_played_keeps_press_order() {
+        let mut arpeggiator = Arpeggiator::new(settings(ArpPattern::AsPlayed, 1));
+        arpeggiator.set_held(&held(&[43]));
+        arpeggiator.set_held(&held(&[43, 36]));
+        arpeggiator.set_held(&held(&[43, 36, 40]));
+
+        assert_eq!(run(&mut arpeggiator, 3), [Some(43), Some(36), Some(40)]);
+    }
+
+    #[test]
+    fn random_is_repeatable_and_holds_for_a_step() {
+        let mut first = Arpeggiator::new(settings(ArpPattern::Random, 2));
+        let mut second = Arpeggiator::new(settings(ArpPattern::Random, 2));
+        first.set_held(&held(&[36, 40, 43]));
+        second.set_held(&held(&[36, 40, 43]));
+
+        assert_eq!(run(&mut first, 16), run(&mut second, 16));
+
+        let note = first.advance(120);
+
+        assert_eq!(first.advance(10), note);
+    }
+
+    #[test]
+    fn gate_closes_part_way_through_step() {
+        let mut arpeggiator = Arpeggiator::new(ArpSettings {
+            gate_percent: 25,
+            ..settings(ArpPattern::Up, 1)
+        });
+        arpeggiator.set_held(&held(&[36]));
+
+        assert_eq!(arpeggiator.advance(0), Some(36));
+        assert_eq!(arpeggiator.advance(29), Some(36));
+        assert_eq!(arpeggiator.advance(1), None);
+        assert_eq!(arpeggiator.advance(90), Some(36));
+    }
+
+    #[test]
+    fn releasing_chord_restarts_pattern() {
+        let mut arpeggiator = Arpeggiator::new(settings(ArpPattern::Up, 1));
+        arpeggiator.set_held(&held(&[36, 40]));
+
+        run(&mut arpeggiator, 2);
+
+        arpeggiator.set_held(&held(&[]));
+
+        assert_eq!(arpeggiator.advance(50), None);
+
+        arpeggiator.set_held(&held(&[36, 40]));
+
+        assert_eq!(arpeggiator.advance(0), Some(36));
+    }
+
+    #[test]
+    fn settings_bytes_round_trip() {
+        let settings = ArpSettings {
+            pattern: ArpPattern::AsPlayed,
+            octaves: 3,
+            division: NoteDivision::EighthTriplet,
+            gate_percent: 80,
+            tempo_bpm: 140,
+        };
+
+        assert_eq!(settings.to_bytes(), [3, 3, 5, 80, 140, 0]);
+        assert_eq!(ArpSettings::from_bytes(&settings.to_bytes()), Some(settings));
+        assert_eq!(ArpSettings::from_bytes(&[5, 1, 0, 50, 120, 0]), None);
+    }
+
+    #[test]
+    fn octaves_stop_at_top_note() {
+        let mut arpeggiator = Arpeggiator::new(settings(ArpPattern::Up, 4));
+        arpeggiator.set_held(&held(&[84]));
+
+        assert_eq!(run(&mut arpeggiator, 3), [Some(84), Some(96), Some(84)]);
+    }
+}
@@ -0,0 +1,370 @@
+//! Integer only oscillators mixing the sounding notes into sample buffers, for a DAC or PWM
+//! output.  Nothing here depends on the target, so the same output can be rendered on the host.
+
+use crate::{phase_increment, SynthState, NUM_NOTES};
+
+pub const WAVETABLE_SIZE: usize = 256;
+
+/// One period of a sine, from Bhaskara I's approximation.
+pub static SINE_WAVETABLE: [i16; WAVETABLE_SIZE] = sine_wavetable();
+
+const fn sine_wavetable() -> [i16; WAVETABLE_SIZE] {
+    let mut table = [0i16; WAVETABLE_SIZE];
+    let half = (WAVETABLE_SIZE / 2) as i64;
+    let mut i = 0;
+
+    while i < WAVETABLE_SIZE {
+        let x = (i as i64) % half;
+        let product = x * (half - x);
+        let value = 32767 * 16 * product / (5 * half * half - 4 * product);
+
+        table[i] = if (i as i64) < half { value as i16 } else { -value as i16 };
+        i += 1;
+    }
+
+    table
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Waveform {
+    Square,
+    Saw,
+    Triangle,
+    /// One period, linearly interpolated.
+    Wavetable(&'static [i16; WAVETABLE_SIZE]),
+}
+
+impl Waveform {
+    fn sample(&self, phase: u32) -> i16 {
+        match self {
+            Waveform::Square => {
+                if phase < 0x8000_0000 {
+                    i16::MAX
+                } else {
+                    -i16::MAX
+                }
+            }
+            Waveform::Saw => (phase >> 16) as u16 as i16,
+            Waveform::Triangle => {
+                let position = (phase >> 15) as i32;
+
+                if position < 0x1_0000 {
+                    (position - 0x8000) as i16
+                } else {
+                    (0x1_7fff - position) as i16
+                }
+            }
+            Waveform::Wavetable(table) => {
+                let index = (phase >> 24) as usize;
+                let fraction = ((phase >> 8) & 0xffff) as i64;
+                let first = table[index] as i64;
+                let second = table[(index + 1) % WAVETABLE_SIZE] as i64;
+
+                // A full scale step times the fraction does not fit in 32 bits
+                (first + (((second - first) * fraction) >> 16)) as i16
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Oscillator {
+    /// Note this oscillator plays, or `None` when free.
+    note_index: Option<u8>,
+    phase: u32,
+    increment: u32,
+    /// Envelope level at the end of the last buffer, 0 - 255.
+    level: u8,
+}
+
+impl Oscillator {
+    const FREE: Oscillator = Oscillator {
+        note_index: None,
+        phase: 0,
+        increment: 0,
+        level: 0,
+    };
+}
+
+/// Renders the notes whose envelopes are sounding with one oscillator each, up to `VOICES`.
+/// Notes beyond that stay silent until an oscillator frees up.
+///
+/// Envelope levels change at the update rate, so each buffer ramps linearly from the previous
+/// level to the current one.
+pub struct AudioRenderer<const VOICES: usize = 4> {
+    sample_rate: u32,
+    waveform: Waveform,
+    volume: u8,
+    oscillators: [Oscillator; VOICES],
+}
+
+impl<const VOICES: usize> AudioRenderer<VOICES> {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            sample_rate: sample_rate.max(1),
+            waveform: Waveform::Square,
+            volume: 255,
+            oscillators: [Oscillator::FREE; VOICES],
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Takes effect for notes started afterwards.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate.max(1);
+    }
+
+    pub fn set_waveform(&mut self, waveform: Waveform) {
+        self.waveform = waveform;
+    }
+
+    /// Output volume, 0 - 255.
+    pub fn set_volume(&mut self, volume: u8) {
+        self.volume = volume;
+    }
+
+    /// Fills `out` with signed samples.  Call after each update, with as many samples as the
+    /// update's time covers.
+    pub fn render(&mut self, synth_state: &SynthState, out: &mut [i16]) {
+        let len = out.len();
+
+        self.render_with(synth_state, len, |i, sample| out[i] = sample);
+    }
+
+    /// Fills `out` with unsigned samples of `bits` bits, centered on half scale, as DACs and
+    /// PWM compare registers expect.
+    pub fn render_unsigned(&mut self, synth_state: &SynthState, out: &mut [u16], bits: u8) {
+        let shift = 16 - bits.clamp(1, 16);
+        let len = out.len();
+
+        self.render_with(synth_state, len, |i, sample| out[i] = (sample as u16 ^ 0x8000) >> shift);
+    }
+
+    fn render_with(&mut self, synth_state: &SynthState, len: usize, mut write: impl FnMut(usize, i16)) {
+        self.assign_oscillators(synth_state);
+
+        let count = len.max(1) as i32;
+        let mut levels = [(0i32, 0i32); VOICES];
+
+        for (oscillator, levels) in self.oscillators.iter_mut().zip(levels.iter_mut()) {
+            if let Some(note_index) = oscillator.note_index {
+                let end_level = synth_state.note_envelope(note_index).level();
+
+                *levels = (oscillator.level as i32, end_level as i32);
+                oscillator.level = end_level;
+            }
+        }
+
+        for i in 0..len {
+            let mut mix = 0i32;
+
+            for (oscillator, (start_level, end_level)) in self.oscillators.iter_mut().zip(levels.iter()) {
+                if oscillator.note_index.is_none() {
+                    continue;
+                }
+
+                let level = start_level + (end_level - start_level) * (i as i32 + 1) / count;
+
+                mix += self.waveform.sample(oscillator.phase) as i32 * level * self.volume as i32 / (255 * 255);
+                oscillator.phase = oscillator.phase.wrapping_add(oscillator.increment);
+            }
+
+            // Scaled so every voice at full level and volume just reaches full scale
+            write(i, (mix / VOICES as i32) as i16);
+        }
+    }
+
+    fn assign_oscillators(&mut self, synth_state: &SynthState) {
+        for oscillator in self.oscillators.iter_mut() {
+            if let Some(note_index) = oscillator.note_index {
+                if !synth_state.note_envelope(note_index).is_active() {
+                    *oscillator = Oscillator::FREE;
+                }
+            }
+        }
+
+        for note_index in 0..NUM_NOTES as u8 {
+            if !synth_state.note_envelope(note_index).is_active()
+                || self.oscillators.iter().any(|oscillator| oscillator.note_index == Some(note_index))
+            {
+                continue;
+            }
+
+            let free = match self.oscillators.iter_mut().find(|oscillator| oscillator.note_index.is_none()) {
+                Some(oscillator) => oscillator,
+                None => break,
+            };
+
+            // The pitch is set once, so retuning does not bend notes already sounding
+            *free = Oscillator {
+                note_index: Some(note_index),
+                phase: 0,
+                increment: phase_increment(synth_state.note_millihz(note_index), self.sample_rate),
+                level: 0,
+            };
+        }
+    }
+}
+
+/// Writes the 44 byte header of a mono 16 bit PCM WAV file holding `sample_count` samples,
+/// to be followed by the samples in little endian.
+pub fn wav_header(sample_rate: u32, sample_count: u32) -> [u8; 44] {
+    let data_size = sample_count * 2;
+    let mut header = [0u8; 44];
+
+    header[0..4].copy_from_slice(b"RIFF");
+    header[4..8].copy_from_slice(&(36 + data_size).to_le_bytes());
+    header[8..12].copy_from_slice(b"WAVE");
+    header[12..16].copy_from_slice(b"fmt ");
+    header[16..20].copy_from_slice(&16u32.to_le_bytes());
+    header[20..22].copy_from_slice(&1u16.to_le_bytes());
+    header[22..24].copy_from_slice(&1u16.to_le_bytes());
+    header[24..28].copy_from_slice(&sample_rate.to_le_bytes());
+    header[28..32].copy_from_slice(&(sample_rate * 2).to_le_bytes());
+    header[32..34].copy_from_slice(&2u16.to_le_bytes());
+    header[34..36].copy_from_slice(&16u16.to_le_bytes());
+    header[36..40].copy_from_slice(b"data");
+    header[40..44].copy_from_slice(&data_size.to_le_bytes());
+
+    header
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use super::*;
+    use crate::{AdsrSettings, SynthEngine};
+
+    use keyboard_matrix::KeyboardState;
+    use std::vec::Vec;
+
+    const SAMPLE_RATE: u32 = 8000;
+
+    /// Holds `keys` for `hold_ms` then releases them for `release_ms`, rendering 1ms per update.
+    fn render(waveform: Waveform, keys: &[usize], hold_ms: u32, release_ms: u32) -> Vec<i16> {
+        let mut synth_engine = SynthEngine::new();
+        let mut renderer = AudioRenderer::<4>::new(SAMPLE_RATE);
+        renderer.set_waveform(waveform);
+
+        synth_engine.set_envelope(AdsrSettings {
+            attack_ms: 0,
+            decay_ms: 0,
+            sustain_level: 255,
+            release_ms: 10,
+        });
+
+        let mut keyboard_state = KeyboardState::default();
+        let mut samples = Vec::new();
+
+        for ms in 0..hold_ms + release_ms {
+            for key in keys {
+                keyboard_state.state.set(*key, ms < hold_ms);
+            }
+
+            synth_engine.update_timed(1, &keyboard_state);
+
+            let mut buffer = [0i16; (SAMPLE_RATE / 1000) as usize];
+            renderer.render(&synth_engine.state, &mut buffer);
+            samples.extend_from_slice(&buffer);
+        }
+
+        samples
+    }
+
+    fn rising_zero_crossings(samples: &[i16]) -> usize {
+        samples.windows(2).filter(|pair| pair[0] < 0 && pair[1] >= 0).count()
+    }
+
+    fn wav(samples: &[i16]) -> Vec<u8> {
+        let mut bytes = wav_header(SAMPLE_RATE, samples.len() as u32).to_vec();
+
+        for sample in samples {
+            bytes.extend_from_slice(&sample.to_le_bytes());
+        }
+
+        bytes
+    }
+
+    #[test]
+    fn oscillators_play_note_frequency() {
+        // One second of A4 is 440 periods
+        for waveform in [Waveform::Square, Waveform::Saw, Waveform::Triangle, Waveform::Wavetable(&SINE_WAVETABLE)] {
+            let samples = render(waveform, &[18], 1000, 0);
+
+            assert!((439..=441).contains(&rising_zero_crossings(&samples)), "{:?}", waveform);
+        }
+    }
+
+    #[test]
+    fn release_fades_to_silence() {
+        let samples = render(Waveform::Square, &[13], 100, 20);
+        let peak = |samples: &[i16]| samples.iter().map(|sample| sample.unsigned_abs()).max().unwrap();
+
+        assert_eq!(peak(&samples[700..800]), i16::MAX as u16 / 4);
+        assert!(peak(&samples[840..848]) < peak(&samples[800..808]));
+        assert_eq!(peak(&samples[900..]), 0);
+    }
+
+    #[test]
+    fn voices_mix_without_clipping() {
+        let samples = render(Waveform::Square, &[13, 15, 17, 20, 14], 50, 0);
+
+        // Four of the five notes sound, together reaching full scale
+        assert_eq!(samples.iter().map(|sample| sample.unsigned_abs()).max(), Some(i16::MAX as u16));
+    }
+
+    #[test]
+    fn unsigned_output_is_centered() {
+        let synth_engine = SynthEngine::new();
+        let mut renderer = AudioRenderer::<4>::new(SAMPLE_RATE);
+        let mut out = [0u16; 100];
+
+        renderer.render_unsigned(&synth_engine.state, &mut out, 10);
+
+        assert!(out.iter().all(|sample| *sample == 512));
+    }
+
+    #[test]
+    fn rendered_wav_file_matches_render() {
+        let samples = render(Waveform::Wavetable(&SINE_WAVETABLE), &[13, 17], 200, 20);
+        let path = std::env::temp_dir().join("synth_engine_chord.wav");
+
+        std::fs::write(&path, wav(&samples)).unwrap();
+        let file = std::fs::read(&path).unwrap();
+
+        assert_eq!(&file[..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes([file[40], file[41], file[42], file[43]]), samples.len() as u32 * 2);
+        assert_eq!(file, wav(&render(Waveform::Wavetable(&SINE_WAVETABLE), &[13, 17], 200, 20)));
+    }
+
+    #[test]
+    fn sine_wavetable_has_expected_shape() {
+        assert_eq!(SINE_WAVETABLE[0], 0);
+        assert_eq!(SINE_WAVETABLE[64], 32767);
+        assert_eq!(SINE_WAVETABLE[192], -32767);
+    }
+
+    #[test]
+    fn wavetable_with_full_scale_step_interpolates() {
+        static SQUARE_WAVETABLE: [i16; WAVETABLE_SIZE] = {
+            let mut table = [i16::MAX; WAVETABLE_SIZE];
+            let mut i = WAVETABLE_SIZE / 2;
+
+            while i < WAVETABLE_SIZE {
+                table[i] = -i16::MAX;
+                i += 1;
+            }
+
+            table
+        };
+
+        let waveform = Waveform::Wavetable(&SQUARE_WAVETABLE);
+
+        assert_eq!(waveform.sample(127 << 24 | 0x80_0000), 0);
+        assert_eq!(waveform.sample(127 << 24 | 0xff_ff00), -32767);
+        assert_eq!(waveform.sample(255 << 24 | 0xff_ff00), 32766);
+    }
+}
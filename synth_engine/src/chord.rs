@@ -0,0 +1,264 @@
+use crate::{Scale, NUM_NOTES};
+
+/// Most notes a chord can have, including the root.
+pub const MAX_CHORD_NOTES: usize = 6;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChordError {
+    /// Custom chords need 1 - 5 intervals, rising, each 1 - 24 semitones above the root.
+    InvalidIntervals,
+    UnknownChord(u8),
+}
+
+/// Chord built from intervals above the root, e.g. `[4, 7, 11]` for a major seventh.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CustomChord {
+    intervals: [u8; MAX_CHORD_NOTES - 1],
+    len: u8,
+}
+
+impl CustomChord {
+    pub fn from_intervals(intervals: &[u8]) -> Result<Self, ChordError> {
+        if intervals.is_empty() || intervals.len() > MAX_CHORD_NOTES - 1 {
+            return Err(ChordError::InvalidIntervals);
+        }
+
+        if intervals[0] == 0 || intervals[intervals.len() - 1] > 24 || intervals.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err(ChordError::InvalidIntervals);
+        }
+
+        let mut chord = Self {
+            intervals: [0; MAX_CHORD_NOTES - 1],
+            len: intervals.len() as u8,
+        };
+        chord.intervals[..intervals.len()].copy_from_slice(intervals);
+
+        Ok(chord)
+    }
+
+    pub fn intervals(&self) -> &[u8] {
+        &self.intervals[..self.len as usize]
+    }
+}
+
+/// Chord a note key plays, built on the key's note.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChordShape {
+    Major,
+    Minor,
+    DominantSeventh,
+    MajorSeventh,
+    MinorSeventh,
+    Sus2,
+    Sus4,
+    /// Every other degree of the scale set with `set_scale`, three notes.  Notes outside the
+    /// scale play alone.
+    DiatonicTriad,
+    /// As `DiatonicTriad`, four notes.
+    DiatonicSeventh,
+    Custom(CustomChord),
+}
+
+impl ChordShape {
+    /// Bus identifier.  `Custom` is 9, followed by its intervals.
+    pub fn id(&self) -> u8 {
+        match self {
+            ChordShape::Major => 0,
+            ChordShape::Minor => 1,
+            ChordShape::DominantSeventh => 2,
+            ChordShape::MajorSeventh => 3,
+            ChordShape::MinorSeventh => 4,
+            ChordShape::Sus2 => 5,
+            ChordShape::Sus4 => 6,
+            ChordShape::DiatonicTriad => 7,
+            ChordShape::DiatonicSeventh => 8,
+            ChordShape::Custom(_) => 9,
+        }
+    }
+
+    /// Chord shape for a bus identifier.  `intervals` is only used for `Custom`.
+    pub fn from_id(id: u8, intervals: &[u8]) -> Result<Self, ChordError> {
+        let shape = match id {
+            0 => ChordShape::Major,
+            1 => ChordShape::Minor,
+            2 => ChordShape::DominantSeventh,
+            3 => ChordShape::MajorSeventh,
+            4 => ChordShape::MinorSeventh,
+            5 => ChordShape::Sus2,
+            6 => ChordShape::Sus4,
+            7 => ChordShape::DiatonicTriad,
+            8 => ChordShape::DiatonicSeventh,
+            9 => ChordShape::Custom(CustomChord::from_intervals(intervals)?),
+            _ => return Err(ChordError::UnknownChord(id)),
+        };
+
+        Ok(shape)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChordSettings {
+    pub shape: ChordShape,
+    /// How many of the lowest notes move up an octave.  Limited to one less than the chord's
+    /// notes.
+    pub inversion: u8,
+}
+
+impl ChordSettings {
+    pub fn new(shape: ChordShape) -> Self {
+        Self { shape, inversion: 0 }
+    }
+
+    /// Writes the note indexes of the chord on `note_index`, lowest first, and returns how many
+    /// there are.  Notes above the top of the range are left out.  `root` and `scale` are only
+    /// used by the diatonic shapes.
+    pub fn notes(&self, note_index: u8, root: u8, scale: &Scale, out: &mut [u8; MAX_CHORD_NOTES]) -> usize {
+        let mut offsets = [0u8; MAX_CHORD_NOTES];
+
+        let len = match self.shape {
+            ChordShape::Major => Self::fixed(&[0, 4, 7], &mut offsets),
+            ChordShape::Minor => Self::fixed(&[0, 3, 7], &mut offsets),
+            ChordShape::DominantSeventh => Self::fixed(&[0, 4, 7, 10], &mut offsets),
+            ChordShape::MajorSeventh => Self::fixed(&[0, 4, 7, 11], &mut offsets),
+            ChordShape::MinorSeventh => Self::fixed(&[0, 3, 7, 10], &mut offsets),
+            ChordShape::Sus2 => Self::fixed(&[0, 2, 7], &mut offsets),
+            ChordShape::Sus4 => Self::fixed(&[0, 5, 7], &mut offsets),
+            ChordShape::DiatonicTriad => Self::diatonic(note_index, root, scale, 3, &mut offsets),
+            ChordShape::DiatonicSeventh => Self::diatonic(note_index, root, scale, 4, &mut offsets),
+            ChordShape::Custom(custom) => {
+                offsets[1..=custom.intervals().len()].copy_from_slice(custom.intervals());
+
+                custom.intervals().len() + 1
+            }
+        };
+
+        // Inverting moves the lowest notes up an octave, so the chord starts on the next one
+        let inversion = (self.inversion as usize).min(len - 1);
+
+        for offset in &mut offsets[..inversion] {
+            *offset += 12;
+        }
+
+        offsets[..len].rotate_left(inversion);
+
+        let mut count = 0;
+
+        for offset in &offsets[..len] {
+            let note = note_index as usize + *offset as usize;
+
+            if note < NUM_NOTES {
+                out[count] = note as u8;
+                count += 1;
+            }
+        }
+
+        count
+    }
+
+    fn fixed(intervals: &[u8], offsets: &mut [u8; MAX_CHORD_NOTES]) -> usize {
+        offsets[..intervals.len()].copy_from_slice(intervals);
+
+        intervals.len()
+    }
+
+    fn diatonic(note_index: u8, root: u8, scale: &Scale, size: u8, offsets: &mut [u8; MAX_CHORD_NOTES]) -> usize {
+        let pitch_class = (note_index + 12 - root % 12) % 12;
+
+        let degree = match scale.offsets().iter().position(|offset| *offset == pitch_class) {
+            Some(degree) => degree as u8,
+            None => return 1,
+        };
+
+        let base = scale.degree_to_semitones(degree);
+
+        for (i, offset) in offsets[..size as usize].iter_mut().enumerate() {
+            *offset = scale.degree_to_semitones(degree + 2 * i as u8) - base;
+        }
+
+        size as usize
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn notes(settings: ChordSettings, note_index: u8, root: u8, scale: Scale) -> ([u8; MAX_CHORD_NOTES], usize) {
+        let mut out = [0u8; MAX_CHORD_NOTES];
+        let count = settings.notes(note_index, root, &scale, &mut out);
+
+        (out, count)
+    }
+
+    #[test]
+    fn major_triad_on_c4() {
+        let (out, count) = notes(ChordSettings::new(ChordShape::Major), 36, 0, Scale::Chromatic);
+
+        assert_eq!(&out[..count], &[36, 40, 43]);
+    }
+
+    #[test]
+    fn inversions_move_lowest_notes_up() {
+        let mut settings = ChordSettings::new(ChordShape::MinorSeventh);
+        settings.inversion = 2;
+
+        let (out, count) = notes(settings, 36, 0, Scale::Chromatic);
+
+        assert_eq!(&out[..count], &[43, 46, 48, 51]);
+
+        // Beyond the last inversion stays on the last one
+        settings.inversion = 9;
+
+        let (out, count) = notes(settings, 36, 0, Scale::Chromatic);
+
+        assert_eq!(&out[..count], &[46, 48, 51, 55]);
+    }
+
+    #[test]
+    fn diatonic_chords_follow_the_key() {
+        let settings = ChordSettings::new(ChordShape::DiatonicSeventh);
+
+        // D in C major is D minor 7, B is half diminished
+        let (out, count) = notes(settings, 38, 0, Scale::Major);
+        assert_eq!(&out[..count], &[38, 41, 45, 48]);
+
+        let (out, count) = notes(settings, 47, 0, Scale::Major);
+        assert_eq!(&out[..count], &[47, 50, 53, 57]);
+
+        // G major: F# is the leading tone, F is not in the key
+        let (out, count) = notes(ChordSettings::new(ChordShape::DiatonicTriad), 42, 7, Scale::Major);
+        assert_eq!(&out[..count], &[42, 45, 48]);
+
+        let (out, count) = notes(ChordSettings::new(ChordShape::DiatonicTriad), 41, 7, Scale::Major);
+        assert_eq!(&out[..count], &[41]);
+    }
+
+    #[test]
+    fn notes_above_range_are_left_out() {
+        let (out, count) = notes(ChordSettings::new(ChordShape::Major), 92, 0, Scale::Chromatic);
+
+        assert_eq!(&out[..count], &[92, 96]);
+    }
+
+    #[test]
+    fn custom_intervals_must_rise() {
+        let custom = CustomChord::from_intervals(&[7, 14]).unwrap();
+        let (out, count) = notes(ChordSettings::new(ChordShape::Custom(custom)), 24, 0, Scale::Chromatic);
+
+        assert_eq!(&out[..count], &[24, 31, 38]);
+
+        assert_eq!(CustomChord::from_intervals(&[7, 4]), Err(ChordError::InvalidIntervals));
+        assert_eq!(CustomChord::from_intervals(&[0, 4]), Err(ChordError::InvalidIntervals));
+        assert_eq!(CustomChord::from_intervals(&[25]), Err(ChordError::InvalidIntervals));
+        assert_eq!(CustomChord::from_intervals(&[]), Err(ChordError::InvalidIntervals));
+    }
+
+    #[test]
+    fn ids_round_trip() {
+        for id in 0..9 {
+            assert_eq!(ChordShape::from_id(id, &[]).unwrap().id(), id);
+        }
+
+        assert_eq!(ChordShape::from_id(10, &[]), Err(ChordError::UnknownChord(10)));
+    }
+}
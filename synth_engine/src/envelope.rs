@@ -0,0 +1,264 @@
+/// Full level in the envelope's 16.16 fixed point.
+const FULL_LEVEL: u32 = 255 << 16;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AdsrSettings {
+    pub attack_ms: u16,
+    pub decay_ms: u16,
+    /// Level held while the note is down, 0 - 255.
+    pub sustain_level: u8,
+    /// Time to fall silent from wherever the level was when the note was released.
+    pub release_ms: u16,
+}
+
+impl AdsrSettings {
+    /// Bus layout: attack, decay, release as little endian milliseconds around the sustain
+    /// level: attack low, attack high, decay low, decay high, sustain, release low, release high.
+    pub fn from_bytes(bytes: &[u8; 7]) -> Self {
+        Self {
+            attack_ms: u16::from_le_bytes([bytes[0], bytes[1]]),
+            decay_ms: u16::from_le_bytes([bytes[2], bytes[3]]),
+            sustain_level: bytes[4],
+            release_ms: u16::from_le_bytes([bytes[5], bytes[6]]),
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; 7] {
+        let [attack_low, attack_high] = self.attack_ms.to_le_bytes();
+        let [decay_low, decay_high] = self.decay_ms.to_le_bytes();
+        let [release_low, release_high] = self.release_ms.to_le_bytes();
+
+        [attack_low, attack_high, decay_low, decay_high, self.sustain_level, release_low, release_high]
+    }
+}
+
+impl Default for AdsrSettings {
+    fn default() -> Self {
+        Self {
+            attack_ms: 5,
+            decay_ms: 200,
+            sustain_level: 192,
+            release_ms: 300,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EnvelopeStage {
+    Idle,
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+/// Linear attack, decay, sustain, release envelope for one note, advanced in milliseconds.  Rates
+/// round up, so no stage takes longer than its time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Envelope {
+    stage: EnvelopeStage,
+    /// Level in 16.16 fixed point, 0 - `FULL_LEVEL`.
+    level: u32,
+    release_from: u32,
+    release_rate: u32,
+}
+
+impl Envelope {
+    pub const fn new() -> Self {
+        Self {
+            stage: EnvelopeStage::Idle,
+            level: 0,
+            release_from: 0,
+            release_rate: 0,
+        }
+    }
+
+    pub fn stage(&self) -> EnvelopeStage {
+        self.stage
+    }
+
+    /// Output level, 0 - 255.
+    pub fn level(&self) -> u8 {
+        (self.level >> 16) as u8
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.stage != EnvelopeStage::Idle
+    }
+
+    /// How far through the release the envelope is, 0 at the start to 255 when silent, or
+    /// `None` outside the release.
+    pub fn release_progress(&self) -> Option<u8> {
+        match self.stage {
+            EnvelopeStage::Release if self.release_from > 0 => {
+                Some((255 - (self.level as u64 * 255 / self.release_from as u64)) as u8)
+            }
+            EnvelopeStage::Release => Some(255),
+            _ => None,
+        }
+    }
+
+    /// Starts the attack from the current level, so a retriggered note does not click.
+    pub fn gate_on(&mut self) {
+        self.stage = EnvelopeStage::Attack;
+    }
+
+    pub fn gate_off(&mut self, settings: &AdsrSettings) {
+        if self.stage == EnvelopeStage::Idle || self.stage == EnvelopeStage::Release {
+            return;
+        }
+
+        self.stage = EnvelopeStage::Release;
+        self.release_from = self.level;
+        self.release_rate = self.level.div_ceil((settings.release_ms as u32).max(1));
+    }
+
+    pub fn advance(&mut self, delta_t_ms: u32, settings: &AdsrSettings) {
+        let sustain = (settings.sustain_level as u32) << 16;
+
+        match self.stage {
+            EnvelopeStage::Idle | EnvelopeStage::Sustain => {}
+            EnvelopeStage::Attack => {
+                let rate = FULL_LEVEL.div_ceil((settings.attack_ms as u32).max(1));
+                self.level = self.level.saturating_add(rate.saturating_mul(delta_t_ms));
+
+                if settings.attack_ms == 0 || self.level >= FULL_LEVEL {
+                    self.level = FULL_LEVEL;
+                    self.stage = EnvelopeStage::Decay;
+                }
+            }
+            EnvelopeStage::Decay => {
+                let rate = (FULL_LEVEL - sustain).div_ceil((settings.decay_ms as u32).max(1));
+                self.level = self.level.saturating_sub(rate.saturating_mul(delta_t_ms));
+
+                if settings.decay_ms == 0 || self.level <= sustain {
+                    self.level = sustain;
+                    self.stage = EnvelopeStage::Sustain;
+                }
+            }
+            EnvelopeStage::Release => {
+                self.level = self.level.saturating_sub(self.release_rate.saturating_mul(delta_t_ms));
+
+                if settings.release_ms == 0 || self.level == 0 {
+                    self.level = 0;
+                    self.stage = EnvelopeStage::Idle;
+                }
+            }
+        }
+    }
+}
+
+impl Default for Envelope {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SETTINGS: AdsrSettings = AdsrSettings {
+        attack_ms: 10,
+        decay_ms: 20,
+        sustain_level: 127,
+        release_ms: 100,
+    };
+
+    fn advance(envelope: &mut Envelope, ms: u32) {
+        for _ in 0..ms {
+            envelope.advance(1, &SETTINGS);
+        }
+    }
+
+    #[test]
+    fn attack_decay_and_sustain_follow_times() {
+        let mut envelope = Envelope::new();
+        envelope.gate_on();
+
+        advance(&mut envelope, 5);
+
+        assert_eq!(envelope.level(), 127);
+
+        advance(&mut envelope, 5);
+
+        assert_eq!(envelope.level(), 255);
+        assert_eq!(envelope.stage(), EnvelopeStage::Decay);
+
+        advance(&mut envelope, 10);
+
+        assert_eq!(envelope.level(), 190);
+
+        advance(&mut envelope, 10);
+
+        assert_eq!(envelope.stage(), EnvelopeStage::Sustain);
+        assert_eq!(envelope.level(), 127);
+
+        advance(&mut envelope, 1000);
+
+        assert_eq!(envelope.level(), 127);
+    }
+
+    #[test]
+    fn release_falls_from_current_level() {
+        let mut envelope = Envelope::new();
+        envelope.gate_on();
+
+        advance(&mut envelope, 5);
+        envelope.gate_off(&SETTINGS);
+
+        assert_eq!(envelope.release_progress(), Some(0));
+
+        advance(&mut envelope, 50);
+
+        assert_eq!(envelope.level(), 63);
+        assert_eq!(envelope.release_progress(), Some(128));
+
+        advance(&mut envelope, 50);
+
+        assert_eq!(envelope.stage(), EnvelopeStage::Idle);
+        assert_eq!(envelope.level(), 0);
+        assert_eq!(envelope.release_progress(), None);
+    }
+
+    #[test]
+    fn retrigger_attacks_from_release_level() {
+        let mut envelope = Envelope::new();
+        envelope.gate_on();
+
+        advance(&mut envelope, 30);
+        envelope.gate_off(&SETTINGS);
+        advance(&mut envelope, 50);
+        envelope.gate_on();
+        advance(&mut envelope, 1);
+
+        assert_eq!(envelope.level(), 88);
+    }
+
+    #[test]
+    fn large_delta_and_zero_times_do_not_overflow() {
+        let settings = AdsrSettings {
+            attack_ms: 0,
+            decay_ms: 0,
+            sustain_level: 255,
+            release_ms: 0,
+        };
+
+        let mut envelope = Envelope::new();
+        envelope.gate_on();
+        envelope.advance(u32::MAX, &settings);
+
+        assert_eq!(envelope.level(), 255);
+
+        envelope.advance(1, &settings);
+        envelope.gate_off(&settings);
+        envelope.advance(1, &settings);
+
+        assert_eq!(envelope.stage(), EnvelopeStage::Idle);
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        assert_eq!(AdsrSettings::from_bytes(&SETTINGS.to_bytes()), SETTINGS);
+    }
+}
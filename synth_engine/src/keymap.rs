@@ -0,0 +1,222 @@
+use keyboard_matrix::KIB_KEY_COUNT;
+
+/// What a physical key does.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyRole {
+    /// Scale degree above the root of the current octave, 0 - 12.  Degrees past the end of the
+    /// scale carry on into the octaves above, so in the chromatic scale it counts semitones.
+    Note(u8),
+    /// Selects an octave, 1 - 8.
+    OctaveSelect(u8),
+    /// Non-musical controls, 0 - 15.  See `FUNCTION_SUSTAIN` and `FUNCTION_LATCH`.
+    Function(u8),
+    Unused,
+}
+
+const ROLE_UNUSED: u8 = 0x00;
+const ROLE_NOTE: u8 = 0x10;
+const ROLE_OCTAVE_SELECT: u8 = 0x20;
+const ROLE_FUNCTION: u8 = 0x30;
+
+impl KeyRole {
+    /// One byte encoding: role kind in the high nibble, value in the low nibble.
+    pub fn to_byte(&self) -> u8 {
+        match self {
+            KeyRole::Unused => ROLE_UNUSED,
+            KeyRole::Note(offset) => ROLE_NOTE | offset,
+            KeyRole::OctaveSelect(octave) => ROLE_OCTAVE_SELECT | octave,
+            KeyRole::Function(function) => ROLE_FUNCTION | function,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        let value = byte & 0x0f;
+
+        match byte & 0xf0 {
+            ROLE_UNUSED if value == 0 => Some(KeyRole::Unused),
+            ROLE_NOTE => Some(KeyRole::Note(value)),
+            ROLE_OCTAVE_SELECT => Some(KeyRole::OctaveSelect(value)),
+            ROLE_FUNCTION => Some(KeyRole::Function(value)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeymapError {
+    UnknownRole { key: u8 },
+    NoteOutOfRange { key: u8 },
+    OctaveOutOfRange { key: u8 },
+    FunctionOutOfRange { key: u8 },
+    /// Two keys play the same note or select the same octave.
+    Duplicate { key: u8, other_key: u8 },
+}
+
+/// Role of every key on the board.  Validated on construction, so each note offset and octave
+/// is reachable from at most one key.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Keymap {
+    roles: [KeyRole; KIB_KEY_COUNT],
+}
+
+impl Keymap {
+    /// KIB layout: octave selectors across the top row, one octave of notes below.
+    pub const DEFAULT: Keymap = Keymap {
+        roles: [
+            KeyRole::OctaveSelect(1),
+            KeyRole::OctaveSelect(2),
+            KeyRole::OctaveSelect(3),
+            KeyRole::OctaveSelect(4),
+            KeyRole::OctaveSelect(5),
+            KeyRole::OctaveSelect(6),
+            KeyRole::OctaveSelect(7),
+            KeyRole::OctaveSelect(8),
+            KeyRole::Note(10), //A#
+            KeyRole::Note(8),  //G#
+            KeyRole::Note(6),  //F#
+            KeyRole::Note(3),  //D#
+            KeyRole::Note(1),  //C#
+            KeyRole::Note(0),  //C
+            KeyRole::Note(2),  //D
+            KeyRole::Note(4),  //E
+            KeyRole::Note(5),  //F
+            KeyRole::Note(7),  //G
+            KeyRole::Note(9),  //A
+            KeyRole::Note(11), //B
+            KeyRole::Note(12), //C2
+        ],
+    };
+
+    pub fn new(roles: [KeyRole; KIB_KEY_COUNT]) -> Result<Self, KeymapError> {
+        for (key, role) in roles.iter().enumerate() {
+            let key = key as u8;
+
+            match role {
+                KeyRole::Note(offset) if *offset > 12 => return Err(KeymapError::NoteOutOfRange { key }),
+                KeyRole::OctaveSelect(octave) if !(1..=8).contains(octave) => {
+                    return Err(KeymapError::OctaveOutOfRange { key })
+                }
+                KeyRole::Function(function) if *function > 15 => {
+                    return Err(KeymapError::FunctionOutOfRange { key })
+                }
+                _ => {}
+            }
+
+            let duplicate = roles[..key as usize].iter().position(|other| match (role, other) {
+                (KeyRole::Note(a), KeyRole::Note(b)) => a == b,
+                (KeyRole::OctaveSelect(a), KeyRole::OctaveSelect(b)) => a == b,
+                _ => false,
+            });
+
+            if let Some(other_key) = duplicate {
+                return Err(KeymapError::Duplicate {
+                    key,
+                    other_key: other_key as u8,
+                });
+            }
+        }
+
+        Ok(Self { roles })
+    }
+
+    /// Builds a keymap from one role byte per key, as sent over the bus.
+    pub fn from_bytes(bytes: &[u8; KIB_KEY_COUNT]) -> Result<Self, KeymapError> {
+        let mut roles = [KeyRole::Unused; KIB_KEY_COUNT];
+
+        for (key, byte) in bytes.iter().enumerate() {
+            roles[key] = KeyRole::from_byte(*byte).ok_or(KeymapError::UnknownRole { key: key as u8 })?;
+        }
+
+        Keymap::new(roles)
+    }
+
+    pub fn to_bytes(&self) -> [u8; KIB_KEY_COUNT] {
+        let mut bytes = [0u8; KIB_KEY_COUNT];
+
+        for (byte, role) in bytes.iter_mut().zip(self.roles.iter()) {
+            *byte = role.to_byte();
+        }
+
+        bytes
+    }
+
+    pub fn role(&self, key: usize) -> KeyRole {
+        self.roles.get(key).copied().unwrap_or(KeyRole::Unused)
+    }
+
+    pub fn roles(&self) -> &[KeyRole; KIB_KEY_COUNT] {
+        &self.roles
+    }
+
+    pub fn key_for_note_offset(&self, note_offset: u8) -> Option<usize> {
+        self.roles.iter().position(|role| *role == KeyRole::Note(note_offset))
+    }
+
+    pub fn key_for_octave(&self, octave: u8) -> Option<usize> {
+        self.roles.iter().position(|role| *role == KeyRole::OctaveSelect(octave))
+    }
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Keymap::DEFAULT
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn default_keymap_is_valid() {
+        assert_eq!(Keymap::new(*Keymap::DEFAULT.roles()), Ok(Keymap::DEFAULT));
+    }
+
+    #[test]
+    fn default_keymap_matches_board_layout() {
+        let keymap = Keymap::default();
+
+        assert_eq!(keymap.key_for_note_offset(0), Some(13));
+        assert_eq!(keymap.key_for_note_offset(12), Some(20));
+        assert_eq!(keymap.key_for_octave(4), Some(3));
+        assert_eq!(keymap.role(8), KeyRole::Note(10));
+        assert_eq!(keymap.role(21), KeyRole::Unused);
+    }
+
+    #[test]
+    fn duplicate_note_is_rejected() {
+        let mut roles = *Keymap::DEFAULT.roles();
+        roles[20] = KeyRole::Note(0);
+
+        assert_eq!(Keymap::new(roles), Err(KeymapError::Duplicate { key: 20, other_key: 13 }));
+    }
+
+    #[test]
+    fn out_of_range_roles_are_rejected() {
+        let mut roles = *Keymap::DEFAULT.roles();
+        roles[0] = KeyRole::OctaveSelect(9);
+
+        assert_eq!(Keymap::new(roles), Err(KeymapError::OctaveOutOfRange { key: 0 }));
+
+        roles[0] = KeyRole::Note(13);
+
+        assert_eq!(Keymap::new(roles), Err(KeymapError::NoteOutOfRange { key: 0 }));
+    }
+
+    #[test]
+    fn bytes_round_trip() {
+        let bytes = Keymap::DEFAULT.to_bytes();
+
+        assert_eq!(bytes[0], 0x21);
+        assert_eq!(bytes[13], 0x10);
+        assert_eq!(Keymap::from_bytes(&bytes), Ok(Keymap::DEFAULT));
+    }
+
+    #[test]
+    fn unknown_role_byte_is_rejected() {
+        let mut bytes = Keymap::DEFAULT.to_bytes();
+        bytes[5] = 0x40;
+
+        assert_eq!(Keymap::from_bytes(&bytes), Err(KeymapError::UnknownRole { key: 5 }));
+    }
+}
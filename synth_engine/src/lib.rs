@@ -1,12 +1,52 @@
 #![no_std]
 
-use core::u8;
-
-use keyboard_matrix::KeyboardState;
+use keyboard_matrix::{KeySet, KeyboardState};
+
+mod arpeggiator;
+mod audio;
+mod chord;
+mod envelope;
+mod keymap;
+mod midi;
+mod midi_input;
+mod mono;
+mod scale;
+mod sequencer;
+mod tuning;
+mod usb_midi;
+mod voice;
+
+pub use arpeggiator::{ArpPattern, ArpSettings, Arpeggiator, NoteDivision, MAX_ARP_NOTES};
+pub use audio::{wav_header, AudioRenderer, Waveform, SINE_WAVETABLE, WAVETABLE_SIZE};
+pub use chord::{ChordError, ChordSettings, ChordShape, CustomChord, MAX_CHORD_NOTES};
+pub use envelope::{AdsrSettings, Envelope, EnvelopeStage};
+pub use keymap::{KeyRole, Keymap, KeymapError};
+pub use midi::{MidiEncoder, MidiError, MAX_MIDI_UPDATE_SIZE};
+pub use midi_input::{MidiMessage, MidiParser};
+pub use mono::{MonoMode, MonoSettings, NotePriority};
+pub use scale::{CustomScale, Scale, ScaleError};
+pub use sequencer::{Sequencer, SequencerEvent, SequencerMode};
+pub use tuning::{nearest_midi_note, phase_increment, RatioTable, Tuning, TuningError, TuningSystem, MAX_TUNING_STEPS};
+pub use usb_midi::{sysex_packets, UsbMidiEncoder, UsbMidiEventPacket, MAX_USB_MIDI_UPDATE_PACKETS};
+pub use voice::{StealPolicy, VoiceAllocator, VoiceSettings, MAX_VOICES};
 
 const MIDI_NOTE_OFFSET : u8 = 24; //0th note is C1
+const NO_NOTE : u8 = 0xff;
+
+static IDLE_ENVELOPE: Envelope = Envelope::new();
+pub const MAX_TRANSPOSE : i8 = 24;
+pub const MAX_FINE_TUNE_CENTS : i8 = 100;
 pub const NUM_NOTES : usize = 97; //8 octaves, 12 notes per octave, plus 1 extra C in octave 8
 
+/// Set of note indexes, one bit per note.
+pub type NoteSet = KeySet<{ NUM_NOTES.div_ceil(32) }>;
+
+/// `KeyRole::Function` holding the sustain pedal down.  Several keys with it form a combination
+/// that must be pressed together.
+pub const FUNCTION_SUSTAIN : u8 = 0;
+/// `KeyRole::Function` toggling latch mode.
+pub const FUNCTION_LATCH : u8 = 1;
+
 /// State of a note
 #[derive(Clone, Copy, PartialEq)]
 pub enum NoteState {
@@ -28,6 +68,15 @@ impl NoteState {
 }
 
 impl NoteState {
+    fn from_int(value: u8) -> NoteState {
+        match value {
+            1 => NoteState::Pressed,
+            2 => NoteState::Sustain,
+            3 => NoteState::Release,
+            _ => NoteState::Off,
+        }
+    }
+
     #[inline(never)]
     pub fn is_active(&self) -> bool {
         matches!(self, NoteState::Pressed | NoteState::Sustain | NoteState::Release)
@@ -54,10 +103,45 @@ impl NoteState {
     }
 }
 
+/// `NoteState` of every note, packed into two bits per note.
+#[derive(Clone, Copy, Default)]
+struct NoteStates {
+    low: NoteSet,
+    high: NoteSet,
+}
+
+impl NoteStates {
+    fn get(&self, note_index: usize) -> NoteState {
+        NoteState::from_int(self.low[note_index] as u8 | (self.high[note_index] as u8) << 1)
+    }
+
+    fn set(&mut self, note_index: usize, note_state: NoteState) {
+        let value = note_state.to_int();
+
+        self.low.set(note_index, value & 1 != 0);
+        self.high.set(note_index, value & 2 != 0);
+    }
+}
+
 pub struct SynthState { 
     pub octave: u8, // 1 - 8
     pub note_index_state: [NoteState; NUM_NOTES], // Tuning from C1 to C9 (extra C in octave 8).  Requires MIDI_NOTE_OFFSET to be accurate midi note value.
     pub dirty: bool,
+    pub keymap: Keymap,
+    pub root: u8, // 0 - 11 semitones above C
+    pub scale: Scale,
+    pub transpose: i8, // Semitones, -24 - 24
+    pub fine_tune_cents: i8, // -100 - 100
+    pub legato: bool, // In mono mode, the note pressed this update slides from the one released
+    pub glide_ms: u16, // Mono mode portamento time, 0 for none
+    pub envelope: AdsrSettings,
+    pub tuning: Tuning,
+    remote_note_state: NoteStates, // Notes received over MIDI, indexed like note_index_state
+    started_midi_note: [u8; NUM_NOTES], // MIDI note each note index sounded when it was pressed
+    voice_note: [u8; MAX_VOICES], // Note index sounding on each voice, or NO_NOTE
+    released_voice_note: [u8; MAX_VOICES], // Note index each voice gave up in the last update, or NO_NOTE
+    stolen: NoteSet, // Held but silenced by the voice limit
+    envelopes: [(u8, Envelope); MAX_VOICES], // Note index each envelope is sounding, or NO_NOTE
 }
 
 
@@ -67,45 +151,28 @@ impl SynthState {
             octave: 4,
             note_index_state: [NoteState::Off; NUM_NOTES],
             dirty: false,
+            keymap: Keymap::DEFAULT,
+            root: 0,
+            scale: Scale::Chromatic,
+            transpose: 0,
+            fine_tune_cents: 0,
+            legato: false,
+            glide_ms: 0,
+            envelope: AdsrSettings::default(),
+            tuning: Tuning::default(),
+            remote_note_state: NoteStates::default(),
+            started_midi_note: [0; NUM_NOTES],
+            voice_note: [NO_NOTE; MAX_VOICES],
+            released_voice_note: [NO_NOTE; MAX_VOICES],
+            stolen: NoteSet::new(),
+            envelopes: [(NO_NOTE, Envelope::new()); MAX_VOICES],
         }
     }
     
-    fn index_to_note_offset(&self, idx: u8) -> u8 {
-        match idx {
-            11=>3, //D#
-            12=>1, //C#
-            13=>0, //C
-            14=>2, //D
-            10=>6, //F#
-            15=>4, //E
-            16=>5, //F
-            17=>7, //G
-            8=>10, //A#
-            9=>8, //G#
-            18=>9, //A
-            19=>11, //B
-            20=>12, //C2
-            _=>0,
-        }
-    }
-
     pub fn note_offset_to_index(&self, note_offset: u8) -> u8 {
-        match note_offset {
-            3=>11, //D#
-            1=>12, //C#
-            0=>13, //C
-            2=>14, //D
-            6=>10, //F#
-            4=>15, //E
-            5=>16, //F
-            7=>17, //G
-            10=>8, //A#
-            8=>9, //G#
-            9=>18, //A
-            11=>19, //B
-            12=>20, //C2
-            _=>0,
-        }
+        self.keymap
+            .key_for_note_offset(note_offset)
+            .map_or(keyboard_matrix::NO_KEY, |key| key as u8)
     }
 
     fn octave_note_offset_to_note_index(octave: u8, note_offset: u8) -> u8 {
@@ -124,19 +191,175 @@ impl SynthState {
         note_index - octave_offset
     }
 
-    fn index_to_note_index(&self, idx: u8) -> u8 {
-        let note_offset = self.index_to_note_offset(idx);
-        let note_index = self.note_offset_to_note_index(note_offset);
+    pub fn midi_to_note_index(&self, midi_note: u8) -> Option<u8> {
+        let note_index = midi_note.checked_sub(MIDI_NOTE_OFFSET)?;
+
+        if (note_index as usize) < NUM_NOTES {
+            Some(note_index)
+        } else {
+            None
+        }
+    }
+
+    /// Note played by a key in the current octave, root and scale.  Keys whose scale degree
+    /// runs past the top note play nothing.
+    pub fn key_note_index(&self, key: usize) -> Option<u8> {
+        match self.keymap.role(key) {
+            KeyRole::Note(degree) => {
+                let note_index = self.note_offset_to_note_index(self.root) + self.scale.degree_to_semitones(degree);
+
+                if (note_index as usize) < NUM_NOTES {
+                    Some(note_index)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether this key's note is held in the current octave, by the key itself, playback or MIDI.
+    pub fn note_held_for_key(&self, key: usize) -> bool {
+        match self.key_note_index(key) {
+            Some(note_index) => {
+                matches!(self.note_index_state[note_index as usize], NoteState::Pressed | NoteState::Sustain)
+                    || self.remote_note_held_for_key(key)
+            }
+            None => false,
+        }
+    }
+
+    /// Whether a note received over MIDI is held on this key in the current octave.
+    pub fn remote_note_held_for_key(&self, key: usize) -> bool {
+        match self.key_note_index(key) {
+            Some(note_index) => {
+                matches!(self.remote_note_state(note_index), NoteState::Pressed | NoteState::Sustain)
+            }
+            None => false,
+        }
+    }
+
+    /// State of a note received over MIDI.
+    pub fn remote_note_state(&self, note_index: u8) -> NoteState {
+        self.remote_note_state.get(note_index as usize)
+    }
+
+    /// Whether this key's note is held but silenced by the voice limit.
+    pub fn note_stolen_for_key(&self, key: usize) -> bool {
+        self.key_note_index(key).is_some_and(|note_index| self.stolen[note_index as usize])
+    }
+
+    /// Voice an active note plays on, while a voice limit is set.  Releasing notes report the
+    /// voice they played on.
+    pub fn note_voice(&self, note_index: u8) -> Option<u8> {
+        let voice_note = match self.note_index_state[note_index as usize] {
+            NoteState::Off => return None,
+            NoteState::Release => &self.released_voice_note,
+            _ => &self.voice_note,
+        };
+
+        voice_note.iter().position(|note| *note == note_index).map(|voice| voice as u8)
+    }
+
+    /// Envelope of a note.  Unlike `note_index_state` it keeps sounding through the release time.
+    /// Up to `MAX_VOICES` notes have an envelope at once.  A note held while all of them are
+    /// busy reads idle until one falls silent.
+    pub fn note_envelope(&self, note_index: u8) -> &Envelope {
+        self.envelopes
+            .iter()
+            .find(|(note, _)| *note == note_index)
+            .map_or(&IDLE_ENVELOPE, |(_, envelope)| envelope)
+    }
+
+    /// Release progress of this key's note in the current octave, 0 - 255, while it is
+    /// releasing.
+    pub fn release_progress_for_key(&self, key: usize) -> Option<u8> {
+        self.key_note_index(key)
+            .and_then(|note_index| self.note_envelope(note_index).release_progress())
+    }
+
+    /// Opens and closes envelopes on this update's presses and releases, then moves them all on
+    /// by `delta_t_ms`.
+    fn advance_envelopes(&mut self, delta_t_ms: u32) {
+        for note_index in 0..NUM_NOTES as u8 {
+            match self.note_index_state[note_index as usize] {
+                NoteState::Pressed => {
+                    if let Some(envelope) = self.open_envelope(note_index, true) {
+                        envelope.gate_on();
+                    }
+                }
+                // A note that found every envelope busy starts once one is free
+                NoteState::Sustain if !self.envelopes.iter().any(|(note, _)| *note == note_index) => {
+                    if let Some(envelope) = self.open_envelope(note_index, false) {
+                        envelope.gate_on();
+                    }
+                }
+                NoteState::Release => {
+                    if let Some((_, envelope)) = self.envelopes.iter_mut().find(|(note, _)| *note == note_index) {
+                        envelope.gate_off(&self.envelope);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        for (note, envelope) in self.envelopes.iter_mut().filter(|(note, _)| *note != NO_NOTE) {
+            envelope.advance(delta_t_ms, &self.envelope);
+
+            if !envelope.is_active() {
+                *note = NO_NOTE;
+            }
+        }
+    }
+
+    /// The note's envelope, or a fresh one in a free slot.  With `steal_release` and no free
+    /// slot, the quietest releasing note gives up its envelope.  Held notes keep theirs, so
+    /// past `MAX_VOICES` held notes there may be none.
+    fn open_envelope(&mut self, note_index: u8, steal_release: bool) -> Option<&mut Envelope> {
+        let slot = match self.envelopes.iter().position(|(note, _)| *note == note_index) {
+            Some(slot) => slot,
+            None => {
+                let slot = self
+                    .envelopes
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, (note, envelope))| {
+                        *note == NO_NOTE || (steal_release && envelope.stage() == EnvelopeStage::Release)
+                    })
+                    .min_by_key(|(_, (note, envelope))| (*note != NO_NOTE, envelope.level()))
+                    .map(|(slot, _)| slot)?;
+
+                self.envelopes[slot] = (note_index, Envelope::new());
+
+                slot
+            }
+        };
 
-        note_index
+        Some(&mut self.envelopes[slot].1)
     }
 
+    /// MIDI note a press of `note_index` starts now, transposed, or `None` outside 0 - 127.
+    /// Note indexes already include the octave, so only the C1 offset is added.
     #[inline(never)]
-    pub fn note_index_to_midi(&self, note_index: u8) -> u8 {
-        let octave_offset = (self.octave + 1) * 12;
-        let midi_note = MIDI_NOTE_OFFSET + octave_offset + note_index;
+    pub fn note_index_to_midi(&self, note_index: u8) -> Option<u8> {
+        let note = MIDI_NOTE_OFFSET as i16 + note_index as i16 + self.transpose as i16;
+
+        u8::try_from(note).ok().filter(|note| *note <= 127)
+    }
 
-        midi_note
+    /// MIDI note `note_index` was started with.  Releases use this so a transpose change while
+    /// the note is held cannot leave the original note hanging.
+    pub fn started_midi_note(&self, note_index: u8) -> u8 {
+        self.started_midi_note[note_index as usize]
+    }
+
+    /// Frequency in millihertz of the MIDI note `note_index` was started with, in the current
+    /// tuning and fine tune.  Worked out on each call, so consumers read it once as they start
+    /// the note and keep it, as `AudioRenderer` and `MidiEncoder` do.
+    pub fn note_millihz(&self, note_index: u8) -> u32 {
+        let millihz = self.tuning.frequency_millihz(self.started_midi_note(note_index), self.root);
+
+        Tuning::detune(millihz, self.fine_tune_cents as i32)
     }
 
     #[inline(never)]
@@ -144,6 +367,13 @@ impl SynthState {
         let note_index = note_index as usize;
         let new_state = self.note_index_state[note_index].activate();
         if self.note_index_state[note_index] != new_state {
+            if new_state == NoteState::Pressed {
+                match self.note_index_to_midi(note_index as u8) {
+                    Some(midi_note) => self.started_midi_note[note_index] = midi_note,
+                    None => return false,
+                }
+            }
+
             self.note_index_state[note_index] = new_state;
             self.dirty = true;
 
@@ -168,14 +398,99 @@ impl SynthState {
     }
 }
 
+impl Default for SynthState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub struct SynthEngine {
     pub state: SynthState,
+    midi_parser: MidiParser,
+    remote_channel: Option<u8>,
+    remote_held: NoteSet,
+    held: NoteSet,
+    arpeggiator: Option<Arpeggiator>,
+    chord: Option<ChordSettings>,
+    voice_allocator: Option<VoiceAllocator>,
+    mono: Option<MonoMode>,
+    latch: bool,
+    latched: NoteSet,
+    sustain_pedal: bool,
+    sustain_keys_down: bool,
+    sustained: NoteSet,
 }
 
 impl SynthEngine {
     pub fn new() -> Self {
         Self {
-            state: SynthState::new()
+            state: SynthState::new(),
+            midi_parser: MidiParser::new(),
+            remote_channel: None,
+            remote_held: NoteSet::new(),
+            held: NoteSet::new(),
+            arpeggiator: None,
+            chord: None,
+            voice_allocator: None,
+            mono: None,
+            latch: false,
+            latched: NoteSet::new(),
+            sustain_pedal: false,
+            sustain_keys_down: false,
+            sustained: NoteSet::new(),
+        }
+    }
+
+    /// Channel 0 - 15 that remote notes are accepted on, or `None` for all channels.
+    pub fn set_remote_channel(&mut self, channel: Option<u8>) {
+        self.remote_channel = channel;
+    }
+
+    /// Parses incoming MIDI bytes.  Messages may be split across calls.
+    pub fn receive_midi(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            if let Some(message) = self.midi_parser.push(*byte) {
+                SynthEngine::apply_remote_message(&mut self.remote_held, self.remote_channel, &self.state, &message);
+            }
+        }
+    }
+
+    /// Applies one already parsed message.  Notes take effect in the remote layer on the next
+    /// `update`, so a note on and off between two updates is not seen.
+    pub fn receive_midi_message(&mut self, message: &MidiMessage) {
+        SynthEngine::apply_remote_message(&mut self.remote_held, self.remote_channel, &self.state, message);
+    }
+
+    fn apply_remote_message(
+        remote_held: &mut NoteSet,
+        remote_channel: Option<u8>,
+        state: &SynthState,
+        message: &MidiMessage,
+    ) {
+        let channel = match *message {
+            MidiMessage::NoteOn { channel, .. }
+            | MidiMessage::NoteOff { channel, .. }
+            | MidiMessage::ControlChange { channel, .. } => channel,
+            _ => return,
+        };
+
+        if remote_channel.is_some_and(|remote_channel| remote_channel != channel) {
+            return;
+        }
+
+        let (note, is_on) = match *message {
+            MidiMessage::NoteOn { note, .. } => (note, true),
+            MidiMessage::NoteOff { note, .. } => (note, false),
+            // All Notes Off
+            MidiMessage::ControlChange { controller: 123, .. } => {
+                remote_held.clear();
+                return;
+            }
+            _ => return,
+        };
+
+        if let Some(note_index) = state.midi_to_note_index(note) {
+            remote_held.set(note_index as usize, is_on);
         }
     }
 
@@ -184,43 +499,351 @@ impl SynthEngine {
         self.state.dirty = true;
     }
 
+    /// Shifts notes pressed from now on, clamped to +-24 semitones.  Held notes keep sounding
+    /// at the pitch they started with, and notes shifted past MIDI note 127 do not play.
+    pub fn set_transpose(&mut self, semitones: i8) {
+        self.state.transpose = semitones.clamp(-MAX_TRANSPOSE, MAX_TRANSPOSE);
+        self.state.dirty = true;
+    }
+
+    /// Fine pitch offset for sound generation, clamped to +-100 cents.  Like `set_tuning`, it
+    /// applies to notes started afterwards.
+    pub fn set_fine_tune(&mut self, cents: i8) {
+        self.state.fine_tune_cents = cents.clamp(-MAX_FINE_TUNE_CENTS, MAX_FINE_TUNE_CENTS);
+        self.state.dirty = true;
+    }
+
+    /// `root` is 0 - 11 semitones above C, larger values wrap.
+    pub fn set_scale(&mut self, root: u8, scale: Scale) {
+        self.state.root = root % 12;
+        self.state.scale = scale;
+        self.state.dirty = true;
+    }
+
     pub fn update(&mut self, keyboard_state: &KeyboardState) {
+        self.update_timed(0, keyboard_state);
+    }
+
+    /// `update` for time based modes such as the arpeggiator, `delta_t_ms` since the last call.
+    pub fn update_timed(&mut self, delta_t_ms: u32, keyboard_state: &KeyboardState) {
+        self.update_notes::<0>(delta_t_ms, keyboard_state, None);
+    }
+
+    /// `update_timed` with a loop recorder, which records the notes held and plays its pattern
+    /// along with them.  The sequencer is kept by the caller, so it only costs RAM when used.
+    pub fn update_sequenced<const CAPACITY: usize>(
+        &mut self,
+        delta_t_ms: u32,
+        keyboard_state: &KeyboardState,
+        sequencer: &mut Sequencer<CAPACITY>,
+    ) {
+        self.update_notes(delta_t_ms, keyboard_state, Some(sequencer));
+    }
+
+    fn update_notes<const CAPACITY: usize>(
+        &mut self,
+        delta_t_ms: u32,
+        keyboard_state: &KeyboardState,
+        sequencer: Option<&mut Sequencer<CAPACITY>>,
+    ) {
         self.state.dirty = false;
 
-        // Update Octave
-        for i in 0..8 {
-        if keyboard_state.pressed[i] && self.state.octave != i as u8 + 1 {
-                self.state.octave = i as u8 + 1;
+        // Update Octave, latch and the sustain keys
+        let mut sustain_keys = 0;
+        let mut sustain_keys_down = 0;
+        let mut sustain_keys_ghosted = false;
+        let mut toggle_latch = false;
 
-                self.state.dirty = true;
+        for (i, role) in self.state.keymap.roles().iter().enumerate() {
+            match *role {
+                KeyRole::OctaveSelect(octave)
+                    if keyboard_state.pressed[i] && !keyboard_state.ghosted[i] && self.state.octave != octave =>
+                {
+                    self.state.octave = octave;
+
+                    self.state.dirty = true;
+                }
+                KeyRole::Function(FUNCTION_SUSTAIN) => {
+                    sustain_keys += 1;
+                    sustain_keys_ghosted |= keyboard_state.ghosted[i];
+
+                    if keyboard_state.is_trusted_down(i) {
+                        sustain_keys_down += 1;
+                    }
+                }
+                KeyRole::Function(FUNCTION_LATCH) if keyboard_state.pressed[i] && !keyboard_state.ghosted[i] => {
+                    toggle_latch = !toggle_latch;
+                }
+                _ => {}
             }
         }
 
-        //Clear notes for non-current octaves
-        for octave in 1..9 {
-            if octave != self.state.octave {
-                for i in 0..12 {
-                    let note_index = SynthState::octave_note_offset_to_note_index(octave, i);
+        if toggle_latch {
+            self.set_latch(!self.latch);
+        }
+
+        if !sustain_keys_ghosted {
+            self.sustain_keys_down = sustain_keys > 0 && sustain_keys_down == sustain_keys;
+        }
+
+        // Held notes for the current octave and scale
+        let mut held = NoteSet::new();
 
-                    self.state.dirty = self.state.deactivate_note_index(note_index) || self.state.dirty;
+        for i in 0..keyboard_matrix::KIB_KEY_COUNT {
+            let note_index = match self.state.key_note_index(i) {
+                Some(note_index) => note_index as usize,
+                None => continue,
+            };
+
+            // An ambiguous key keeps its previous state.  A stuck key releases its note rather
+            // than droning on.
+            let is_held = if keyboard_state.ghosted[i] {
+                self.held[note_index]
+            } else {
+                keyboard_state.is_trusted_down(i)
+            };
+
+            held.set(note_index, is_held);
+
+            // Latch toggles a note on each press of its key rather than following the key, so
+            // an octave change under a held key toggles nothing
+            if self.latch && keyboard_state.pressed[i] && keyboard_state.is_trusted_down(i) {
+                self.latched.set(note_index, !self.latched[note_index]);
+            }
+        }
+
+        self.held = held;
+
+        let roots = if self.latch { self.latched } else { held };
+
+        // Each key plays its chord.  A note shared by several chords stays held until the last of
+        // them is released.
+        let mut held = match &self.chord {
+            Some(chord) => {
+                let mut held = NoteSet::new();
+                let mut chord_notes = [0u8; MAX_CHORD_NOTES];
+
+                for note_index in roots.iter() {
+                    let count = chord.notes(note_index as u8, self.state.root, &self.state.scale, &mut chord_notes);
+
+                    for note in &chord_notes[..count] {
+                        held.insert(*note as usize);
+                    }
                 }
+
+                held
             }
+            None => roots,
+        };
+
+        // Notes played while the pedal is down keep sounding until it lifts
+        if self.sustain() {
+            self.sustained = self.sustained | held;
+            held = self.sustained;
+        } else {
+            self.sustained.clear();
+        }
+
+        // Loop playback joins the notes held on the keys
+        if let Some(sequencer) = sequencer {
+            sequencer.advance(delta_t_ms);
+            sequencer.input(&held);
+
+            held = held | *sequencer.playing();
         }
 
         // Update Notes
-        for i in 8..21 {
-            let note_index = self.state.index_to_note_index(i);
+        let mut sounding = match &mut self.arpeggiator {
+            Some(arpeggiator) => {
+                arpeggiator.set_held(&held);
+
+                let mut sounding = NoteSet::new();
+
+                if let Some(note_index) = arpeggiator.advance(delta_t_ms) {
+                    sounding.insert(note_index as usize);
+                }
+
+                sounding
+            }
+            None => held,
+        };
+
+        // Notes transposed past the MIDI range cannot start, rather than sharing a note number
+        sounding = sounding
+            .iter()
+            .filter(|note_index| {
+                matches!(self.state.note_index_state[*note_index], NoteState::Pressed | NoteState::Sustain)
+                    || self.state.note_index_to_midi(*note_index as u8).is_some()
+            })
+            .collect();
+
+        // Mono mode narrows what is left to one note
+        match &mut self.mono {
+            Some(mono) => {
+                mono.select(&mut sounding);
+
+                self.state.legato = mono.is_legato();
+                self.state.glide_ms = mono.settings().glide_ms;
+            }
+            None => {
+                self.state.legato = false;
+                self.state.glide_ms = 0;
+            }
+        }
+
+        match &mut self.voice_allocator {
+            Some(allocator) => {
+                allocator.allocate(&mut sounding);
+
+                self.state.stolen = *allocator.stolen();
+
+                // Released notes keep their voice for the Note Off
+                for (voice, note) in self.state.voice_note.iter_mut().enumerate() {
+                    let next = allocator.note_for_voice(voice).unwrap_or(NO_NOTE);
+
+                    self.state.released_voice_note[voice] = if *note != next { *note } else { NO_NOTE };
+                    *note = next;
+                }
+            }
+            None => {
+                self.state.stolen.clear();
+                self.state.voice_note = [NO_NOTE; MAX_VOICES];
+                self.state.released_voice_note = [NO_NOTE; MAX_VOICES];
+            }
+        }
+
+        for note_index in 0..NUM_NOTES {
+            self.set_note_index_active(note_index as u8, sounding[note_index]);
+        }
+
+        self.state.advance_envelopes(delta_t_ms);
+
+        self.update_remote_notes();
+    }
+
+    fn set_note_index_active(&mut self, note_index: u8, is_active: bool) {
+        let changed = if is_active {
+            self.state.activate_note_index(note_index)
+        } else {
+            self.state.deactivate_note_index(note_index)
+        };
+
+        self.state.dirty = changed || self.state.dirty;
+    }
+
+    /// Steps through held notes instead of playing them together, or `None` to play normally.
+    pub fn set_arpeggiator(&mut self, settings: Option<ArpSettings>) {
+        match (&mut self.arpeggiator, settings) {
+            (Some(arpeggiator), Some(settings)) => arpeggiator.set_settings(settings),
+            (_, settings) => self.arpeggiator = settings.map(Arpeggiator::new),
+        }
+    }
+
+    pub fn arpeggiator(&self) -> Option<&ArpSettings> {
+        self.arpeggiator.as_ref().map(|arpeggiator| arpeggiator.settings())
+    }
+
+    /// Plays a chord on each note key's note, or `None` for single notes.
+    pub fn set_chord(&mut self, settings: Option<ChordSettings>) {
+        self.chord = settings;
+    }
+
+    pub fn chord(&self) -> Option<&ChordSettings> {
+        self.chord.as_ref()
+    }
+
+    /// Limits the notes sounding at once, or `None` for no limit.
+    pub fn set_voice_allocation(&mut self, settings: Option<VoiceSettings>) {
+        match (&mut self.voice_allocator, settings) {
+            (Some(allocator), Some(settings)) => allocator.set_settings(settings),
+            (_, settings) => self.voice_allocator = settings.map(VoiceAllocator::new),
+        }
+    }
+
+    pub fn voice_allocation(&self) -> Option<&VoiceSettings> {
+        self.voice_allocator.as_ref().map(|allocator| allocator.settings())
+    }
+
+    /// Plays one note at a time, chosen from the held notes by priority, or `None` for
+    /// polyphonic play.
+    pub fn set_mono(&mut self, settings: Option<MonoSettings>) {
+        match (&mut self.mono, settings) {
+            (Some(mono), Some(settings)) => mono.set_settings(settings),
+            (_, settings) => self.mono = settings.map(MonoMode::new),
+        }
+    }
+
+    pub fn mono(&self) -> Option<&MonoSettings> {
+        self.mono.as_ref().map(|mono| mono.settings())
+    }
+
+    /// Applies to notes started afterwards.  The audio renderer and MIDI encoder read a note's
+    /// pitch once, when they start it, so sounding notes keep theirs.  A pitch bend for a new
+    /// note still bends the others on its MIDI channel; see `MidiEncoder::set_pitch_bend_range`.
+    pub fn set_tuning(&mut self, tuning: Tuning) {
+        self.state.tuning = tuning;
+    }
 
-            if keyboard_state.state[i as usize] {
-                self.state.dirty = self.state.activate_note_index(note_index) || self.state.dirty;
+    pub fn set_envelope(&mut self, settings: AdsrSettings) {
+        self.state.envelope = settings;
+    }
+
+    /// Damper pedal from the bus.  Keys with the `FUNCTION_SUSTAIN` role also hold it down while
+    /// they are all pressed together.
+    pub fn set_sustain_pedal(&mut self, is_down: bool) {
+        self.sustain_pedal = is_down;
+    }
+
+    /// Whether the pedal is down, from the bus or the keys.
+    pub fn sustain(&self) -> bool {
+        self.sustain_pedal || self.sustain_keys_down
+    }
+
+    /// In latch mode each press of a note key toggles its note.  Turning latch off releases the
+    /// latched notes.
+    pub fn set_latch(&mut self, latch: bool) {
+        self.latch = latch;
+        self.latched.clear();
+    }
+
+    pub fn latch(&self) -> bool {
+        self.latch
+    }
+
+    fn update_remote_notes(&mut self) {
+        for note_index in 0..NUM_NOTES {
+            let note_state = self.state.remote_note_state.get(note_index);
+            let changed = if self.remote_held[note_index] {
+                note_state.activate()
             } else {
-                self.state.dirty = self.state.deactivate_note_index(note_index) || self.state.dirty;
+                note_state.deactivate()
+            };
+
+            if changed != note_state {
+                self.state.remote_note_state.set(note_index, changed);
+                self.state.dirty = true;
             }
         }
     }
+
+    /// Swaps in a new layout, releasing every note so none is left held by a key that moved.
+    pub fn set_keymap(&mut self, keymap: Keymap) {
+        for note_index in 0..NUM_NOTES as u8 {
+            self.state.dirty = self.state.deactivate_note_index(note_index) || self.state.dirty;
+        }
+
+        self.state.keymap = keymap;
+    }
+}
+
+impl Default for SynthEngine {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 #[cfg(test)]
+#[allow(non_snake_case)]
 mod test {
     use crate::{SynthState, SynthEngine, MIDI_NOTE_OFFSET};
 
@@ -249,6 +872,35 @@ mod test {
         assert_eq!(note_index, 96);
     }
 
+    #[test]
+    fn note_index_for_C4_produces_midi_60() {
+        let mut synth_state = SynthState::new();
+
+        assert_eq!(synth_state.note_index_to_midi(36), Some(60));
+
+        // The current octave is not added again
+        synth_state.octave = 8;
+
+        assert_eq!(synth_state.note_index_to_midi(36), Some(60));
+        assert_eq!(synth_state.note_index_to_midi(96), Some(120));
+    }
+
+    #[test]
+    fn transpose_shifts_midi_note_within_range() {
+        let mut synth_engine = SynthEngine::new();
+
+        synth_engine.set_transpose(-30);
+
+        assert_eq!(synth_engine.state.transpose, -24);
+        assert_eq!(synth_engine.state.note_index_to_midi(0), Some(0));
+
+        synth_engine.set_transpose(24);
+
+        assert_eq!(synth_engine.state.note_index_to_midi(36), Some(84));
+        assert_eq!(synth_engine.state.note_index_to_midi(79), Some(127));
+        assert_eq!(synth_engine.state.note_index_to_midi(80), None);
+    }
+
     #[test]
     fn note_offset_for_C4_produces_correct_note_offset() {
         let synth_state = SynthState::new();
@@ -280,7 +932,7 @@ mod test {
     #[test]
     fn update_with_no_key_pressed_shows_off() {
         let mut synth_engine = SynthEngine::new();
-        let mut keyboard_state = keyboard_matrix::KeyboardState::default();
+        let keyboard_state = keyboard_matrix::KeyboardState::default();
 
         synth_engine.update(&keyboard_state);
 
@@ -292,7 +944,7 @@ mod test {
         let mut synth_engine = SynthEngine::new();
         let mut keyboard_state = keyboard_matrix::KeyboardState::default();
 
-        keyboard_state.state[13] = true;
+        keyboard_state.state.set(13, true);
 
         synth_engine.update(&keyboard_state);
 
@@ -306,7 +958,7 @@ mod test {
 
         synth_engine.state.note_index_state[36] = crate::NoteState::Pressed;
 
-        keyboard_state.state[13] = true;
+        keyboard_state.state.set(13, true);
 
         synth_engine.update(&keyboard_state);
 
@@ -320,7 +972,7 @@ mod test {
 
         synth_engine.state.note_index_state[36] = crate::NoteState::Sustain;
 
-        keyboard_state.state[13] = true;
+        keyboard_state.state.set(13, true);
 
         synth_engine.update(&keyboard_state);
 
@@ -334,7 +986,7 @@ mod test {
 
         synth_engine.state.note_index_state[36] = crate::NoteState::Sustain;
 
-        keyboard_state.state[13] = false;
+        keyboard_state.state.set(13, false);
 
         synth_engine.update(&keyboard_state);
 
@@ -348,13 +1000,490 @@ mod test {
 
         synth_engine.state.note_index_state[36] = crate::NoteState::Release;
 
-        keyboard_state.state[13] = false;
+        keyboard_state.state.set(13, false);
+
+        synth_engine.update(&keyboard_state);
+
+        assert_eq!(synth_engine.state.note_index_state[36].to_int(), crate::NoteState::Off.to_int());
+    }
+
+    #[test]
+    fn update_with_ghosted_key_does_not_press() {
+        let mut synth_engine = SynthEngine::new();
+        let mut keyboard_state = keyboard_matrix::KeyboardState::default();
+
+        keyboard_state.state.set(13, true);
+        keyboard_state.ghosted.set(13, true);
+
+        synth_engine.update(&keyboard_state);
+
+        assert_eq!(synth_engine.state.note_index_state[36].to_int(), crate::NoteState::Off.to_int());
+    }
+
+    #[test]
+    fn update_with_stuck_key_releases_note() {
+        let mut synth_engine = SynthEngine::new();
+        let mut keyboard_state = keyboard_matrix::KeyboardState::default();
+
+        synth_engine.state.note_index_state[36] = crate::NoteState::Sustain;
+
+        keyboard_state.state.set(13, true);
+        keyboard_state.stuck.set(13, true);
 
         synth_engine.update(&keyboard_state);
 
+        assert_eq!(synth_engine.state.note_index_state[36].to_int(), crate::NoteState::Release.to_int());
+    }
+
+    #[test]
+    fn update_with_mirrored_keymap_plays_remapped_note() {
+        let mut synth_engine = SynthEngine::new();
+        let mut keyboard_state = keyboard_matrix::KeyboardState::default();
+
+        let mut roles = *crate::Keymap::DEFAULT.roles();
+        roles.swap(13, 20);
+        roles.swap(0, 7);
+        synth_engine.set_keymap(crate::Keymap::new(roles).unwrap());
+
+        keyboard_state.state.set(20, true);
+        keyboard_state.pressed.set(0, true);
+
+        synth_engine.update(&keyboard_state);
+
+        assert_eq!(synth_engine.state.octave, 8);
+        assert_eq!(synth_engine.state.note_index_state[84].to_int(), crate::NoteState::Pressed.to_int());
+        assert_eq!(synth_engine.state.note_offset_to_index(0), 20);
+    }
+
+    #[test]
+    fn update_in_d_major_plays_scale_degrees() {
+        let mut synth_engine = SynthEngine::new();
+        let mut keyboard_state = keyboard_matrix::KeyboardState::default();
+
+        synth_engine.set_scale(2, crate::Scale::Major);
+
+        // First degree, fourth degree and the top key, which wraps to E of the next octave
+        keyboard_state.state.set(13, true);
+        keyboard_state.state.set(11, true);
+        keyboard_state.state.set(20, true);
+
+        synth_engine.update(&keyboard_state);
+
+        for note_index in [38, 43, 59] {
+            assert_eq!(synth_engine.state.note_index_state[note_index].to_int(), crate::NoteState::Pressed.to_int());
+        }
+
         assert_eq!(synth_engine.state.note_index_state[36].to_int(), crate::NoteState::Off.to_int());
     }
 
+    #[test]
+    fn held_key_sustains_across_octave_boundary() {
+        let mut synth_engine = SynthEngine::new();
+        let mut keyboard_state = keyboard_matrix::KeyboardState::default();
+
+        // C2 of octave 4 shares its note with C of octave 5
+        keyboard_state.state.set(20, true);
+
+        synth_engine.update(&keyboard_state);
+        synth_engine.update(&keyboard_state);
+
+        assert_eq!(synth_engine.state.note_index_state[48].to_int(), crate::NoteState::Sustain.to_int());
+    }
+
+    #[test]
+    fn scale_degrees_past_top_note_are_silent() {
+        let mut synth_engine = SynthEngine::new();
+        let mut keyboard_state = keyboard_matrix::KeyboardState::default();
+
+        synth_engine.set_octave(8);
+        synth_engine.set_scale(11, crate::Scale::MinorPentatonic);
+
+        keyboard_state.state.set(20, true);
+
+        synth_engine.update(&keyboard_state);
+
+        assert_eq!(synth_engine.state.key_note_index(20), None);
+        assert!(synth_engine.state.note_index_state.iter().all(|note_state| !note_state.is_active()));
+    }
+
+    #[test]
+    fn arpeggiator_plays_held_chord_one_note_at_a_time() {
+        let mut synth_engine = SynthEngine::new();
+        let mut keyboard_state = keyboard_matrix::KeyboardState::default();
+
+        synth_engine.set_arpeggiator(Some(crate::ArpSettings {
+            tempo_bpm: 125,
+            ..crate::ArpSettings::default()
+        }));
+
+        keyboard_state.state.set(13, true);
+        keyboard_state.state.set(15, true);
+
+        synth_engine.update_timed(0, &keyboard_state);
+
+        assert_eq!(synth_engine.state.note_index_state[36].to_int(), crate::NoteState::Pressed.to_int());
+        assert_eq!(synth_engine.state.note_index_state[40].to_int(), crate::NoteState::Off.to_int());
+
+        // Gate closes half way through the 120ms step
+        synth_engine.update_timed(60, &keyboard_state);
+
+        assert_eq!(synth_engine.state.note_index_state[36].to_int(), crate::NoteState::Release.to_int());
+
+        synth_engine.update_timed(60, &keyboard_state);
+
+        assert_eq!(synth_engine.state.note_index_state[40].to_int(), crate::NoteState::Pressed.to_int());
+
+        synth_engine.set_arpeggiator(None);
+        synth_engine.update(&keyboard_state);
+
+        assert_eq!(synth_engine.state.note_index_state[36].to_int(), crate::NoteState::Pressed.to_int());
+        assert_eq!(synth_engine.state.note_index_state[40].to_int(), crate::NoteState::Sustain.to_int());
+    }
+
+    #[test]
+    fn sequencer_playback_drives_note_state() {
+        let mut synth_engine = SynthEngine::new();
+        let mut sequencer = crate::Sequencer::<8>::new();
+        let mut keyboard_state = keyboard_matrix::KeyboardState::default();
+
+        sequencer.set_tempo(125);
+        sequencer.set_length_bars(1);
+        sequencer.record();
+
+        keyboard_state.state.set(13, true);
+        synth_engine.update_sequenced(20, &keyboard_state, &mut sequencer);
+
+        keyboard_state.state.set(13, false);
+        synth_engine.update_sequenced(20, &keyboard_state, &mut sequencer);
+
+        assert_eq!(synth_engine.state.note_index_state[36].to_int(), crate::NoteState::Release.to_int());
+
+        // One bar later the recorded note plays on its own
+        sequencer.play();
+
+        for _ in 0..95 {
+            synth_engine.update_sequenced(20, &keyboard_state, &mut sequencer);
+        }
+
+        assert_eq!(synth_engine.state.note_index_state[36].to_int(), crate::NoteState::Pressed.to_int());
+        assert!(synth_engine.state.note_held_for_key(13));
+    }
+
+    #[test]
+    fn shared_chord_notes_release_with_last_chord() {
+        let mut synth_engine = SynthEngine::new();
+        let mut keyboard_state = keyboard_matrix::KeyboardState::default();
+
+        synth_engine.set_chord(Some(crate::ChordSettings::new(crate::ChordShape::Major)));
+
+        // C major and E major share E
+        keyboard_state.state.set(13, true);
+        keyboard_state.state.set(15, true);
+        synth_engine.update(&keyboard_state);
+
+        for note_index in [36, 40, 43, 44, 47] {
+            assert!(synth_engine.state.note_index_state[note_index].is_active());
+        }
+
+        keyboard_state.state.set(13, false);
+        synth_engine.update(&keyboard_state);
+
+        assert_eq!(synth_engine.state.note_index_state[36].to_int(), crate::NoteState::Release.to_int());
+        assert_eq!(synth_engine.state.note_index_state[43].to_int(), crate::NoteState::Release.to_int());
+        assert_eq!(synth_engine.state.note_index_state[40].to_int(), crate::NoteState::Sustain.to_int());
+
+        keyboard_state.state.set(15, false);
+        synth_engine.update(&keyboard_state);
+
+        assert_eq!(synth_engine.state.note_index_state[40].to_int(), crate::NoteState::Release.to_int());
+    }
+
+    #[test]
+    fn sustain_pedal_holds_notes_through_octave_change() {
+        let mut synth_engine = SynthEngine::new();
+        let mut keyboard_state = keyboard_matrix::KeyboardState::default();
+
+        synth_engine.set_sustain_pedal(true);
+
+        keyboard_state.state.set(13, true);
+        synth_engine.update(&keyboard_state);
+
+        keyboard_state.state.set(13, false);
+        synth_engine.update(&keyboard_state);
+
+        assert_eq!(synth_engine.state.note_index_state[36].to_int(), crate::NoteState::Sustain.to_int());
+
+        // Octave 5, the C held there joins the sustained C4
+        keyboard_state.pressed.set(4, true);
+        keyboard_state.state.set(13, true);
+        synth_engine.update(&keyboard_state);
+
+        keyboard_state.pressed.set(4, false);
+        keyboard_state.state.set(13, false);
+        synth_engine.update(&keyboard_state);
+
+        assert!(synth_engine.state.note_index_state[36].is_active());
+        assert!(synth_engine.state.note_index_state[48].is_active());
+
+        synth_engine.set_sustain_pedal(false);
+        synth_engine.update(&keyboard_state);
+
+        assert_eq!(synth_engine.state.note_index_state[36].to_int(), crate::NoteState::Release.to_int());
+        assert_eq!(synth_engine.state.note_index_state[48].to_int(), crate::NoteState::Release.to_int());
+    }
+
+    #[test]
+    fn sustain_key_combination_needs_all_keys() {
+        let mut synth_engine = SynthEngine::new();
+        let mut keyboard_state = keyboard_matrix::KeyboardState::default();
+
+        let mut roles = *crate::Keymap::DEFAULT.roles();
+        roles[8] = crate::KeyRole::Function(crate::FUNCTION_SUSTAIN);
+        roles[9] = crate::KeyRole::Function(crate::FUNCTION_SUSTAIN);
+        synth_engine.set_keymap(crate::Keymap::new(roles).unwrap());
+
+        keyboard_state.state.set(8, true);
+        synth_engine.update(&keyboard_state);
+
+        assert!(!synth_engine.sustain());
+
+        keyboard_state.state.set(9, true);
+        synth_engine.update(&keyboard_state);
+
+        assert!(synth_engine.sustain());
+    }
+
+    #[test]
+    fn latch_toggles_notes_and_keeps_them_across_octaves() {
+        let mut synth_engine = SynthEngine::new();
+        let mut keyboard_state = keyboard_matrix::KeyboardState::default();
+
+        synth_engine.set_latch(true);
+
+        keyboard_state.state.set(13, true);
+        keyboard_state.pressed.set(13, true);
+        synth_engine.update(&keyboard_state);
+        keyboard_state.pressed.set(13, false);
+
+        keyboard_state.state.set(13, false);
+        synth_engine.update(&keyboard_state);
+
+        assert_eq!(synth_engine.state.note_index_state[36].to_int(), crate::NoteState::Sustain.to_int());
+
+        // Changing octave leaves the latched note playing
+        keyboard_state.pressed.set(4, true);
+        synth_engine.update(&keyboard_state);
+        keyboard_state.pressed.set(4, false);
+
+        assert!(synth_engine.state.note_index_state[36].is_active());
+
+        // Pressing C4 again in its own octave releases it
+        keyboard_state.pressed.set(3, true);
+        synth_engine.update(&keyboard_state);
+        keyboard_state.pressed.set(3, false);
+
+        keyboard_state.state.set(13, true);
+        keyboard_state.pressed.set(13, true);
+        synth_engine.update(&keyboard_state);
+
+        assert_eq!(synth_engine.state.note_index_state[36].to_int(), crate::NoteState::Release.to_int());
+    }
+
+    #[test]
+    fn latch_ignores_octave_change_under_held_key() {
+        let mut synth_engine = SynthEngine::new();
+        let mut keyboard_state = keyboard_matrix::KeyboardState::default();
+
+        synth_engine.set_latch(true);
+
+        keyboard_state.state.set(13, true);
+        keyboard_state.pressed.set(13, true);
+        synth_engine.update(&keyboard_state);
+        keyboard_state.pressed.set(13, false);
+
+        // Octave 5 while C4 stays down
+        keyboard_state.pressed.set(4, true);
+        synth_engine.update(&keyboard_state);
+        keyboard_state.pressed.set(4, false);
+        synth_engine.update(&keyboard_state);
+
+        assert!(synth_engine.state.note_index_state[36].is_active());
+        assert_eq!(synth_engine.state.note_index_state[48].to_int(), crate::NoteState::Off.to_int());
+    }
+
+    #[test]
+    fn voice_limit_steals_oldest_note() {
+        let mut synth_engine = SynthEngine::new();
+        let mut keyboard_state = keyboard_matrix::KeyboardState::default();
+
+        synth_engine.set_voice_allocation(Some(crate::VoiceSettings {
+            voices: 2,
+            policy: crate::StealPolicy::Oldest,
+        }));
+
+        for key in [13, 15, 17] {
+            keyboard_state.state.set(key, true);
+            synth_engine.update(&keyboard_state);
+        }
+
+        assert_eq!(synth_engine.state.note_index_state[36].to_int(), crate::NoteState::Release.to_int());
+        assert!(synth_engine.state.note_stolen_for_key(13));
+        assert!(!synth_engine.state.note_held_for_key(13));
+
+        // The stolen C4 still reports its voice for the Note Off
+        assert_eq!(synth_engine.state.note_voice(36), Some(0));
+        assert_eq!(synth_engine.state.note_voice(40), Some(1));
+        assert_eq!(synth_engine.state.note_voice(43), Some(0));
+    }
+
+    #[test]
+    fn envelope_keeps_sounding_after_note_state_is_off() {
+        let mut synth_engine = SynthEngine::new();
+        let mut keyboard_state = keyboard_matrix::KeyboardState::default();
+
+        synth_engine.set_envelope(crate::AdsrSettings {
+            attack_ms: 0,
+            decay_ms: 0,
+            sustain_level: 200,
+            release_ms: 100,
+        });
+
+        keyboard_state.state.set(13, true);
+        synth_engine.update_timed(10, &keyboard_state);
+        synth_engine.update_timed(10, &keyboard_state);
+
+        assert_eq!(synth_engine.state.note_envelope(36).level(), 200);
+
+        keyboard_state.state.set(13, false);
+        synth_engine.update_timed(10, &keyboard_state);
+        synth_engine.update_timed(40, &keyboard_state);
+
+        assert_eq!(synth_engine.state.note_index_state[36].to_int(), crate::NoteState::Off.to_int());
+        assert_eq!(synth_engine.state.note_envelope(36).level(), 100);
+        assert_eq!(synth_engine.state.release_progress_for_key(13), Some(128));
+
+        synth_engine.update_timed(50, &keyboard_state);
+
+        assert!(!synth_engine.state.note_envelope(36).is_active());
+    }
+
+    #[test]
+    fn envelope_pool_reuses_quietest_released_envelope() {
+        let mut state = crate::SynthState::new();
+
+        state.envelope = crate::AdsrSettings {
+            attack_ms: 0,
+            decay_ms: 0,
+            sustain_level: 200,
+            release_ms: 100,
+        };
+
+        for note_index in 0..crate::MAX_VOICES {
+            state.note_index_state[note_index] = crate::NoteState::Pressed;
+        }
+
+        state.advance_envelopes(10);
+
+        for note_index in 0..crate::MAX_VOICES {
+            state.note_index_state[note_index] = crate::NoteState::Sustain;
+        }
+
+        state.note_index_state[0] = crate::NoteState::Release;
+        state.advance_envelopes(50);
+        state.note_index_state[0] = crate::NoteState::Off;
+
+        state.note_index_state[1] = crate::NoteState::Release;
+        state.advance_envelopes(10);
+        state.note_index_state[1] = crate::NoteState::Off;
+
+        state.note_index_state[40] = crate::NoteState::Pressed;
+        state.advance_envelopes(10);
+
+        assert!(!state.note_envelope(0).is_active());
+        assert_eq!(state.note_envelope(1).stage(), crate::EnvelopeStage::Release);
+        assert!(state.note_envelope(40).is_active());
+    }
+
+    #[test]
+    fn envelope_pool_keeps_held_notes_and_starts_late_notes() {
+        let mut state = crate::SynthState::new();
+
+        state.envelope = crate::AdsrSettings {
+            attack_ms: 0,
+            decay_ms: 0,
+            sustain_level: 200,
+            release_ms: 100,
+        };
+
+        for note_index in 0..crate::MAX_VOICES {
+            state.note_index_state[note_index] = crate::NoteState::Pressed;
+        }
+
+        state.advance_envelopes(10);
+
+        for note_index in 0..crate::MAX_VOICES {
+            state.note_index_state[note_index] = crate::NoteState::Sustain;
+        }
+
+        state.note_index_state[40] = crate::NoteState::Pressed;
+        state.advance_envelopes(10);
+        state.note_index_state[40] = crate::NoteState::Sustain;
+
+        assert!((0..crate::MAX_VOICES as u8).all(|note_index| state.note_envelope(note_index).level() == 200));
+        assert!(!state.note_envelope(40).is_active());
+
+        // Once a release finishes, the waiting note takes its envelope
+        state.note_index_state[0] = crate::NoteState::Release;
+        state.advance_envelopes(100);
+        state.note_index_state[0] = crate::NoteState::Off;
+        state.advance_envelopes(10);
+
+        assert!(!state.note_envelope(0).is_active());
+        assert!(state.note_envelope(40).is_active());
+    }
+
+    #[test]
+    fn received_midi_notes_update_remote_layer() {
+        let mut synth_engine = SynthEngine::new();
+        let keyboard_state = keyboard_matrix::KeyboardState::default();
+
+        // Note On C4 and E4 using running status, split across two reads
+        synth_engine.receive_midi(&[0x90, 60, 100, 64]);
+        synth_engine.receive_midi(&[100]);
+        synth_engine.update(&keyboard_state);
+
+        assert!(synth_engine.state.dirty);
+        assert_eq!(synth_engine.state.remote_note_state(36).to_int(), crate::NoteState::Pressed.to_int());
+        assert_eq!(synth_engine.state.note_index_state[36].to_int(), crate::NoteState::Off.to_int());
+        assert!(synth_engine.state.remote_note_held_for_key(15));
+
+        // Note Off C4 as a zero velocity Note On
+        synth_engine.receive_midi(&[60, 0]);
+        synth_engine.update(&keyboard_state);
+
+        assert_eq!(synth_engine.state.remote_note_state(36).to_int(), crate::NoteState::Release.to_int());
+        assert_eq!(synth_engine.state.remote_note_state(40).to_int(), crate::NoteState::Sustain.to_int());
+    }
+
+    #[test]
+    fn remote_channel_filters_notes() {
+        let mut synth_engine = SynthEngine::new();
+        let keyboard_state = keyboard_matrix::KeyboardState::default();
+
+        synth_engine.set_remote_channel(Some(1));
+        synth_engine.receive_midi(&[0x90, 60, 100, 0x91, 62, 100]);
+        synth_engine.update(&keyboard_state);
+
+        assert!(!synth_engine.state.remote_note_state(36).is_active());
+        assert!(synth_engine.state.remote_note_state(38).is_active());
+
+        // All Notes Off
+        synth_engine.receive_midi(&[0xb1, 123, 0]);
+        synth_engine.update(&keyboard_state);
+
+        assert_eq!(synth_engine.state.remote_note_state(38).to_int(), crate::NoteState::Release.to_int());
+    }
+
     #[test]
     fn nodestate_activate_pressed_is_sustain() {
         let under_test = crate::NoteState::Pressed;
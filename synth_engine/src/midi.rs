@@ -0,0 +1,448 @@
+use crate::{nearest_midi_note, NoteState, SynthState, NUM_NOTES};
+
+pub(crate) const NOTE_OFF: u8 = 0x80;
+pub(crate) const NOTE_ON: u8 = 0x90;
+const PITCH_BEND: u8 = 0xe0;
+const PITCH_BEND_CENTER: u16 = 8192;
+const NO_SENT_NOTE: u8 = 0xff;
+
+/// Release velocity sent with Note Off, the MIDI default for keyboards without release sensing.
+pub(crate) const NOTE_OFF_VELOCITY: u8 = 64;
+
+/// Channel for a note: `channel`, or with `channel_per_voice` the channel that many above it,
+/// wrapping after 15.
+pub(crate) fn voice_channel(channel: u8, channel_per_voice: bool, voice: Option<u8>) -> u8 {
+    match voice {
+        Some(voice) if channel_per_voice => (channel + voice) & 0x0f,
+        _ => channel,
+    }
+}
+
+/// Largest output of one `encode`: every note changing, none sharing a status byte, each with a
+/// pitch bend.
+pub const MAX_MIDI_UPDATE_SIZE: usize = NUM_NOTES * 6;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MidiError {
+    BufferTooSmall,
+    /// SysEx data must start with 0xF0, end with 0xF7 and hold only 7 bit bytes between.
+    InvalidSysEx,
+}
+
+pub(crate) struct NoteChange {
+    pub is_on: bool,
+    pub note_index: u8,
+    /// MIDI note the note was started with.
+    pub midi_note: u8,
+    pub voice: Option<u8>,
+}
+
+/// Calls `emit` for every note that just released, then every note that was just pressed,
+/// lowest note first.
+///
+/// A legato update sends the press first, so a mono receiver slides without retriggering.
+pub(crate) fn for_each_note_change(
+    synth_state: &SynthState,
+    emit: &mut dyn FnMut(NoteChange) -> Result<(), MidiError>,
+) -> Result<(), MidiError> {
+    let order = if synth_state.legato {
+        [(true, NoteState::Pressed), (false, NoteState::Release)]
+    } else {
+        [(false, NoteState::Release), (true, NoteState::Pressed)]
+    };
+
+    for (is_on, note_state) in order {
+        for note_index in 0..NUM_NOTES {
+            if synth_state.note_index_state[note_index] == note_state {
+                let note_index = note_index as u8;
+
+                emit(NoteChange {
+                    is_on,
+                    note_index,
+                    midi_note: synth_state.started_midi_note(note_index),
+                    voice: synth_state.note_voice(note_index),
+                })?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Turns the note transitions of a `SynthState` update into MIDI 1.0 channel messages.
+///
+/// Status bytes are omitted while they repeat (running status).  If anything else is written to
+/// the same output between updates, call `reset_running_status` so the next message is complete.
+pub struct MidiEncoder {
+    channel: u8,
+    velocity: u8,
+    zero_velocity_note_off: bool,
+    channel_per_voice: bool,
+    pitch_bend_range: Option<u8>,
+    /// Pitch bend last sent on each channel.
+    channel_bend: [u16; 16],
+    /// Note each note index's Note On was sent as, so its Note Off matches after a tuning
+    /// change, or `NO_SENT_NOTE`.
+    sent_note: [u8; NUM_NOTES],
+    running_status: Option<u8>,
+}
+
+impl MidiEncoder {
+    /// `channel` is 0 - 15 (MIDI channels 1 - 16).
+    pub fn new(channel: u8) -> Self {
+        Self {
+            channel: channel & 0x0f,
+            velocity: 100,
+            zero_velocity_note_off: false,
+            channel_per_voice: false,
+            pitch_bend_range: None,
+            channel_bend: [PITCH_BEND_CENTER; 16],
+            sent_note: [NO_SENT_NOTE; NUM_NOTES],
+            running_status: None,
+        }
+    }
+
+    pub fn set_channel(&mut self, channel: u8) {
+        self.channel = channel & 0x0f;
+    }
+
+    /// Note On velocity, 1 - 127.  0 would read as a Note Off.
+    pub fn set_velocity(&mut self, velocity: u8) {
+        self.velocity = velocity.clamp(1, 127);
+    }
+
+    /// Sends releases as Note On with velocity 0, so a whole update shares one status byte.
+    pub fn set_zero_velocity_note_off(&mut self, zero_velocity_note_off: bool) {
+        self.zero_velocity_note_off = zero_velocity_note_off;
+    }
+
+    /// Sends each voice's notes on its own channel, counting up from the encoder's channel, so
+    /// a multitimbral receiver can follow the voice allocation.
+    pub fn set_channel_per_voice(&mut self, channel_per_voice: bool) {
+        self.channel_per_voice = channel_per_voice;
+    }
+
+    /// Sends each note as the nearest standard MIDI note, with a pitch bend before its Note On
+    /// for the rest of its tuned pitch.  `range` is the receiver's bend range in semitones, or
+    /// `None` to send the notes untuned.
+    ///
+    /// Bends apply to a whole channel, so notes played together need `set_channel_per_voice`.
+    pub fn set_pitch_bend_range(&mut self, range: Option<u8>) {
+        self.pitch_bend_range = range.map(|range| range.clamp(1, 24));
+    }
+
+    pub fn reset_running_status(&mut self) {
+        self.running_status = None;
+    }
+
+    /// Writes Note Off and Note On messages for the notes that changed in the last update.
+    /// Returns the bytes written.
+    ///
+    /// On `BufferTooSmall` the output is incomplete; size `out` to `MAX_MIDI_UPDATE_SIZE`.
+    pub fn encode(&mut self, synth_state: &SynthState, out: &mut [u8]) -> Result<usize, MidiError> {
+        let mut size = 0;
+
+        for_each_note_change(synth_state, &mut |change| {
+            let channel = voice_channel(self.channel, self.channel_per_voice, change.voice);
+
+            let sent_note = self.sent_note[change.note_index as usize];
+
+            let (note, bend) = match self.pitch_bend_range {
+                _ if !change.is_on && sent_note != NO_SENT_NOTE => (sent_note, None),
+                Some(range) if change.is_on => {
+                    let (note, hundredths) = nearest_midi_note(synth_state.note_millihz(change.note_index));
+                    let bend = PITCH_BEND_CENTER as i32 + hundredths * 8192 / (range as i32 * 100 * 100);
+
+                    (note, Some(bend.clamp(0, 0x3fff) as u16))
+                }
+                _ => (change.midi_note, None),
+            };
+
+            match bend {
+                Some(bend) if self.channel_bend[channel as usize] != bend => {
+                    size += self.write_message(PITCH_BEND | channel, bend as u8 & 0x7f, (bend >> 7) as u8, &mut out[size..])?;
+                    self.channel_bend[channel as usize] = bend;
+                }
+                _ => {}
+            }
+
+            size += if change.is_on {
+                self.write_message(NOTE_ON | channel, note, self.velocity, &mut out[size..])?
+            } else {
+                self.write_note_off(channel, note, &mut out[size..])?
+            };
+
+            // Only once written, so a retry after `BufferTooSmall` still ends the note sent
+            self.sent_note[change.note_index as usize] = if change.is_on { note } else { NO_SENT_NOTE };
+
+            Ok(())
+        })?;
+
+        Ok(size)
+    }
+
+    fn write_note_off(&mut self, channel: u8, note: u8, out: &mut [u8]) -> Result<usize, MidiError> {
+        if self.zero_velocity_note_off {
+            self.write_message(NOTE_ON | channel, note, 0, out)
+        } else {
+            self.write_message(NOTE_OFF | channel, note, NOTE_OFF_VELOCITY, out)
+        }
+    }
+
+    fn write_message(&mut self, status: u8, first: u8, second: u8, out: &mut [u8]) -> Result<usize, MidiError> {
+        let send_status = self.running_status != Some(status);
+        let size = if send_status { 3 } else { 2 };
+
+        if out.len() < size {
+            // The receiver's running status is unknown once a message is dropped
+            self.running_status = None;
+
+            return Err(MidiError::BufferTooSmall);
+        }
+
+        let mut position = 0;
+
+        if send_status {
+            out[0] = status;
+            position = 1;
+            self.running_status = Some(status);
+        }
+
+        out[position] = first & 0x7f;
+        out[position + 1] = second;
+
+        Ok(size)
+    }
+}
+
+impl Default for MidiEncoder {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use super::*;
+    use crate::SynthEngine;
+
+    use keyboard_matrix::KeyboardState;
+    use std::vec::Vec;
+
+    struct Harness {
+        synth_engine: SynthEngine,
+        keyboard_state: KeyboardState,
+        encoder: MidiEncoder,
+    }
+
+    impl Harness {
+        fn new() -> Self {
+            Self {
+                synth_engine: SynthEngine::new(),
+                keyboard_state: KeyboardState::default(),
+                encoder: MidiEncoder::default(),
+            }
+        }
+
+        /// Holds exactly `keys` down for one update and returns the MIDI written.
+        fn play(&mut self, keys: &[usize]) -> Vec<u8> {
+            let mut down = [false; 21];
+
+            for key in keys {
+                down[*key] = true;
+            }
+
+            self.keyboard_state = self.keyboard_state.build_new(down);
+            self.synth_engine.update(&self.keyboard_state);
+
+            let mut out = [0u8; MAX_MIDI_UPDATE_SIZE];
+            let size = self.encoder.encode(&self.synth_engine.state, &mut out).unwrap();
+
+            out[..size].to_vec()
+        }
+    }
+
+    #[test]
+    fn chord_shares_one_status_byte() {
+        let mut harness = Harness::new();
+
+        // C, E, G in octave 4
+        assert_eq!(harness.play(&[13, 15, 17]), [0x90, 60, 100, 64, 100, 67, 100]);
+
+        // Held notes send nothing
+        assert_eq!(harness.play(&[13, 15, 17]), []);
+    }
+
+    #[test]
+    fn release_sends_note_off() {
+        let mut harness = Harness::new();
+
+        harness.play(&[13, 15]);
+
+        assert_eq!(harness.play(&[15]), [0x80, 60, 64]);
+        assert_eq!(harness.play(&[]), [64, 64]);
+        assert_eq!(harness.play(&[]), []);
+    }
+
+    #[test]
+    fn octave_switch_moves_held_note() {
+        let mut harness = Harness::new();
+
+        harness.play(&[13]);
+
+        // Octave 5 key while C is held
+        assert_eq!(harness.play(&[4, 13]), [0x80, 60, 64, 0x90, 72, 100]);
+    }
+
+    #[test]
+    fn transpose_change_releases_started_note() {
+        let mut harness = Harness::new();
+        harness.synth_engine.set_transpose(2);
+
+        assert_eq!(harness.play(&[13]), [0x90, 62, 100]);
+
+        harness.synth_engine.set_transpose(-12);
+
+        assert_eq!(harness.play(&[13]), []);
+        assert_eq!(harness.play(&[]), [0x80, 62, 64]);
+        assert_eq!(harness.play(&[13]), [0x90, 48, 100]);
+    }
+
+    #[test]
+    fn notes_transposed_past_midi_range_stay_silent() {
+        let mut harness = Harness::new();
+        harness.synth_engine.set_octave(8);
+        harness.synth_engine.set_transpose(19);
+
+        // C8 transposed is 127, C#8 would be 128
+        assert_eq!(harness.play(&[13, 14]), [0x90, 127, 100]);
+        assert_eq!(harness.play(&[13]), []);
+        assert_eq!(harness.play(&[]), [0x80, 127, 64]);
+    }
+
+    #[test]
+    fn channel_and_velocity_are_configurable() {
+        let mut harness = Harness::new();
+        harness.encoder.set_channel(9);
+        harness.encoder.set_velocity(0);
+
+        assert_eq!(harness.play(&[20]), [0x99, 72, 1]);
+    }
+
+    #[test]
+    fn voices_get_their_own_channels() {
+        let mut harness = Harness::new();
+        harness.encoder.set_channel(2);
+        harness.encoder.set_channel_per_voice(true);
+        harness.synth_engine.set_voice_allocation(Some(crate::VoiceSettings {
+            voices: 2,
+            policy: crate::StealPolicy::Oldest,
+        }));
+
+        assert_eq!(harness.play(&[13, 15]), [0x92, 60, 100, 0x93, 64, 100]);
+
+        // G steals C's voice, so both happen on channel 2
+        assert_eq!(harness.play(&[13, 15, 17]), [0x82, 60, 64, 0x92, 67, 100]);
+    }
+
+    #[test]
+    fn mono_legato_overlaps_notes() {
+        let mut harness = Harness::new();
+        harness.synth_engine.set_mono(Some(crate::MonoSettings::default()));
+
+        assert_eq!(harness.play(&[13, 15]), [0x90, 64, 100]);
+
+        // Releasing E falls back to the still held C, starting it before E ends
+        assert_eq!(harness.play(&[13]), [60, 100, 0x80, 64, 64]);
+        assert_eq!(harness.play(&[]), [60, 64]);
+    }
+
+    #[test]
+    fn retuned_notes_send_pitch_bend() {
+        let mut harness = Harness::new();
+        harness.encoder.set_pitch_bend_range(Some(2));
+        harness.synth_engine.set_tuning(crate::Tuning {
+            reference_millihz: 432_000,
+            ..crate::Tuning::default()
+        });
+
+        // A4 at 432 Hz is A4 bent down 31.77 cents, 8192 - 1301
+        assert_eq!(harness.play(&[18]), [0xe0, 0x6b, 0x35, 0x90, 69, 100]);
+
+        // Same bend for the next note, running status Note On only
+        assert_eq!(harness.play(&[18, 13]), [60, 100]);
+        assert_eq!(harness.play(&[]), [0x80, 60, 64, 69, 64]);
+    }
+
+    #[test]
+    fn note_off_matches_note_on_after_retuning() {
+        let mut harness = Harness::new();
+        harness.encoder.set_pitch_bend_range(Some(2));
+
+        assert_eq!(harness.play(&[18]), [0x90, 69, 100]);
+
+        // A4 would now go out as G#4
+        harness.synth_engine.set_tuning(crate::Tuning {
+            reference_millihz: 415_300,
+            ..crate::Tuning::default()
+        });
+
+        assert_eq!(harness.play(&[]), [0x80, 69, 64]);
+    }
+
+    #[test]
+    fn note_off_retried_after_small_buffer_matches_note_on() {
+        let mut harness = Harness::new();
+        harness.encoder.set_pitch_bend_range(Some(2));
+        harness.synth_engine.set_tuning(crate::Tuning {
+            reference_millihz: 415_300,
+            ..crate::Tuning::default()
+        });
+
+        // A4 is G#4 in this tuning
+        assert_eq!(harness.play(&[18]), [0x90, 68, 100]);
+
+        harness.keyboard_state = harness.keyboard_state.build_new([false; 21]);
+        harness.synth_engine.update(&harness.keyboard_state);
+
+        let mut out = [0u8; 2];
+
+        assert_eq!(harness.encoder.encode(&harness.synth_engine.state, &mut out), Err(MidiError::BufferTooSmall));
+
+        let mut out = [0u8; MAX_MIDI_UPDATE_SIZE];
+        let size = harness.encoder.encode(&harness.synth_engine.state, &mut out).unwrap();
+
+        assert_eq!(out[..size], [0x80, 68, 64]);
+    }
+
+    #[test]
+    fn zero_velocity_note_off_keeps_running_status() {
+        let mut harness = Harness::new();
+        harness.encoder.set_zero_velocity_note_off(true);
+
+        harness.play(&[13]);
+
+        assert_eq!(harness.play(&[14]), [60, 0, 62, 100]);
+    }
+
+    #[test]
+    fn small_buffer_is_reported_and_resets_running_status() {
+        let mut synth_engine = SynthEngine::new();
+        let mut encoder = MidiEncoder::default();
+
+        let mut keyboard_state = KeyboardState::default();
+        keyboard_state.state.set(13, true);
+        keyboard_state.state.set(14, true);
+        synth_engine.update(&keyboard_state);
+
+        let mut out = [0u8; 4];
+
+        assert_eq!(encoder.encode(&synth_engine.state, &mut out), Err(MidiError::BufferTooSmall));
+
+        let mut out = [0u8; 8];
+
+        assert_eq!(encoder.encode(&synth_engine.state, &mut out), Ok(5));
+        assert_eq!(out[0], 0x90);
+    }
+}
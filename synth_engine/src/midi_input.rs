@@ -0,0 +1,281 @@
+/// A complete incoming MIDI message.  Channels are 0 - 15.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MidiMessage<'a> {
+    /// Also produced for Note On with velocity 0.
+    NoteOff { channel: u8, note: u8, velocity: u8 },
+    NoteOn { channel: u8, note: u8, velocity: u8 },
+    PolyPressure { channel: u8, note: u8, pressure: u8 },
+    ControlChange { channel: u8, controller: u8, value: u8 },
+    ProgramChange { channel: u8, program: u8 },
+    ChannelPressure { channel: u8, pressure: u8 },
+    /// 14 bit bend, 8192 is centered.
+    PitchBend { channel: u8, value: u16 },
+    /// System common messages other than SysEx, unused data bytes are 0.
+    SystemCommon { status: u8, data: [u8; 2] },
+    /// Clock, start, stop and the other single byte realtime messages.
+    Realtime(u8),
+    /// Data bytes between 0xF0 and 0xF7, excluding both.
+    SysEx(&'a [u8]),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ParseState {
+    Idle,
+    Message { status: u8, received: usize },
+    SysEx { overflowed: bool },
+}
+
+/// Streaming MIDI 1.0 byte parser.  Feed bytes one at a time in the order received.
+///
+/// Running status is honored for channel messages, realtime bytes may appear anywhere, including
+/// inside other messages, and SysEx up to `SYSEX_CAPACITY` data bytes is collected.  Longer SysEx
+/// is dropped and counted in `dropped_sysex`.
+pub struct MidiParser<const SYSEX_CAPACITY: usize = 32> {
+    state: ParseState,
+    data: [u8; 2],
+    sysex: [u8; SYSEX_CAPACITY],
+    sysex_size: usize,
+    dropped_sysex: u32,
+}
+
+/// Data bytes following a status byte, or `None` for statuses that are not parsed here.
+fn data_size(status: u8) -> Option<usize> {
+    match status {
+        0x80..=0xbf | 0xe0..=0xef => Some(2),
+        0xc0..=0xdf => Some(1),
+        0xf1 | 0xf3 => Some(1),
+        0xf2 => Some(2),
+        0xf6 => Some(0),
+        _ => None,
+    }
+}
+
+impl<const SYSEX_CAPACITY: usize> MidiParser<SYSEX_CAPACITY> {
+    pub fn new() -> Self {
+        Self {
+            state: ParseState::Idle,
+            data: [0; 2],
+            sysex: [0; SYSEX_CAPACITY],
+            sysex_size: 0,
+            dropped_sysex: 0,
+        }
+    }
+
+    pub fn dropped_sysex(&self) -> u32 {
+        self.dropped_sysex
+    }
+
+    /// Returns a message when `byte` completes one.
+    pub fn push(&mut self, byte: u8) -> Option<MidiMessage<'_>> {
+        if byte >= 0xf8 {
+            return Some(MidiMessage::Realtime(byte));
+        }
+
+        if byte == 0xf7 {
+            let state = self.state;
+            self.state = ParseState::Idle;
+
+            return match state {
+                ParseState::SysEx { overflowed: false } => Some(MidiMessage::SysEx(&self.sysex[..self.sysex_size])),
+                _ => None,
+            };
+        }
+
+        if byte & 0x80 != 0 {
+            // Any other status ends an unfinished SysEx, which is then discarded
+            if byte == 0xf0 {
+                self.state = ParseState::SysEx { overflowed: false };
+                self.sysex_size = 0;
+
+                return None;
+            }
+
+            match data_size(byte) {
+                Some(0) => {
+                    self.state = ParseState::Idle;
+
+                    return Some(MidiMessage::SystemCommon { status: byte, data: [0; 2] });
+                }
+                Some(_) => self.state = ParseState::Message { status: byte, received: 0 },
+                None => self.state = ParseState::Idle,
+            }
+
+            return None;
+        }
+
+        match self.state {
+            ParseState::Idle => None,
+            ParseState::SysEx { overflowed } => {
+                if overflowed {
+                    return None;
+                }
+
+                if self.sysex_size == SYSEX_CAPACITY {
+                    self.state = ParseState::SysEx { overflowed: true };
+                    self.dropped_sysex += 1;
+
+                    return None;
+                }
+
+                self.sysex[self.sysex_size] = byte;
+                self.sysex_size += 1;
+
+                None
+            }
+            ParseState::Message { status, received } => {
+                self.data[received] = byte;
+
+                if received + 1 < data_size(status).unwrap_or(0) {
+                    self.state = ParseState::Message { status, received: received + 1 };
+
+                    return None;
+                }
+
+                // Channel messages keep their status for the next data bytes, system common does not
+                self.state = if status < 0xf0 {
+                    ParseState::Message { status, received: 0 }
+                } else {
+                    ParseState::Idle
+                };
+
+                Some(self.message(status))
+            }
+        }
+    }
+
+    fn message(&self, status: u8) -> MidiMessage<'_> {
+        let channel = status & 0x0f;
+        let [first, second] = self.data;
+
+        match status & 0xf0 {
+            0x80 => MidiMessage::NoteOff { channel, note: first, velocity: second },
+            0x90 if second == 0 => MidiMessage::NoteOff { channel, note: first, velocity: 0 },
+            0x90 => MidiMessage::NoteOn { channel, note: first, velocity: second },
+            0xa0 => MidiMessage::PolyPressure { channel, note: first, pressure: second },
+            0xb0 => MidiMessage::ControlChange { channel, controller: first, value: second },
+            0xc0 => MidiMessage::ProgramChange { channel, program: first },
+            0xd0 => MidiMessage::ChannelPressure { channel, pressure: first },
+            0xe0 => MidiMessage::PitchBend {
+                channel,
+                value: (second as u16) << 7 | first as u16,
+            },
+            _ => {
+                let data = if data_size(status) == Some(1) { [first, 0] } else { [first, second] };
+
+                MidiMessage::SystemCommon { status, data }
+            }
+        }
+    }
+}
+
+impl<const SYSEX_CAPACITY: usize> Default for MidiParser<SYSEX_CAPACITY> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use super::*;
+
+    use std::format;
+    use std::string::String;
+    use std::vec::Vec;
+
+    /// Parses `bytes` and returns each message formatted, since SysEx borrows the parser.
+    fn parse<const SYSEX_CAPACITY: usize>(parser: &mut MidiParser<SYSEX_CAPACITY>, bytes: &[u8]) -> Vec<String> {
+        bytes
+            .iter()
+            .filter_map(|byte| parser.push(*byte).map(|message| format!("{:?}", message)))
+            .collect()
+    }
+
+    fn expected(messages: &[MidiMessage]) -> Vec<String> {
+        messages.iter().map(|message| format!("{:?}", message)).collect()
+    }
+
+    #[test]
+    fn running_status_repeats_note_on() {
+        let mut parser = MidiParser::<32>::new();
+
+        assert_eq!(
+            parse(&mut parser, &[0x91, 60, 100, 64, 90, 60, 0]),
+            expected(&[
+                MidiMessage::NoteOn { channel: 1, note: 60, velocity: 100 },
+                MidiMessage::NoteOn { channel: 1, note: 64, velocity: 90 },
+                MidiMessage::NoteOff { channel: 1, note: 60, velocity: 0 },
+            ])
+        );
+    }
+
+    #[test]
+    fn realtime_inside_message_does_not_break_it() {
+        let mut parser = MidiParser::<32>::new();
+
+        assert_eq!(
+            parse(&mut parser, &[0x80, 0xf8, 60, 0xfe, 64]),
+            expected(&[
+                MidiMessage::Realtime(0xf8),
+                MidiMessage::Realtime(0xfe),
+                MidiMessage::NoteOff { channel: 0, note: 60, velocity: 64 },
+            ])
+        );
+    }
+
+    #[test]
+    fn two_and_three_byte_messages() {
+        let mut parser = MidiParser::<32>::new();
+
+        assert_eq!(
+            parse(&mut parser, &[0xc2, 5, 7, 0xe0, 0x00, 0x40, 0xf2, 1, 2, 3]),
+            expected(&[
+                MidiMessage::ProgramChange { channel: 2, program: 5 },
+                MidiMessage::ProgramChange { channel: 2, program: 7 },
+                MidiMessage::PitchBend { channel: 0, value: 8192 },
+                MidiMessage::SystemCommon { status: 0xf2, data: [1, 2] },
+            ])
+        );
+    }
+
+    #[test]
+    fn sysex_is_collected() {
+        let mut parser = MidiParser::<32>::new();
+
+        assert_eq!(
+            parse(&mut parser, &[0xf0, 0x7d, 0xf8, 1, 2, 0xf7, 3]),
+            expected(&[MidiMessage::Realtime(0xf8), MidiMessage::SysEx(&[0x7d, 1, 2])])
+        );
+    }
+
+    #[test]
+    fn long_sysex_is_dropped_and_parsing_resumes() {
+        let mut parser = MidiParser::<2>::new();
+
+        assert_eq!(
+            parse(&mut parser, &[0xf0, 1, 2, 3, 0xf7, 0x90, 60, 1]),
+            expected(&[MidiMessage::NoteOn { channel: 0, note: 60, velocity: 1 }])
+        );
+        assert_eq!(parser.dropped_sysex(), 1);
+    }
+
+    #[test]
+    fn status_byte_aborts_unfinished_sysex() {
+        let mut parser = MidiParser::<32>::new();
+
+        assert_eq!(
+            parse(&mut parser, &[0xf0, 1, 0x90, 60, 100, 0xf7]),
+            expected(&[MidiMessage::NoteOn { channel: 0, note: 60, velocity: 100 }])
+        );
+    }
+
+    #[test]
+    fn stray_data_bytes_are_ignored() {
+        let mut parser = MidiParser::<32>::new();
+
+        assert_eq!(
+            parse(&mut parser, &[60, 100, 0xf1, 3, 4]),
+            expected(&[MidiMessage::SystemCommon { status: 0xf1, data: [3, 0] }])
+        );
+    }
+}
@@ -0,0 +1,239 @@
+use crate::NoteSet;
+
+/// Presses remembered for `NotePriority::Last`.
+const MAX_PRESS_ORDER: usize = 16;
+
+/// Which held note sounds in mono mode.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NotePriority {
+    Last,
+    Lowest,
+    Highest,
+}
+
+impl NotePriority {
+    pub fn id(&self) -> u8 {
+        match self {
+            NotePriority::Last => 0,
+            NotePriority::Lowest => 1,
+            NotePriority::Highest => 2,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(NotePriority::Last),
+            1 => Some(NotePriority::Lowest),
+            2 => Some(NotePriority::Highest),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MonoSettings {
+    pub priority: NotePriority,
+    /// Moving between held notes is flagged as legato, so the new note starts before the old
+    /// one ends instead of retriggering.
+    pub legato: bool,
+    /// Portamento time between notes, 0 for none.
+    pub glide_ms: u16,
+}
+
+impl Default for MonoSettings {
+    fn default() -> Self {
+        Self {
+            priority: NotePriority::Last,
+            legato: true,
+            glide_ms: 0,
+        }
+    }
+}
+
+/// Reduces the held notes to one, chosen by priority.  Releasing the sounding note falls back
+/// to the best of the notes still held.
+///
+/// Last note priority remembers the latest 16 presses.  Once those are all released, it falls
+/// back to the highest note still held.
+pub struct MonoMode {
+    settings: MonoSettings,
+    /// Latest held notes, oldest press first.
+    order: [u8; MAX_PRESS_ORDER],
+    len: usize,
+    held: NoteSet,
+    current: Option<u8>,
+    legato: bool,
+}
+
+impl MonoMode {
+    pub fn new(settings: MonoSettings) -> Self {
+        Self {
+            settings,
+            order: [0; MAX_PRESS_ORDER],
+            len: 0,
+            held: NoteSet::new(),
+            current: None,
+            legato: false,
+        }
+    }
+
+    pub fn settings(&self) -> &MonoSettings {
+        &self.settings
+    }
+
+    pub fn set_settings(&mut self, settings: MonoSettings) {
+        self.settings = settings;
+    }
+
+    /// Note sounding after the last `select`.
+    pub fn current(&self) -> Option<u8> {
+        self.current
+    }
+
+    /// The last `select` moved from one held note straight to another with legato on.
+    pub fn is_legato(&self) -> bool {
+        self.legato
+    }
+
+    /// Clears every note from `held` except the one that should sound.
+    pub fn select(&mut self, held: &mut NoteSet) {
+        let mut len = 0;
+
+        for i in 0..self.len {
+            let note_index = self.order[i];
+
+            if held[note_index as usize] {
+                self.order[len] = note_index;
+                len += 1;
+            }
+        }
+
+        self.len = len;
+
+        // Notes pressed in the same update join lowest first, pushing out the oldest
+        for note_index in (*held - self.held).iter() {
+            if self.len == MAX_PRESS_ORDER {
+                self.order.copy_within(1.., 0);
+                self.len -= 1;
+            }
+
+            self.order[self.len] = note_index as u8;
+            self.len += 1;
+        }
+
+        self.held = *held;
+
+        let next = match self.settings.priority {
+            NotePriority::Last => self.order[..self.len]
+                .last()
+                .copied()
+                .or_else(|| held.iter().last().map(|note_index| note_index as u8)),
+            NotePriority::Lowest => held.iter().next().map(|note_index| note_index as u8),
+            NotePriority::Highest => held.iter().last().map(|note_index| note_index as u8),
+        };
+
+        self.legato = self.settings.legato && self.current.is_some() && next.is_some() && next != self.current;
+        self.current = next;
+
+        held.clear();
+
+        if let Some(note_index) = next {
+            held.insert(note_index as usize);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use super::*;
+
+    fn select(mono: &mut MonoMode, note_indexes: &[u8]) -> Option<u8> {
+        let mut held: NoteSet = note_indexes.iter().map(|note_index| *note_index as usize).collect();
+
+        mono.select(&mut held);
+
+        let sounding = held.iter().next().map(|note_index| note_index as u8);
+        assert_eq!(sounding, mono.current());
+
+        sounding
+    }
+
+    fn mono(priority: NotePriority) -> MonoMode {
+        MonoMode::new(MonoSettings {
+            priority,
+            ..MonoSettings::default()
+        })
+    }
+
+    #[test]
+    fn last_note_falls_back_to_previous() {
+        let mut mono = mono(NotePriority::Last);
+
+        assert_eq!(select(&mut mono, &[40]), Some(40));
+        assert_eq!(select(&mut mono, &[40, 36]), Some(36));
+        assert_eq!(select(&mut mono, &[40, 36, 43]), Some(43));
+        assert_eq!(select(&mut mono, &[40, 36]), Some(36));
+        assert_eq!(select(&mut mono, &[40]), Some(40));
+        assert_eq!(select(&mut mono, &[]), None);
+    }
+
+    #[test]
+    fn last_note_past_remembered_presses_falls_back_to_highest() {
+        let mut mono = mono(NotePriority::Last);
+        let mut notes = std::vec::Vec::new();
+
+        // 18 presses, the first two are forgotten
+        for note_index in [40, 36].into_iter().chain(50..66) {
+            notes.push(note_index);
+            select(&mut mono, &notes);
+        }
+
+        assert_eq!(select(&mut mono, &notes), Some(65));
+        assert_eq!(select(&mut mono, &notes[..2]), Some(40));
+    }
+
+    #[test]
+    fn lowest_and_highest_ignore_press_order() {
+        let mut lowest = mono(NotePriority::Lowest);
+
+        assert_eq!(select(&mut lowest, &[40]), Some(40));
+        assert_eq!(select(&mut lowest, &[40, 43]), Some(40));
+        assert_eq!(select(&mut lowest, &[40, 43, 36]), Some(36));
+        assert_eq!(select(&mut lowest, &[43]), Some(43));
+
+        let mut highest = mono(NotePriority::Highest);
+
+        assert_eq!(select(&mut highest, &[40, 36]), Some(40));
+        assert_eq!(select(&mut highest, &[36]), Some(36));
+    }
+
+    #[test]
+    fn only_changes_between_held_notes_are_legato() {
+        let mut mono = mono(NotePriority::Last);
+
+        select(&mut mono, &[36]);
+        assert!(!mono.is_legato());
+
+        select(&mut mono, &[36, 40]);
+        assert!(mono.is_legato());
+
+        select(&mut mono, &[36, 40]);
+        assert!(!mono.is_legato());
+
+        select(&mut mono, &[36]);
+        assert!(mono.is_legato());
+
+        select(&mut mono, &[]);
+        select(&mut mono, &[40]);
+        assert!(!mono.is_legato());
+
+        mono.set_settings(MonoSettings {
+            legato: false,
+            ..MonoSettings::default()
+        });
+
+        select(&mut mono, &[40, 43]);
+        assert!(!mono.is_legato());
+    }
+}
@@ -0,0 +1,187 @@
+const CHROMATIC: [u8; 12] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11];
+const MAJOR: [u8; 7] = [0, 2, 4, 5, 7, 9, 11];
+const NATURAL_MINOR: [u8; 7] = [0, 2, 3, 5, 7, 8, 10];
+const HARMONIC_MINOR: [u8; 7] = [0, 2, 3, 5, 7, 8, 11];
+const DORIAN: [u8; 7] = [0, 2, 3, 5, 7, 9, 10];
+const PHRYGIAN: [u8; 7] = [0, 1, 3, 5, 7, 8, 10];
+const LYDIAN: [u8; 7] = [0, 2, 4, 6, 7, 9, 11];
+const MIXOLYDIAN: [u8; 7] = [0, 2, 4, 5, 7, 9, 10];
+const LOCRIAN: [u8; 7] = [0, 1, 3, 5, 6, 8, 10];
+const MAJOR_PENTATONIC: [u8; 5] = [0, 2, 4, 7, 9];
+const MINOR_PENTATONIC: [u8; 5] = [0, 3, 5, 7, 10];
+const BLUES: [u8; 6] = [0, 3, 5, 6, 7, 10];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScaleError {
+    /// Custom scales need 1 - 12 steps of at least one semitone adding up to an octave.
+    InvalidSteps,
+    UnknownScale(u8),
+}
+
+/// Scale built from a list of steps between degrees, e.g. `[2, 2, 1, 2, 2, 2, 1]` for major.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CustomScale {
+    offsets: [u8; 12],
+    len: u8,
+}
+
+impl CustomScale {
+    pub fn from_steps(steps: &[u8]) -> Result<Self, ScaleError> {
+        if steps.is_empty() || steps.len() > 12 || steps.contains(&0) {
+            return Err(ScaleError::InvalidSteps);
+        }
+
+        if steps.iter().map(|step| *step as u32).sum::<u32>() != 12 {
+            return Err(ScaleError::InvalidSteps);
+        }
+
+        let mut offsets = [0u8; 12];
+
+        for i in 1..steps.len() {
+            offsets[i] = offsets[i - 1] + steps[i - 1];
+        }
+
+        Ok(Self {
+            offsets,
+            len: steps.len() as u8,
+        })
+    }
+}
+
+/// Which notes the note keys play.  Key note offsets become scale degrees counted up from the
+/// root, continuing into the next octave once the scale runs out.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Scale {
+    #[default]
+    Chromatic,
+    Major,
+    NaturalMinor,
+    HarmonicMinor,
+    Dorian,
+    Phrygian,
+    Lydian,
+    Mixolydian,
+    Locrian,
+    MajorPentatonic,
+    MinorPentatonic,
+    Blues,
+    Custom(CustomScale),
+}
+
+impl Scale {
+    /// Bus identifier.  `Custom` is 12, followed by its steps.
+    pub fn id(&self) -> u8 {
+        match self {
+            Scale::Chromatic => 0,
+            Scale::Major => 1,
+            Scale::NaturalMinor => 2,
+            Scale::HarmonicMinor => 3,
+            Scale::Dorian => 4,
+            Scale::Phrygian => 5,
+            Scale::Lydian => 6,
+            Scale::Mixolydian => 7,
+            Scale::Locrian => 8,
+            Scale::MajorPentatonic => 9,
+            Scale::MinorPentatonic => 10,
+            Scale::Blues => 11,
+            Scale::Custom(_) => 12,
+        }
+    }
+
+    /// Preset scale for a bus identifier.  `steps` is only used for `Custom`.
+    pub fn from_id(id: u8, steps: &[u8]) -> Result<Self, ScaleError> {
+        let scale = match id {
+            0 => Scale::Chromatic,
+            1 => Scale::Major,
+            2 => Scale::NaturalMinor,
+            3 => Scale::HarmonicMinor,
+            4 => Scale::Dorian,
+            5 => Scale::Phrygian,
+            6 => Scale::Lydian,
+            7 => Scale::Mixolydian,
+            8 => Scale::Locrian,
+            9 => Scale::MajorPentatonic,
+            10 => Scale::MinorPentatonic,
+            11 => Scale::Blues,
+            12 => Scale::Custom(CustomScale::from_steps(steps)?),
+            _ => return Err(ScaleError::UnknownScale(id)),
+        };
+
+        Ok(scale)
+    }
+
+    /// Semitones above the root of each degree in one octave.
+    pub fn offsets(&self) -> &[u8] {
+        match self {
+            Scale::Chromatic => &CHROMATIC,
+            Scale::Major => &MAJOR,
+            Scale::NaturalMinor => &NATURAL_MINOR,
+            Scale::HarmonicMinor => &HARMONIC_MINOR,
+            Scale::Dorian => &DORIAN,
+            Scale::Phrygian => &PHRYGIAN,
+            Scale::Lydian => &LYDIAN,
+            Scale::Mixolydian => &MIXOLYDIAN,
+            Scale::Locrian => &LOCRIAN,
+            Scale::MajorPentatonic => &MAJOR_PENTATONIC,
+            Scale::MinorPentatonic => &MINOR_PENTATONIC,
+            Scale::Blues => &BLUES,
+            Scale::Custom(custom) => &custom.offsets[..custom.len as usize],
+        }
+    }
+
+    /// Semitones above the root for a degree, wrapping into higher octaves.
+    pub fn degree_to_semitones(&self, degree: u8) -> u8 {
+        let offsets = self.offsets();
+        let len = offsets.len() as u8;
+
+        (degree / len) * 12 + offsets[(degree % len) as usize]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn chromatic_degrees_are_semitones() {
+        for degree in 0..=12 {
+            assert_eq!(Scale::Chromatic.degree_to_semitones(degree), degree);
+        }
+    }
+
+    #[test]
+    fn major_wraps_into_next_octave() {
+        assert_eq!(Scale::Major.degree_to_semitones(6), 11);
+        assert_eq!(Scale::Major.degree_to_semitones(7), 12);
+        assert_eq!(Scale::Major.degree_to_semitones(12), 21);
+    }
+
+    #[test]
+    fn pentatonic_covers_more_than_two_octaves() {
+        assert_eq!(Scale::MinorPentatonic.degree_to_semitones(12), 29);
+    }
+
+    #[test]
+    fn custom_steps_build_offsets() {
+        let custom = Scale::Custom(CustomScale::from_steps(&[2, 2, 1, 2, 2, 2, 1]).unwrap());
+
+        assert_eq!(custom.offsets(), Scale::Major.offsets());
+    }
+
+    #[test]
+    fn custom_steps_must_span_an_octave() {
+        assert_eq!(CustomScale::from_steps(&[2, 2, 2]), Err(ScaleError::InvalidSteps));
+        assert_eq!(CustomScale::from_steps(&[0, 12]), Err(ScaleError::InvalidSteps));
+        assert_eq!(CustomScale::from_steps(&[]), Err(ScaleError::InvalidSteps));
+    }
+
+    #[test]
+    fn ids_round_trip() {
+        for id in 0..12 {
+            assert_eq!(Scale::from_id(id, &[]).unwrap().id(), id);
+        }
+
+        assert_eq!(Scale::from_id(12, &[6, 6]).unwrap().offsets(), &[0, 6]);
+        assert_eq!(Scale::from_id(13, &[]), Err(ScaleError::UnknownScale(13)));
+    }
+}
@@ -0,0 +1,397 @@
+use crate::arpeggiator::{NoteDivision, TICKS_PER_QUARTER};
+use crate::NoteSet;
+
+const TICKS_PER_BAR: u16 = 4 * TICKS_PER_QUARTER as u16;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SequencerEvent {
+    /// Position in the loop, 24 ticks per quarter note.
+    pub tick: u16,
+    pub note_index: u8,
+    pub is_on: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SequencerMode {
+    Stopped,
+    Playing,
+    /// Playing while adding what is played on the keys, on top of any events already recorded.
+    Recording,
+}
+
+/// Loop recorder.  Records note on and off at tick positions into a fixed pattern and plays them
+/// back in a loop.  Events beyond `CAPACITY` are dropped and counted in `dropped_events`.
+pub struct Sequencer<const CAPACITY: usize = 64> {
+    events: [SequencerEvent; CAPACITY],
+    event_count: usize,
+    dropped_events: u32,
+    length_ticks: u16,
+    tempo_bpm: u16,
+    quantize: Option<NoteDivision>,
+    mode: SequencerMode,
+    tick: u16,
+    /// The current tick's events have not been played yet.
+    tick_pending: bool,
+    /// Time into the current tick, in units of 1 / (TICKS_PER_QUARTER * tempo) ms.
+    elapsed: u32,
+    playing: NoteSet,
+    last_input: NoteSet,
+}
+
+impl<const CAPACITY: usize> Sequencer<CAPACITY> {
+    pub fn new() -> Self {
+        Self {
+            events: [SequencerEvent {
+                tick: 0,
+                note_index: 0,
+                is_on: false,
+            }; CAPACITY],
+            event_count: 0,
+            dropped_events: 0,
+            length_ticks: 2 * TICKS_PER_BAR,
+            tempo_bpm: 120,
+            quantize: None,
+            mode: SequencerMode::Stopped,
+            tick: 0,
+            tick_pending: false,
+            elapsed: 0,
+            playing: NoteSet::new(),
+            last_input: NoteSet::new(),
+        }
+    }
+
+    pub fn mode(&self) -> SequencerMode {
+        self.mode
+    }
+
+    pub fn events(&self) -> &[SequencerEvent] {
+        &self.events[..self.event_count]
+    }
+
+    pub fn dropped_events(&self) -> u32 {
+        self.dropped_events
+    }
+
+    /// Notes the pattern is holding now.
+    pub fn playing(&self) -> &NoteSet {
+        &self.playing
+    }
+
+    /// Loop length, 1 - 8 bars of 4/4.
+    pub fn set_length_bars(&mut self, bars: u8) {
+        self.length_ticks = bars.clamp(1, 8) as u16 * TICKS_PER_BAR;
+        self.tick %= self.length_ticks;
+    }
+
+    pub fn set_tempo(&mut self, tempo_bpm: u16) {
+        self.tempo_bpm = tempo_bpm.clamp(20, 300);
+    }
+
+    /// Grid that recorded Note Ons snap to, or `None` to keep them where played.
+    pub fn set_quantize(&mut self, quantize: Option<NoteDivision>) {
+        self.quantize = quantize;
+    }
+
+    /// Starts playback from the top of the loop, or continues if already running.
+    pub fn play(&mut self) {
+        self.start();
+        self.mode = SequencerMode::Playing;
+    }
+
+    /// Starts recording from the top of the loop, or overdubs from the current position.
+    pub fn record(&mut self) {
+        self.start();
+        self.mode = SequencerMode::Recording;
+    }
+
+    pub fn stop(&mut self) {
+        self.mode = SequencerMode::Stopped;
+        self.playing.clear();
+    }
+
+    /// Erases the pattern.  Keeps running, so recording can start over straight away.
+    pub fn clear(&mut self) {
+        self.event_count = 0;
+        self.dropped_events = 0;
+        self.playing.clear();
+    }
+
+    fn start(&mut self) {
+        if self.mode == SequencerMode::Stopped {
+            self.tick = 0;
+            self.tick_pending = true;
+            self.elapsed = 0;
+        }
+    }
+
+    /// Moves time forward, playing the events of every tick reached.  Beyond a whole loop only
+    /// the last loop is played, as the earlier ones would leave the same notes playing.
+    pub fn advance(&mut self, delta_t_ms: u32) {
+        if self.mode == SequencerMode::Stopped {
+            return;
+        }
+
+        if self.tick_pending {
+            self.play_tick();
+            self.tick_pending = false;
+        }
+
+        let elapsed = delta_t_ms.saturating_mul(TICKS_PER_QUARTER * self.tempo_bpm as u32);
+        self.elapsed = self.elapsed.saturating_add(elapsed);
+
+        let ticks = self.elapsed / 60_000;
+        self.elapsed %= 60_000;
+
+        let length_ticks = self.length_ticks as u32;
+        let skipped = ticks.saturating_sub(length_ticks);
+        self.tick = ((self.tick as u32 + skipped) % length_ticks) as u16;
+
+        for _ in 0..ticks.min(length_ticks) {
+            self.tick = (self.tick + 1) % self.length_ticks;
+            self.play_tick();
+        }
+    }
+
+    /// Records changes in the notes held on the keys at the current position while recording.
+    pub fn input(&mut self, held: &NoteSet) {
+        if self.mode == SequencerMode::Recording {
+            for note_index in (*held - self.last_input).iter() {
+                self.record_event(note_index as u8, true);
+            }
+
+            for note_index in (self.last_input - *held).iter() {
+                self.record_event(note_index as u8, false);
+            }
+        }
+
+        self.last_input = *held;
+    }
+
+    fn play_tick(&mut self) {
+        for event in &self.events[..self.event_count] {
+            if event.tick == self.tick {
+                self.playing.set(event.note_index as usize, event.is_on);
+            }
+        }
+    }
+
+    fn record_event(&mut self, note_index: u8, is_on: bool) {
+        let tick = if is_on {
+            self.quantized(self.tick)
+        } else {
+            // A note must not end before a Note On that quantizing moved later, which is at most
+            // half a grid step ahead
+            let ahead = self.quantize.map_or(0, |division| division.ticks() as u16 / 2);
+
+            let on_tick = self.events[..self.event_count]
+                .iter()
+                .filter(|event| event.is_on && event.note_index == note_index)
+                .map(|event| (event.tick + self.length_ticks - self.tick) % self.length_ticks)
+                .filter(|distance| *distance <= ahead)
+                .max();
+
+            match on_tick {
+                Some(distance) => (self.tick + distance + 1) % self.length_ticks,
+                None => self.tick,
+            }
+        };
+
+        if self.event_count == CAPACITY {
+            self.dropped_events += 1;
+            return;
+        }
+
+        // Keep events ordered by tick, after any already at the same tick
+        let position = self.events[..self.event_count]
+            .iter()
+            .position(|event| event.tick > tick)
+            .unwrap_or(self.event_count);
+
+        self.events.copy_within(position..self.event_count, position + 1);
+        self.events[position] = SequencerEvent { tick, note_index, is_on };
+        self.event_count += 1;
+    }
+
+    fn quantized(&self, tick: u16) -> u16 {
+        match self.quantize {
+            Some(division) => {
+                let grid = division.ticks() as u16;
+
+                ((tick + grid / 2) / grid * grid) % self.length_ticks
+            }
+            None => tick,
+        }
+    }
+}
+
+impl<const CAPACITY: usize> Default for Sequencer<CAPACITY> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn held(note_indexes: &[u8]) -> NoteSet {
+        note_indexes.iter().map(|note_index| *note_index as usize).collect()
+    }
+
+    /// A sequencer at 125 bpm, where a tick is exactly 20ms, looping one bar.
+    fn sequencer() -> Sequencer<8> {
+        let mut sequencer = Sequencer::new();
+        sequencer.set_tempo(125);
+        sequencer.set_length_bars(1);
+
+        sequencer
+    }
+
+    /// Advances `ticks` ticks while holding `notes`.
+    fn hold(sequencer: &mut Sequencer<8>, notes: &[u8], ticks: u32) {
+        for _ in 0..ticks {
+            sequencer.advance(20);
+            sequencer.input(&held(notes));
+        }
+    }
+
+    #[test]
+    fn recorded_phrase_plays_back_in_loop() {
+        let mut sequencer = sequencer();
+        sequencer.record();
+
+        hold(&mut sequencer, &[], 10);
+        hold(&mut sequencer, &[36], 12);
+        hold(&mut sequencer, &[], 74);
+
+        assert_eq!(
+            sequencer.events(),
+            [
+                SequencerEvent { tick: 11, note_index: 36, is_on: true },
+                SequencerEvent { tick: 23, note_index: 36, is_on: false },
+            ]
+        );
+
+        sequencer.play();
+
+        // Back at tick 0 of the next loop
+        hold(&mut sequencer, &[], 11);
+
+        assert!(sequencer.playing()[36]);
+
+        hold(&mut sequencer, &[], 12);
+
+        assert!(!sequencer.playing()[36]);
+    }
+
+    #[test]
+    fn quantize_moves_note_on_and_keeps_note_off_after_it() {
+        let mut sequencer = sequencer();
+        sequencer.set_quantize(Some(NoteDivision::Sixteenth));
+        sequencer.record();
+
+        hold(&mut sequencer, &[], 4);
+        hold(&mut sequencer, &[40], 1);
+        hold(&mut sequencer, &[], 1);
+
+        assert_eq!(
+            sequencer.events(),
+            [
+                SequencerEvent { tick: 6, note_index: 40, is_on: true },
+                SequencerEvent { tick: 7, note_index: 40, is_on: false },
+            ]
+        );
+    }
+
+    #[test]
+    fn quantize_keeps_note_off_after_note_on_across_loop_end() {
+        let mut sequencer = sequencer();
+        sequencer.set_quantize(Some(NoteDivision::Sixteenth));
+        sequencer.record();
+
+        hold(&mut sequencer, &[], 94);
+        hold(&mut sequencer, &[40], 1);
+        hold(&mut sequencer, &[], 1);
+
+        assert_eq!(
+            sequencer.events(),
+            [
+                SequencerEvent { tick: 0, note_index: 40, is_on: true },
+                SequencerEvent { tick: 1, note_index: 40, is_on: false },
+            ]
+        );
+    }
+
+    #[test]
+    fn long_stall_plays_last_loop() {
+        let mut sequencer = sequencer();
+        sequencer.record();
+
+        hold(&mut sequencer, &[36], 2);
+        hold(&mut sequencer, &[], 1);
+
+        sequencer.play();
+        sequencer.advance(u32::MAX);
+
+        // Still looping through the pattern afterwards
+        let mut played = false;
+
+        for _ in 0..96 {
+            sequencer.advance(20);
+            played |= sequencer.playing()[36];
+        }
+
+        assert!(played);
+    }
+
+    #[test]
+    fn overdub_adds_to_existing_events() {
+        let mut sequencer = sequencer();
+        sequencer.record();
+
+        hold(&mut sequencer, &[36], 2);
+        hold(&mut sequencer, &[], 94);
+        hold(&mut sequencer, &[], 4);
+        hold(&mut sequencer, &[43], 2);
+        hold(&mut sequencer, &[], 2);
+
+        let notes: [u8; 4] = core::array::from_fn(|i| sequencer.events()[i].note_index);
+
+        assert_eq!(notes, [36, 36, 43, 43]);
+    }
+
+    #[test]
+    fn full_pattern_drops_events() {
+        let mut sequencer = sequencer();
+        sequencer.record();
+
+        for _ in 0..5 {
+            hold(&mut sequencer, &[36], 1);
+            hold(&mut sequencer, &[], 1);
+        }
+
+        assert_eq!(sequencer.events().len(), 8);
+        assert_eq!(sequencer.dropped_events(), 2);
+    }
+
+    #[test]
+    fn stop_and_clear_release_playing_notes() {
+        let mut sequencer = sequencer();
+        sequencer.record();
+
+        hold(&mut sequencer, &[36], 2);
+        hold(&mut sequencer, &[], 95);
+
+        assert!(sequencer.playing()[36]);
+
+        sequencer.clear();
+
+        assert!(!sequencer.playing()[36]);
+        assert!(sequencer.events().is_empty());
+
+        sequencer.stop();
+        sequencer.advance(1000);
+
+        assert_eq!(sequencer.mode(), SequencerMode::Stopped);
+    }
+}
@@ -0,0 +1,432 @@
+//! Note frequencies.  Pitch intervals are handled in hundredths of a cent and frequencies in
+//! millihertz, all in integers.
+
+/// Most steps a ratio table can hold, including its period.
+pub const MAX_TUNING_STEPS: usize = 32;
+
+const ONE: u64 = 1 << 30;
+const LN_2: u64 = 744_261_118;
+const HUNDREDTHS_PER_SEMITONE: i32 = 100 * 100;
+const HUNDREDTHS_PER_OCTAVE: i32 = 12 * HUNDREDTHS_PER_SEMITONE;
+
+/// 2^(k/12) in 2.30 fixed point.
+const SEMITONE_RATIOS: [u64; 12] = [
+    1_073_741_824, 1_137_589_835, 1_205_234_447, 1_276_901_417, 1_352_829_926, 1_433_273_380,
+    1_518_500_250, 1_608_794_974, 1_704_458_901, 1_805_811_301, 1_913_190_429, 2_026_954_652,
+];
+
+/// 5-limit ratios of the twelve chromatic steps above the tonic.
+const JUST_RATIOS: [(u32, u32); 12] = [
+    (1, 1), (16, 15), (9, 8), (6, 5), (5, 4), (4, 3), (45, 32), (3, 2), (8, 5), (5, 3), (9, 5), (15, 8),
+];
+
+/// MIDI note of A4, and its frequency in millihertz as MIDI receivers expect it.
+const A4_MIDI_NOTE: i32 = 69;
+const A4_MILLIHZ: u32 = 440_000;
+
+/// `value` raised by `hundredths` hundredths of a cent, saturating.
+fn shift_pitch(value: u64, hundredths: i32) -> u64 {
+    let octaves = hundredths.div_euclid(HUNDREDTHS_PER_OCTAVE);
+    let within_octave = hundredths.rem_euclid(HUNDREDTHS_PER_OCTAVE);
+    let semitone = (within_octave / HUNDREDTHS_PER_SEMITONE) as usize;
+    let remainder = (within_octave % HUNDREDTHS_PER_SEMITONE) as u64;
+
+    // e^y for the part below a semitone, y < 0.06, from the first terms of its series
+    let y = remainder * LN_2 / HUNDREDTHS_PER_OCTAVE as u64;
+    let y2 = (y * y) >> 30;
+    let y3 = (y2 * y) >> 30;
+    let ratio = (SEMITONE_RATIOS[semitone] * (ONE + y + y2 / 2 + y3 / 6)) >> 30;
+
+    let scaled = (value as u128 * ratio as u128) >> 30;
+
+    let scaled = if octaves >= 0 {
+        scaled.checked_shl(octaves as u32).unwrap_or(u128::MAX)
+    } else {
+        scaled >> (-octaves).min(127)
+    };
+
+    scaled.min(u64::MAX as u128) as u64
+}
+
+/// Interval from `from` up to `to` in hundredths of a cent.  Both must be non-zero.
+fn interval(to: u64, from: u64) -> i32 {
+    let (mut to, mut from) = (to as u128, from as u128);
+    let mut octaves = 0;
+
+    while to >= 2 * from {
+        from *= 2;
+        octaves += 1;
+    }
+
+    while to < from {
+        to *= 2;
+        octaves -= 1;
+    }
+
+    let semitone = SEMITONE_RATIOS
+        .iter()
+        .rposition(|ratio| from * *ratio as u128 <= to << 30)
+        .unwrap_or(0);
+    let from = from * SEMITONE_RATIOS[semitone] as u128;
+    let to = to << 30;
+
+    // ln of the remaining ratio r, under a semitone, as 2(z + z^3 / 3) with z = (r - 1) / (r + 1)
+    let z = (((to - from) << 30) / (to + from)) as u64;
+    let z3 = (((z * z) >> 30) * z) >> 30;
+    let ln = 2 * (z + z3 / 3);
+
+    octaves * HUNDREDTHS_PER_OCTAVE
+        + semitone as i32 * HUNDREDTHS_PER_SEMITONE
+        + (ln * HUNDREDTHS_PER_OCTAVE as u64 / LN_2) as i32
+}
+
+/// MIDI note nearest to a frequency at standard A4 = 440 Hz pitch, and how far above it the
+/// frequency is in hundredths of a cent.
+pub fn nearest_midi_note(millihz: u32) -> (u8, i32) {
+    let hundredths = interval(millihz.max(1) as u64, A4_MILLIHZ as u64);
+    let semitones = (hundredths + HUNDREDTHS_PER_SEMITONE / 2).div_euclid(HUNDREDTHS_PER_SEMITONE);
+    let note = (A4_MIDI_NOTE + semitones).clamp(0, 127);
+
+    (note as u8, hundredths - (note - A4_MIDI_NOTE) * HUNDREDTHS_PER_SEMITONE)
+}
+
+/// Oscillator phase step per sample for a frequency, with a full turn of the phase as 2^32.
+pub fn phase_increment(millihz: u32, sample_rate: u32) -> u32 {
+    (((millihz as u64) << 32) / (sample_rate.max(1) as u64 * 1000)) as u32
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TuningError {
+    /// Ratios must rise, start above 1/1 and stay below 4/1.
+    InvalidRatio,
+    TooManySteps,
+    /// Not a Scala scale file: a description line, a step count, then that many pitches.
+    InvalidScala,
+}
+
+/// Ratios of a scale's steps above its tonic.  The last step is the period the scale repeats
+/// at, usually the octave.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RatioTable {
+    /// 2.30 fixed point.
+    ratios: [u32; MAX_TUNING_STEPS],
+    len: u8,
+}
+
+impl RatioTable {
+    /// Builds a table from `(numerator, denominator)` pairs.
+    pub fn from_ratios(ratios: &[(u32, u32)]) -> Result<Self, TuningError> {
+        let mut table = RatioTable::new();
+
+        for (numerator, denominator) in ratios {
+            if *denominator == 0 {
+                return Err(TuningError::InvalidRatio);
+            }
+
+            table.push(((*numerator as u64) << 30) / *denominator as u64)?;
+        }
+
+        table.validate()
+    }
+
+    /// Parses the text of a Scala `.scl` file.  Pitches with a period are in cents, others are
+    /// ratios such as `3/2` or `2`.
+    pub fn from_scala(text: &str) -> Result<Self, TuningError> {
+        let mut lines = text.lines().filter(|line| !line.starts_with('!'));
+
+        // Description
+        lines.next().ok_or(TuningError::InvalidScala)?;
+
+        let count: usize = lines
+            .next()
+            .and_then(|line| line.split_whitespace().next())
+            .and_then(|count| count.parse().ok())
+            .ok_or(TuningError::InvalidScala)?;
+
+        let mut table = RatioTable::new();
+
+        for _ in 0..count {
+            let pitch = lines
+                .next()
+                .and_then(|line| line.split_whitespace().next())
+                .ok_or(TuningError::InvalidScala)?;
+
+            let ratio = if pitch.contains('.') {
+                shift_pitch(ONE, parse_cents(pitch)?)
+            } else {
+                let (numerator, denominator) = pitch.split_once('/').unwrap_or((pitch, "1"));
+                let numerator: u64 = numerator.parse().map_err(|_| TuningError::InvalidScala)?;
+                let denominator: u64 = denominator.parse().map_err(|_| TuningError::InvalidScala)?;
+
+                if denominator == 0 {
+                    return Err(TuningError::InvalidRatio);
+                }
+
+                (numerator << 30) / denominator
+            };
+
+            table.push(ratio)?;
+        }
+
+        table.validate()
+    }
+
+    const fn new() -> Self {
+        Self {
+            ratios: [0; MAX_TUNING_STEPS],
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, ratio: u64) -> Result<(), TuningError> {
+        if self.len as usize == MAX_TUNING_STEPS {
+            return Err(TuningError::TooManySteps);
+        }
+
+        if ratio <= ONE || ratio > u32::MAX as u64 {
+            return Err(TuningError::InvalidRatio);
+        }
+
+        self.ratios[self.len as usize] = ratio as u32;
+        self.len += 1;
+
+        Ok(())
+    }
+
+    fn validate(self) -> Result<Self, TuningError> {
+        if self.len == 0 || self.ratios().windows(2).any(|pair| pair[0] >= pair[1]) {
+            return Err(TuningError::InvalidRatio);
+        }
+
+        Ok(self)
+    }
+
+    /// Steps per period, counting the period itself.
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn ratios(&self) -> &[u32] {
+        &self.ratios[..self.len as usize]
+    }
+
+    /// `value` raised by `steps` steps of the scale, in either direction.
+    fn raise(&self, value: u64, steps: i32) -> u64 {
+        let len = self.len as i32;
+        let period = self.ratios[self.len as usize - 1] as u128;
+        let mut value = value as u128;
+
+        for _ in 0..steps.div_euclid(len).max(0) {
+            value = (value * period) >> 30;
+        }
+
+        for _ in 0..(-steps.div_euclid(len)).max(0) {
+            value = (value << 30) / period;
+        }
+
+        let step = steps.rem_euclid(len) as usize;
+
+        if step > 0 {
+            value = (value * self.ratios[step - 1] as u128) >> 30;
+        }
+
+        value.min(u64::MAX as u128) as u64
+    }
+}
+
+/// Cents such as `-701.955` in hundredths of a cent, ignoring digits past the hundredths.
+fn parse_cents(text: &str) -> Result<i32, TuningError> {
+    let (negative, text) = match text.strip_prefix('-') {
+        Some(text) => (true, text),
+        None => (false, text),
+    };
+
+    let (whole, fraction) = text.split_once('.').unwrap_or((text, ""));
+    let whole: i32 = if whole.is_empty() { Ok(0) } else { whole.parse() }.map_err(|_| TuningError::InvalidScala)?;
+
+    let mut hundredths = 0;
+
+    for (i, digit) in fraction.chars().chain("00".chars()).take(2).enumerate() {
+        let digit = digit.to_digit(10).ok_or(TuningError::InvalidScala)? as i32;
+
+        hundredths += digit * if i == 0 { 10 } else { 1 };
+    }
+
+    let value = whole.checked_mul(100).and_then(|whole| whole.checked_add(hundredths)).ok_or(TuningError::InvalidScala)?;
+
+    Ok(if negative { -value } else { value })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TuningSystem {
+    /// The octave split into this many equal steps, one per MIDI note, with A4 at the
+    /// reference pitch.
+    Equal { divisions: u8 },
+    /// 5-limit just intonation on the scale root.
+    Just,
+    /// Steps from a ratio table on the scale root, one per MIDI note.
+    Ratios(RatioTable),
+}
+
+impl TuningSystem {
+    /// Bus identifier.
+    pub fn id(&self) -> u8 {
+        match self {
+            TuningSystem::Equal { .. } => 0,
+            TuningSystem::Just => 1,
+            TuningSystem::Ratios(_) => 2,
+        }
+    }
+}
+
+/// Maps MIDI notes to frequencies.  Scales built on a root, just intonation and ratio tables,
+/// start from the root in the octave of middle C, at its 12 tone equal tempered pitch.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tuning {
+    /// Frequency of A4 in millihertz.
+    pub reference_millihz: u32,
+    pub system: TuningSystem,
+}
+
+impl Tuning {
+    /// Frequency of `midi_note` in millihertz.  `root` is 0 - 11 semitones above C.
+    pub fn frequency_millihz(&self, midi_note: u8, root: u8) -> u32 {
+        let reference = self.reference_millihz as u64;
+        let from_a4 = midi_note as i32 - A4_MIDI_NOTE;
+        let tonic = 60 + (root % 12) as i32;
+        let tonic_frequency = || shift_pitch(reference, (tonic - A4_MIDI_NOTE) * HUNDREDTHS_PER_SEMITONE);
+
+        let frequency = match &self.system {
+            TuningSystem::Equal { divisions } => {
+                shift_pitch(reference, from_a4 * HUNDREDTHS_PER_OCTAVE / (*divisions).max(1) as i32)
+            }
+            TuningSystem::Just => {
+                let steps = midi_note as i32 - tonic;
+                let (numerator, denominator) = JUST_RATIOS[steps.rem_euclid(12) as usize];
+                let frequency = tonic_frequency() * numerator as u64 / denominator as u64;
+
+                shift_pitch(frequency, steps.div_euclid(12) * HUNDREDTHS_PER_OCTAVE)
+            }
+            TuningSystem::Ratios(table) => table.raise(tonic_frequency(), midi_note as i32 - tonic),
+        };
+
+        frequency.min(u32::MAX as u64) as u32
+    }
+
+    /// `millihz` raised by `cents`.
+    pub fn detune(millihz: u32, cents: i32) -> u32 {
+        shift_pitch(millihz as u64, cents * 100).min(u32::MAX as u64) as u32
+    }
+}
+
+impl Default for Tuning {
+    fn default() -> Self {
+        Self {
+            reference_millihz: A4_MILLIHZ,
+            system: TuningSystem::Equal { divisions: 12 },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Asserts `millihz` is within 1 millihertz per 10 Hz of `expected`.
+    fn assert_close(millihz: u32, expected: u32) {
+        let tolerance = expected / 10_000 + 1;
+
+        assert!(millihz.abs_diff(expected) <= tolerance, "{} is not {}", millihz, expected);
+    }
+
+    #[test]
+    fn twelve_tone_equal_temperament_matches_standard_pitches() {
+        let tuning = Tuning::default();
+
+        assert_eq!(tuning.frequency_millihz(69, 0), 440_000);
+        assert_close(tuning.frequency_millihz(60, 0), 261_626);
+        assert_close(tuning.frequency_millihz(24, 0), 32_703);
+        assert_close(tuning.frequency_millihz(127, 0), 12_543_854);
+    }
+
+    #[test]
+    fn reference_pitch_moves_every_note() {
+        let tuning = Tuning {
+            reference_millihz: 432_000,
+            ..Tuning::default()
+        };
+
+        assert_eq!(tuning.frequency_millihz(57, 0), 216_000);
+        assert_close(tuning.frequency_millihz(60, 0), 256_869);
+    }
+
+    #[test]
+    fn other_equal_temperaments_split_the_octave() {
+        let tuning = Tuning {
+            system: TuningSystem::Equal { divisions: 19 },
+            ..Tuning::default()
+        };
+
+        assert_eq!(tuning.frequency_millihz(69 + 19, 0), 880_000);
+        assert_close(tuning.frequency_millihz(70, 0), 456_348);
+    }
+
+    #[test]
+    fn just_intonation_uses_pure_ratios_on_the_root() {
+        let tuning = Tuning {
+            system: TuningSystem::Just,
+            ..Tuning::default()
+        };
+
+        // D major: A is a pure fifth above D4, F#3 a pure major third above D3
+        let d4 = tuning.frequency_millihz(62, 2);
+
+        assert_close(d4, 293_665);
+        assert_close(tuning.frequency_millihz(69, 2), d4 * 3 / 2);
+        assert_close(tuning.frequency_millihz(54, 2), d4 / 2 * 5 / 4);
+    }
+
+    #[test]
+    fn scala_file_loads_ratios_and_cents() {
+        let text = "! pentatonic.scl\n!\nJust pentatonic\n 5\n!\n 9/8\n 5/4\n 701.955 fifth\n 5/3\n 2\n";
+        let table = RatioTable::from_scala(text).unwrap();
+        let tuning = Tuning {
+            system: TuningSystem::Ratios(table),
+            ..Tuning::default()
+        };
+
+        let c4 = tuning.frequency_millihz(60, 0);
+
+        assert_eq!(table.len(), 5);
+        assert_close(tuning.frequency_millihz(62, 0), c4 * 5 / 4);
+        assert_close(tuning.frequency_millihz(63, 0), c4 * 3 / 2);
+        assert_close(tuning.frequency_millihz(65, 0), c4 * 2);
+        assert_close(tuning.frequency_millihz(59, 0), c4 / 2 * 5 / 3);
+    }
+
+    #[test]
+    fn bad_tables_are_rejected() {
+        assert_eq!(RatioTable::from_ratios(&[(3, 2), (5, 4)]), Err(TuningError::InvalidRatio));
+        assert_eq!(RatioTable::from_ratios(&[(1, 1)]), Err(TuningError::InvalidRatio));
+        assert_eq!(RatioTable::from_ratios(&[(5, 1)]), Err(TuningError::InvalidRatio));
+        assert_eq!(RatioTable::from_scala("Short\n3\n3/2\n2\n"), Err(TuningError::InvalidScala));
+        assert_eq!(RatioTable::from_scala("Cents\n1\n12x.0\n"), Err(TuningError::InvalidScala));
+    }
+
+    #[test]
+    fn nearest_midi_note_reports_offset() {
+        assert_eq!(nearest_midi_note(440_000), (69, 0));
+
+        let (note, hundredths) = nearest_midi_note(Tuning::detune(261_626, 30));
+        assert_eq!(note, 60);
+        assert!((2999..=3001).contains(&hundredths), "{}", hundredths);
+
+        let (note, hundredths) = nearest_midi_note(Tuning::detune(261_626, -70));
+        assert_eq!(note, 59);
+        assert!((2999..=3001).contains(&hundredths), "{}", hundredths);
+    }
+}
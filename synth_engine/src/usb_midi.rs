@@ -0,0 +1,271 @@
+//! USB-MIDI 1.0 Event Packets: a cable number and Code Index Number (CIN) in the first byte,
+//! followed by a MIDI message padded with zeros to three bytes.
+
+use crate::midi::{for_each_note_change, voice_channel, MidiError, NOTE_OFF, NOTE_OFF_VELOCITY, NOTE_ON};
+use crate::{SynthState, NUM_NOTES};
+
+const CIN_TWO_BYTE_SYSTEM_COMMON: u8 = 0x2;
+const CIN_THREE_BYTE_SYSTEM_COMMON: u8 = 0x3;
+const CIN_SYSEX_START: u8 = 0x4;
+const CIN_SYSEX_END_1: u8 = 0x5;
+/// Shares its code index with a SysEx ending in one byte.
+const CIN_SINGLE_BYTE_SYSTEM_COMMON: u8 = 0x5;
+const CIN_SYSEX_END_2: u8 = 0x6;
+const CIN_SYSEX_END_3: u8 = 0x7;
+const CIN_SINGLE_BYTE: u8 = 0xf;
+
+const SYSEX_START: u8 = 0xf0;
+const SYSEX_END: u8 = 0xf7;
+
+/// Largest output of one `UsbMidiEncoder::encode`: one packet per note.
+pub const MAX_USB_MIDI_UPDATE_PACKETS: usize = NUM_NOTES;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct UsbMidiEventPacket {
+    pub bytes: [u8; 4],
+}
+
+impl UsbMidiEventPacket {
+    pub fn new(cable: u8, code_index: u8, midi: [u8; 3]) -> Self {
+        Self {
+            bytes: [(cable & 0x0f) << 4 | (code_index & 0x0f), midi[0], midi[1], midi[2]],
+        }
+    }
+
+    /// Packs one complete MIDI message that is not SysEx.  Returns `None` for a SysEx or data
+    /// byte status, or a message of the wrong length for its status.
+    pub fn from_message(cable: u8, message: &[u8]) -> Option<Self> {
+        let status = *message.first()?;
+
+        let (code_index, size) = match status {
+            0x80..=0xef => {
+                let size = if (0xc0..=0xdf).contains(&status) { 2 } else { 3 };
+                (status >> 4, size)
+            }
+            0xf1 | 0xf3 => (CIN_TWO_BYTE_SYSTEM_COMMON, 2),
+            0xf2 => (CIN_THREE_BYTE_SYSTEM_COMMON, 3),
+            0xf6 => (CIN_SINGLE_BYTE_SYSTEM_COMMON, 1),
+            0xf8..=0xff => (CIN_SINGLE_BYTE, 1),
+            _ => return None,
+        };
+
+        if message.len() != size {
+            return None;
+        }
+
+        let mut midi = [0u8; 3];
+        midi[..size].copy_from_slice(message);
+
+        Some(Self::new(cable, code_index, midi))
+    }
+
+    pub fn cable(&self) -> u8 {
+        self.bytes[0] >> 4
+    }
+
+    pub fn code_index(&self) -> u8 {
+        self.bytes[0] & 0x0f
+    }
+
+    pub fn midi(&self) -> [u8; 3] {
+        [self.bytes[1], self.bytes[2], self.bytes[3]]
+    }
+}
+
+/// Splits a complete SysEx message, 0xF0 through 0xF7, into event packets.  Returns the number
+/// of packets written.
+pub fn sysex_packets(cable: u8, sysex: &[u8], out: &mut [UsbMidiEventPacket]) -> Result<usize, MidiError> {
+    let is_valid = sysex.len() >= 2
+        && sysex[0] == SYSEX_START
+        && sysex[sysex.len() - 1] == SYSEX_END
+        && sysex[1..sysex.len() - 1].iter().all(|byte| *byte < 0x80);
+
+    if !is_valid {
+        return Err(MidiError::InvalidSysEx);
+    }
+
+    let packet_count = sysex.len().div_ceil(3);
+
+    if out.len() < packet_count {
+        return Err(MidiError::BufferTooSmall);
+    }
+
+    for (packet, chunk) in out.iter_mut().zip(sysex.chunks(3)) {
+        let is_last = chunk[chunk.len() - 1] == SYSEX_END;
+
+        let code_index = match (is_last, chunk.len()) {
+            (false, _) => CIN_SYSEX_START,
+            (true, 1) => CIN_SYSEX_END_1,
+            (true, 2) => CIN_SYSEX_END_2,
+            (true, _) => CIN_SYSEX_END_3,
+        };
+
+        let mut midi = [0u8; 3];
+        midi[..chunk.len()].copy_from_slice(chunk);
+
+        *packet = UsbMidiEventPacket::new(cable, code_index, midi);
+    }
+
+    Ok(packet_count)
+}
+
+/// Turns the note transitions of a `SynthState` update into USB-MIDI event packets.  USB-MIDI
+/// has no running status, so every packet carries its full message.
+pub struct UsbMidiEncoder {
+    cable: u8,
+    channel: u8,
+    velocity: u8,
+    channel_per_voice: bool,
+}
+
+impl UsbMidiEncoder {
+    /// `cable` and `channel` are 0 - 15.
+    pub fn new(cable: u8, channel: u8) -> Self {
+        Self {
+            cable: cable & 0x0f,
+            channel: channel & 0x0f,
+            velocity: 100,
+            channel_per_voice: false,
+        }
+    }
+
+    pub fn set_channel(&mut self, channel: u8) {
+        self.channel = channel & 0x0f;
+    }
+
+    /// Sends each voice's notes on its own channel, counting up from the encoder's channel.
+    pub fn set_channel_per_voice(&mut self, channel_per_voice: bool) {
+        self.channel_per_voice = channel_per_voice;
+    }
+
+    /// Note On velocity, 1 - 127.
+    pub fn set_velocity(&mut self, velocity: u8) {
+        self.velocity = velocity.clamp(1, 127);
+    }
+
+    /// Writes one packet per note that changed in the last update.  Returns the packets written.
+    pub fn encode(&self, synth_state: &SynthState, out: &mut [UsbMidiEventPacket]) -> Result<usize, MidiError> {
+        let mut count = 0;
+
+        for_each_note_change(synth_state, &mut |change| {
+            let channel = voice_channel(self.channel, self.channel_per_voice, change.voice);
+
+            let midi = if change.is_on {
+                [NOTE_ON | channel, change.midi_note, self.velocity]
+            } else {
+                [NOTE_OFF | channel, change.midi_note, NOTE_OFF_VELOCITY]
+            };
+
+            let packet = out.get_mut(count).ok_or(MidiError::BufferTooSmall)?;
+            *packet = UsbMidiEventPacket::new(self.cable, midi[0] >> 4, midi);
+            count += 1;
+
+            Ok(())
+        })?;
+
+        Ok(count)
+    }
+}
+
+impl Default for UsbMidiEncoder {
+    fn default() -> Self {
+        Self::new(0, 0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std;
+    use super::*;
+    use crate::SynthEngine;
+
+    use keyboard_matrix::KeyboardState;
+    use std::vec::Vec;
+
+    fn sysex(message: &[u8]) -> Vec<[u8; 4]> {
+        let mut out = [UsbMidiEventPacket::default(); 8];
+        let count = sysex_packets(1, message, &mut out).unwrap();
+
+        out[..count].iter().map(|packet| packet.bytes).collect()
+    }
+
+    #[test]
+    fn note_changes_become_packets() {
+        let mut synth_engine = SynthEngine::new();
+        let encoder = UsbMidiEncoder::new(2, 3);
+
+        let mut keyboard_state = KeyboardState::default();
+        keyboard_state.state.set(13, true);
+        keyboard_state.state.set(15, true);
+        synth_engine.update(&keyboard_state);
+
+        let mut out = [UsbMidiEventPacket::default(); MAX_USB_MIDI_UPDATE_PACKETS];
+        let count = encoder.encode(&synth_engine.state, &mut out).unwrap();
+
+        assert_eq!(count, 2);
+        assert_eq!(out[0].bytes, [0x29, 0x93, 60, 100]);
+        assert_eq!(out[1].bytes, [0x29, 0x93, 64, 100]);
+
+        keyboard_state.state.set(13, false);
+        synth_engine.update(&keyboard_state);
+
+        let count = encoder.encode(&synth_engine.state, &mut out).unwrap();
+
+        assert_eq!(&out[..count], &[UsbMidiEventPacket::new(2, 0x8, [0x83, 60, 64])]);
+    }
+
+    #[test]
+    fn short_buffer_is_reported() {
+        let mut synth_engine = SynthEngine::new();
+
+        let mut keyboard_state = KeyboardState::default();
+        keyboard_state.state.set(13, true);
+        keyboard_state.state.set(15, true);
+        synth_engine.update(&keyboard_state);
+
+        let mut out = [UsbMidiEventPacket::default(); 1];
+
+        assert_eq!(
+            UsbMidiEncoder::default().encode(&synth_engine.state, &mut out),
+            Err(MidiError::BufferTooSmall)
+        );
+    }
+
+    #[test]
+    fn sysex_ending_on_each_packet_position() {
+        assert_eq!(sysex(&[0xf0, 0xf7]), [[0x16, 0xf0, 0xf7, 0]]);
+        assert_eq!(sysex(&[0xf0, 0x01, 0xf7]), [[0x17, 0xf0, 0x01, 0xf7]]);
+        assert_eq!(
+            sysex(&[0xf0, 0x01, 0x02, 0xf7]),
+            [[0x14, 0xf0, 0x01, 0x02], [0x15, 0xf7, 0, 0]]
+        );
+        assert_eq!(
+            sysex(&[0xf0, 0x01, 0x02, 0x03, 0xf7]),
+            [[0x14, 0xf0, 0x01, 0x02], [0x16, 0x03, 0xf7, 0]]
+        );
+    }
+
+    #[test]
+    fn malformed_sysex_is_rejected() {
+        let mut out = [UsbMidiEventPacket::default(); 8];
+
+        assert_eq!(sysex_packets(0, &[0xf0, 0x01], &mut out), Err(MidiError::InvalidSysEx));
+        assert_eq!(sysex_packets(0, &[0xf0, 0x90, 0xf7], &mut out), Err(MidiError::InvalidSysEx));
+        assert_eq!(sysex_packets(0, &[0xf0, 0x01, 0x02, 0xf7], &mut out[..1]), Err(MidiError::BufferTooSmall));
+    }
+
+    #[test]
+    fn single_messages_get_matching_code_index() {
+        assert_eq!(UsbMidiEventPacket::from_message(0, &[0xc1, 5]).unwrap().bytes, [0x0c, 0xc1, 5, 0]);
+        assert_eq!(UsbMidiEventPacket::from_message(0, &[0xf8]).unwrap().bytes, [0x0f, 0xf8, 0, 0]);
+        assert_eq!(UsbMidiEventPacket::from_message(0, &[0xf2, 1, 2]).unwrap().bytes, [0x03, 0xf2, 1, 2]);
+        assert_eq!(UsbMidiEventPacket::from_message(0, &[0x90, 60]), None);
+        assert_eq!(UsbMidiEventPacket::from_message(0, &[0xf0, 0xf7]), None);
+    }
+
+    #[test]
+    fn tune_request_is_single_byte_system_common() {
+        assert_eq!(UsbMidiEventPacket::from_message(1, &[0xf6]).unwrap().bytes, [0x15, 0xf6, 0, 0]);
+        assert_eq!(UsbMidiEventPacket::from_message(0, &[0xf6, 0]), None);
+    }
+}
@@ -0,0 +1,265 @@
+use crate::NoteSet;
+
+/// Most voices an allocator can manage.
+pub const MAX_VOICES: usize = 16;
+
+const NO_NOTE: u8 = 0xff;
+
+/// Which sounding note gives up its voice when a new note finds none free.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StealPolicy {
+    Oldest,
+    Lowest,
+    Highest,
+    /// New notes wait for a key to be released and do not sound until pressed again.
+    NoSteal,
+}
+
+impl StealPolicy {
+    pub fn id(&self) -> u8 {
+        match self {
+            StealPolicy::Oldest => 0,
+            StealPolicy::Lowest => 1,
+            StealPolicy::Highest => 2,
+            StealPolicy::NoSteal => 3,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(StealPolicy::Oldest),
+            1 => Some(StealPolicy::Lowest),
+            2 => Some(StealPolicy::Highest),
+            3 => Some(StealPolicy::NoSteal),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct VoiceSettings {
+    /// 1 - `MAX_VOICES`.
+    pub voices: u8,
+    pub policy: StealPolicy,
+}
+
+/// Limits how many notes sound at once and gives each a voice number.
+///
+/// A note that is refused a voice, or loses its voice to a newer note, stays silent until it is
+/// released and played again.
+pub struct VoiceAllocator {
+    settings: VoiceSettings,
+    /// Note playing on each voice, or `NO_NOTE`.
+    voice_note: [u8; MAX_VOICES],
+    /// When each voice's note started, counted in notes started.
+    voice_started: [u32; MAX_VOICES],
+    note_count: u32,
+    stolen: NoteSet,
+}
+
+impl VoiceAllocator {
+    pub fn new(settings: VoiceSettings) -> Self {
+        let mut allocator = Self {
+            settings,
+            voice_note: [NO_NOTE; MAX_VOICES],
+            voice_started: [0; MAX_VOICES],
+            note_count: 0,
+            stolen: NoteSet::new(),
+        };
+        allocator.set_settings(settings);
+
+        allocator
+    }
+
+    pub fn settings(&self) -> &VoiceSettings {
+        &self.settings
+    }
+
+    /// Notes on voices beyond a reduced count are stolen.
+    pub fn set_settings(&mut self, settings: VoiceSettings) {
+        self.settings = VoiceSettings {
+            voices: settings.voices.clamp(1, MAX_VOICES as u8),
+            policy: settings.policy,
+        };
+
+        for note in &mut self.voice_note[self.settings.voices as usize..] {
+            if *note != NO_NOTE {
+                self.stolen.insert(*note as usize);
+                *note = NO_NOTE;
+            }
+        }
+    }
+
+    pub fn voice_for_note(&self, note_index: u8) -> Option<u8> {
+        self.voice_note[..self.settings.voices as usize]
+            .iter()
+            .position(|note| *note == note_index)
+            .map(|voice| voice as u8)
+    }
+
+    /// Note playing on `voice`, if any.
+    pub fn note_for_voice(&self, voice: usize) -> Option<u8> {
+        self.voice_note[..self.settings.voices as usize]
+            .get(voice)
+            .copied()
+            .filter(|note| *note != NO_NOTE)
+    }
+
+    /// Whether a held note has no voice.
+    pub fn is_stolen(&self, note_index: u8) -> bool {
+        self.stolen.contains(note_index as usize)
+    }
+
+    /// Held notes without a voice.
+    pub fn stolen(&self) -> &NoteSet {
+        &self.stolen
+    }
+
+    /// Gives voices to newly held notes, lowest first, and clears the notes left without one
+    /// from `held`.
+    pub fn allocate(&mut self, held: &mut NoteSet) {
+        let voices = self.settings.voices as usize;
+
+        for note in &mut self.voice_note[..voices] {
+            if *note != NO_NOTE && !held[*note as usize] {
+                *note = NO_NOTE;
+            }
+        }
+
+        self.stolen = self.stolen & *held;
+
+        // Notes started in this update are not stolen by another started alongside them
+        let update_start = self.note_count;
+
+        for note_index in held.iter().map(|note_index| note_index as u8) {
+            if self.is_stolen(note_index) || self.voice_for_note(note_index).is_some() {
+                continue;
+            }
+
+            let voice = match self.voice_note[..voices].iter().position(|note| *note == NO_NOTE) {
+                Some(voice) => Some(voice),
+                None => self.victim(update_start),
+            };
+
+            match voice {
+                Some(voice) => {
+                    if self.voice_note[voice] != NO_NOTE {
+                        self.stolen.insert(self.voice_note[voice] as usize);
+                    }
+
+                    self.voice_note[voice] = note_index;
+                    self.voice_started[voice] = self.note_count;
+                    self.note_count = self.note_count.wrapping_add(1);
+                }
+                None => self.stolen.insert(note_index as usize),
+            }
+        }
+
+        *held = *held - self.stolen;
+    }
+
+    fn victim(&self, update_start: u32) -> Option<usize> {
+        let candidates = (0..self.settings.voices as usize).filter(|voice| self.voice_started[*voice] < update_start);
+
+        match self.settings.policy {
+            StealPolicy::Oldest => candidates.min_by_key(|voice| self.voice_started[*voice]),
+            StealPolicy::Lowest => candidates.min_by_key(|voice| self.voice_note[*voice]),
+            StealPolicy::Highest => candidates.max_by_key(|voice| self.voice_note[*voice]),
+            StealPolicy::NoSteal => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn held(note_indexes: &[u8]) -> NoteSet {
+        note_indexes.iter().map(|note_index| *note_index as usize).collect()
+    }
+
+    fn allocate(allocator: &mut VoiceAllocator, note_indexes: &[u8]) -> NoteSet {
+        let mut notes = held(note_indexes);
+        allocator.allocate(&mut notes);
+
+        notes
+    }
+
+    fn allocator(voices: u8, policy: StealPolicy) -> VoiceAllocator {
+        VoiceAllocator::new(VoiceSettings { voices, policy })
+    }
+
+    #[test]
+    fn notes_take_free_voices() {
+        let mut allocator = allocator(4, StealPolicy::Oldest);
+
+        allocate(&mut allocator, &[36, 40]);
+
+        assert_eq!(allocator.voice_for_note(36), Some(0));
+        assert_eq!(allocator.voice_for_note(40), Some(1));
+
+        // A released note frees its voice for the next
+        allocate(&mut allocator, &[40]);
+        allocate(&mut allocator, &[40, 43]);
+
+        assert_eq!(allocator.voice_for_note(43), Some(0));
+    }
+
+    #[test]
+    fn oldest_note_is_stolen() {
+        let mut allocator = allocator(2, StealPolicy::Oldest);
+
+        allocate(&mut allocator, &[40]);
+        allocate(&mut allocator, &[40, 36]);
+        let sounding = allocate(&mut allocator, &[40, 36, 43]);
+
+        assert_eq!(sounding, held(&[36, 43]));
+        assert!(allocator.is_stolen(40));
+        assert_eq!(allocator.voice_for_note(43), Some(0));
+    }
+
+    #[test]
+    fn lowest_and_highest_pick_by_pitch() {
+        let mut allocator_lowest = allocator(2, StealPolicy::Lowest);
+        allocate(&mut allocator_lowest, &[36, 40]);
+
+        assert_eq!(allocate(&mut allocator_lowest, &[36, 40, 43]), held(&[40, 43]));
+
+        let mut allocator_highest = allocator(2, StealPolicy::Highest);
+        allocate(&mut allocator_highest, &[36, 40]);
+
+        assert_eq!(allocate(&mut allocator_highest, &[36, 40, 43]), held(&[36, 43]));
+    }
+
+    #[test]
+    fn no_steal_refuses_new_notes_until_pressed_again() {
+        let mut allocator = allocator(1, StealPolicy::NoSteal);
+
+        allocate(&mut allocator, &[36]);
+
+        assert_eq!(allocate(&mut allocator, &[36, 40]), held(&[36]));
+
+        // 40 stays silent after 36 releases, until it is released and pressed again
+        assert_eq!(allocate(&mut allocator, &[40]), held(&[]));
+        allocate(&mut allocator, &[]);
+
+        assert_eq!(allocate(&mut allocator, &[40]), held(&[40]));
+    }
+
+    #[test]
+    fn notes_pressed_together_do_not_steal_each_other() {
+        let mut allocator = allocator(2, StealPolicy::Lowest);
+
+        assert_eq!(allocate(&mut allocator, &[36, 40, 43]), held(&[36, 40]));
+    }
+
+    #[test]
+    fn fewer_voices_steal_notes_beyond_them() {
+        let mut allocator = allocator(4, StealPolicy::Oldest);
+        allocate(&mut allocator, &[36, 40, 43]);
+
+        allocator.set_settings(VoiceSettings { voices: 2, policy: StealPolicy::Oldest });
+
+        assert_eq!(allocate(&mut allocator, &[36, 40, 43]), held(&[36, 40]));
+    }
+}